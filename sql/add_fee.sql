@@ -0,0 +1,37 @@
+-- add `f_fee` to the tables created before the fee tiers, run it once before upgrading galois
+-- mysql -u {user_name} -p {database} < add_fee.sql
+DROP PROCEDURE IF EXISTS `p_add_fee`;
+
+DELIMITER //
+CREATE PROCEDURE `p_add_fee`()
+BEGIN
+  DECLARE done INT DEFAULT FALSE;
+  DECLARE tbl VARCHAR(64);
+  DECLARE cur CURSOR FOR
+    SELECT t.`table_name` FROM information_schema.tables t
+    WHERE t.`table_schema` = DATABASE()
+      AND t.`table_name` LIKE 't\_clearing\_result%'
+      AND NOT EXISTS (
+        SELECT 1 FROM information_schema.columns c
+        WHERE c.`table_schema` = t.`table_schema`
+          AND c.`table_name` = t.`table_name`
+          AND c.`column_name` = 'f_fee'
+      );
+  DECLARE CONTINUE HANDLER FOR NOT FOUND SET done = TRUE;
+  OPEN cur;
+  alter_loop: LOOP
+    FETCH cur INTO tbl;
+    IF done THEN
+      LEAVE alter_loop;
+    END IF;
+    SET @ddl = CONCAT('ALTER TABLE `', tbl, '` ADD COLUMN `f_fee` varchar(18) NOT NULL DEFAULT ''0'' AFTER `f_base_charge`');
+    PREPARE stmt FROM @ddl;
+    EXECUTE stmt;
+    DEALLOCATE PREPARE stmt;
+  END LOOP;
+  CLOSE cur;
+END //
+DELIMITER ;
+
+CALL `p_add_fee`();
+DROP PROCEDURE `p_add_fee`;
@@ -0,0 +1,37 @@
+-- add `f_seq` to the unique key of the tables created before it, run it once before upgrading galois
+-- mysql -u {user_name} -p {database} < add_seq.sql
+DROP PROCEDURE IF EXISTS `p_add_seq`;
+
+DELIMITER //
+CREATE PROCEDURE `p_add_seq`()
+BEGIN
+  DECLARE done INT DEFAULT FALSE;
+  DECLARE tbl VARCHAR(64);
+  DECLARE cur CURSOR FOR
+    SELECT t.`table_name` FROM information_schema.tables t
+    WHERE t.`table_schema` = DATABASE()
+      AND t.`table_name` LIKE 't\_clearing\_result%'
+      AND NOT EXISTS (
+        SELECT 1 FROM information_schema.columns c
+        WHERE c.`table_schema` = t.`table_schema`
+          AND c.`table_name` = t.`table_name`
+          AND c.`column_name` = 'f_seq'
+      );
+  DECLARE CONTINUE HANDLER FOR NOT FOUND SET done = TRUE;
+  OPEN cur;
+  alter_loop: LOOP
+    FETCH cur INTO tbl;
+    IF done THEN
+      LEAVE alter_loop;
+    END IF;
+    SET @ddl = CONCAT('ALTER TABLE `', tbl, '` ADD COLUMN `f_seq` int unsigned NOT NULL DEFAULT ''0'' AFTER `f_event_id`, DROP INDEX `f_event_id`, ADD UNIQUE KEY `f_event_id` (`f_event_id`,`f_seq`,`f_order_id`)');
+    PREPARE stmt FROM @ddl;
+    EXECUTE stmt;
+    DEALLOCATE PREPARE stmt;
+  END LOOP;
+  CLOSE cur;
+END //
+DELIMITER ;
+
+CALL `p_add_seq`();
+DROP PROCEDURE `p_add_seq`;
@@ -10,6 +10,7 @@ CREATE TABLE `t_sequence` (
 CREATE TABLE `t_clearing_result` (
   `f_id` bigint unsigned NOT NULL AUTO_INCREMENT,
   `f_event_id` bigint unsigned NOT NULL,
+  `f_seq` int unsigned NOT NULL DEFAULT '0' COMMENT 'the order of the result in the event',
   `f_order_id` bigint unsigned NOT NULL,
   `f_user_id` varchar(66) NOT NULL,
   `f_status` int unsigned NOT NULL,
@@ -24,9 +25,10 @@ CREATE TABLE `t_clearing_result` (
   `f_base_frozen` varchar(20) NOT NULL,
   `f_quote_charge` varchar(18) NOT NULL,
   `f_base_charge` varchar(18) NOT NULL,
+  `f_fee` varchar(18) NOT NULL DEFAULT '0',
   `f_timestamp` timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
   PRIMARY KEY (`f_id`),
-  UNIQUE KEY `f_event_id` (`f_event_id`,`f_order_id`)
+  UNIQUE KEY `f_event_id` (`f_event_id`,`f_seq`,`f_order_id`)
 ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
 
 -- initializing a new trading pair should create a table like t_clearing_result
@@ -15,7 +15,8 @@
 use crate::{
     assets,
     core::*,
-    matcher::{Match, Role, State},
+    matcher::{Match, OrderType, Role, State},
+    fees::{FeeRates, FeeSchedule},
     orderbook::AskOrBid,
     output::Output,
 };
@@ -23,6 +24,7 @@ use rust_decimal::{prelude::Zero, Decimal};
 
 pub fn clear(
     accounts: &mut Accounts,
+    fees: &mut FeeSchedule,
     event_id: u64,
     symbol: &Symbol,
     taker_fee: Fee,
@@ -32,8 +34,24 @@ pub fn clear(
 ) -> Vec<Output> {
     let base = symbol.0;
     let quote = symbol.1;
+    let symbol_rates = FeeRates {
+        taker_fee,
+        maker_fee,
+    };
+    // the rates are taken before the volume of this match is counted
+    let taker_fee = fees.rates(&mr.taker.user_id, quote, symbol_rates).taker_fee;
     match mr.taker.state {
-        State::Submitted => {
+        // the freeze delta of a replaced order is settled before matching, the makers
+        // canceled by self-trade prevention are settled below
+        State::Submitted
+        | State::Replaced
+        | State::Triggering
+        | State::Rejected
+        | State::Duplicated
+        | State::QuoteKept
+        | State::QuoteDropped
+            if mr.maker.is_empty() && mr.taker.canceled.is_zero() =>
+        {
             let base_account = assets::get_balance_to_owned(accounts, &mr.taker.user_id, base);
             let quote_account = assets::get_balance_to_owned(accounts, &mr.taker.user_id, quote);
             vec![Output {
@@ -43,7 +61,11 @@ pub fn clear(
                 symbol: *symbol,
                 role: Role::Taker,
                 state: mr.taker.state,
-                ask_or_bid: AskOrBid::Ask,
+                // a retry is answered with the side of the original order
+                ask_or_bid: match mr.taker.state {
+                    State::Duplicated => mr.taker.ask_or_bid,
+                    _ => AskOrBid::Ask,
+                },
                 price: mr.taker.price,
                 base_delta: Amount::zero(),
                 quote_delta: Amount::zero(),
@@ -53,10 +75,43 @@ pub fn clear(
                 quote_available: quote_account.available,
                 base_frozen: base_account.frozen,
                 quote_frozen: quote_account.frozen,
+                fee: Fee::zero(),
                 timestamp: time,
             }]
         }
-        State::Canceled => {
+        // the OCO siblings release the funds computed by the matcher only
+        State::OcoCanceled | State::OcoReduced if mr.maker.is_empty() => {
+            let c = match mr.taker.ask_or_bid {
+                AskOrBid::Ask => base,
+                AskOrBid::Bid => quote,
+            };
+            if !mr.taker.canceled.is_zero() {
+                assets::try_unfreeze(accounts, &mr.taker.user_id, c, mr.taker.canceled).unwrap();
+            }
+            let base_account = assets::get_balance_to_owned(accounts, &mr.taker.user_id, base);
+            let quote_account = assets::get_balance_to_owned(accounts, &mr.taker.user_id, quote);
+            vec![Output {
+                event_id,
+                order_id: mr.taker.order_id,
+                user_id: mr.taker.user_id,
+                symbol: *symbol,
+                role: Role::Taker,
+                state: mr.taker.state,
+                ask_or_bid: mr.taker.ask_or_bid,
+                price: mr.taker.price,
+                base_delta: Amount::zero(),
+                quote_delta: Amount::zero(),
+                base_charge: Amount::zero(),
+                quote_charge: Amount::zero(),
+                base_available: base_account.available,
+                quote_available: quote_account.available,
+                base_frozen: base_account.frozen,
+                quote_frozen: quote_account.frozen,
+                fee: Fee::zero(),
+                timestamp: time,
+            }]
+        }
+        State::Canceled | State::Expired => {
             match mr.taker.ask_or_bid {
                 AskOrBid::Ask => {
                     // revert base
@@ -83,18 +138,17 @@ pub fn clear(
                         quote_available: quote_account.available,
                         base_frozen: base_account.frozen,
                         quote_frozen: quote_account.frozen,
+                        fee: Fee::zero(),
                         timestamp: time,
                     }]
                 }
                 AskOrBid::Bid => {
-                    // revert quote
-                    assets::try_unfreeze(
-                        accounts,
-                        &mr.taker.user_id,
-                        quote,
-                        mr.taker.unfilled * mr.taker.price,
-                    )
-                    .unwrap();
+                    // revert quote, the unfilled of a market bid is measured in quote
+                    let unfilled = match mr.taker.order_type {
+                        OrderType::Limit => mr.taker.unfilled * mr.taker.price,
+                        OrderType::Market => mr.taker.unfilled,
+                    };
+                    assets::try_unfreeze(accounts, &mr.taker.user_id, quote, unfilled).unwrap();
                     let base_account =
                         assets::get_balance_to_owned(accounts, &mr.taker.user_id, base);
                     let quote_account =
@@ -116,12 +170,14 @@ pub fn clear(
                         quote_available: quote_account.available,
                         base_frozen: base_account.frozen,
                         quote_frozen: quote_account.frozen,
+                        fee: Fee::zero(),
                         timestamp: time,
                     }]
                 }
             }
         }
-        // Filled, PartialFilled, ConditionalCanceled
+        // Filled, PartialFilled, ConditionalCanceled, IocCanceled, FokKilled, PostOnlyRejected,
+        // or any taker meeting self-trade prevention
         _ => {
             match mr.taker.ask_or_bid {
                 AskOrBid::Ask => {
@@ -134,17 +190,36 @@ pub fn clear(
                         base_sum += m.filled;
                         let quote_decr = m.filled * m.price;
                         quote_sum += quote_decr;
+                        // maker canceled by self-trade prevention
+                        if m.canceled > Decimal::zero() {
+                            assets::try_unfreeze(accounts, &m.user_id, quote, m.canceled * m.price)
+                                .unwrap();
+                        }
                         // maker is bid, incr base available(filled), decr quote frozen(quot_decr)
                         assets::add_to_available(accounts, &m.user_id, base, m.filled).unwrap();
                         let quote_account =
                             assets::deduct_frozen(accounts, &m.user_id, quote, quote_decr).unwrap();
                         // charge fee for maker
                         // maker is bid, incr base, decr quote, so we charge base
-                        let charge_fee = m.filled * maker_fee;
+                        let fee = fees.rates(&m.user_id, quote, symbol_rates).maker_fee;
+                        let fee = fee.max(-taker_fee);
+                        fees.add_volume(&m.user_id, quote, quote_decr);
+                        let charge_fee = m.filled * fee.max(Fee::zero());
                         let base_account =
                             assets::deduct_available(accounts, &m.user_id, base, charge_fee)
                                 .unwrap();
                         assets::add_to_available(accounts, &SYSTEM, base, charge_fee).unwrap();
+                        // a negative fee is a rebate in quote out of the taker fee of the fill
+                        let rebate = quote_decr * (-fee).max(Fee::zero());
+                        let quote_account = match rebate.is_zero() {
+                            true => quote_account,
+                            false => {
+                                assets::add_to_available(accounts, &SYSTEM, quote, -rebate)
+                                    .unwrap();
+                                assets::add_to_available(accounts, &m.user_id, quote, rebate)
+                                    .unwrap()
+                            }
+                        };
                         cr.push(Output {
                             event_id,
                             order_id: m.order_id,
@@ -157,25 +232,31 @@ pub fn clear(
                             base_delta: m.filled,
                             quote_delta: -quote_decr,
                             base_charge: -charge_fee,
-                            quote_charge: Decimal::zero(),
+                            quote_charge: rebate,
                             base_available: base_account.available,
                             quote_available: quote_account.available,
                             base_frozen: base_account.frozen,
                             quote_frozen: quote_account.frozen,
+                            fee,
                             timestamp: time,
                         });
                     }
                     // taker base account frozen decr sum(filled)
                     // taker quote account available incr sum(filled * price)
-                    if mr.taker.state == State::ConditionalCanceled {
+                    if mr.taker.state.releases_unfilled() {
                         assets::try_unfreeze(accounts, &mr.taker.user_id, base, mr.taker.unfilled)
                             .unwrap();
                     }
+                    if mr.taker.canceled > Decimal::zero() {
+                        assets::try_unfreeze(accounts, &mr.taker.user_id, base, mr.taker.canceled)
+                            .unwrap();
+                    }
                     let base_account =
                         assets::deduct_frozen(accounts, &mr.taker.user_id, base, base_sum).unwrap();
                     assets::add_to_available(accounts, &mr.taker.user_id, quote, quote_sum)
                         .unwrap();
                     // charge fee for taker
+                    fees.add_volume(&mr.taker.user_id, quote, quote_sum);
                     let charge_fee = quote_sum * taker_fee;
                     // taker is ask, incr quote, decr base, so we charge quote
                     let quote_account =
@@ -199,6 +280,7 @@ pub fn clear(
                         quote_available: quote_account.available,
                         base_frozen: base_account.frozen,
                         quote_frozen: quote_account.frozen,
+                        fee: taker_fee,
                         timestamp: time,
                     });
                     cr
@@ -219,17 +301,34 @@ pub fn clear(
                         let quote_incr = m.filled * m.price;
                         quote_sum += quote_incr;
                         return_quote += m.filled * mr.taker.price - m.filled * m.price;
+                        // maker canceled by self-trade prevention
+                        if m.canceled > Decimal::zero() {
+                            assets::try_unfreeze(accounts, &m.user_id, base, m.canceled).unwrap();
+                        }
                         // maker is ask, incr quote available(quote_incr), decr base frozen(filled)
                         let base_account =
                             assets::deduct_frozen(accounts, &m.user_id, base, m.filled).unwrap();
                         assets::add_to_available(accounts, &m.user_id, quote, quote_incr).unwrap();
                         // charge fee for maker
                         // maker is ask, incr quote, decr base, so we charge quote
-                        let charge_fee = quote_incr * maker_fee;
+                        let fee = fees.rates(&m.user_id, quote, symbol_rates).maker_fee;
+                        let fee = fee.max(-taker_fee);
+                        fees.add_volume(&m.user_id, quote, quote_incr);
+                        let charge_fee = quote_incr * fee.max(Fee::zero());
                         let quote_account =
                             assets::deduct_available(accounts, &m.user_id, quote, charge_fee)
                                 .unwrap();
                         assets::add_to_available(accounts, &SYSTEM, quote, charge_fee).unwrap();
+                        // a negative fee is a rebate in base out of the taker fee of the fill
+                        let rebate = m.filled * (-fee).max(Fee::zero());
+                        let base_account = match rebate.is_zero() {
+                            true => base_account,
+                            false => {
+                                assets::add_to_available(accounts, &SYSTEM, base, -rebate).unwrap();
+                                assets::add_to_available(accounts, &m.user_id, base, rebate)
+                                    .unwrap()
+                            }
+                        };
                         cr.push(Output {
                             event_id,
                             order_id: m.order_id,
@@ -241,12 +340,13 @@ pub fn clear(
                             price: m.price,
                             base_delta: -m.filled,
                             quote_delta: quote_incr,
-                            base_charge: Decimal::zero(),
+                            base_charge: rebate,
                             quote_charge: -charge_fee,
                             base_available: base_account.available,
                             quote_available: quote_account.available,
                             base_frozen: base_account.frozen,
                             quote_frozen: quote_account.frozen,
+                            fee,
                             timestamp: time,
                         });
                     }
@@ -255,6 +355,7 @@ pub fn clear(
                     assets::add_to_available(accounts, &mr.taker.user_id, base, base_sum).unwrap();
                     assets::deduct_frozen(accounts, &mr.taker.user_id, quote, quote_sum).unwrap();
                     // charge fee for taker
+                    fees.add_volume(&mr.taker.user_id, quote, quote_sum);
                     let charge_fee = base_sum * taker_fee;
                     // taker is bid, incr base, decr quote, so we charge base
                     assets::deduct_available(accounts, &mr.taker.user_id, base, charge_fee)
@@ -269,18 +370,48 @@ pub fn clear(
                     //  ...
                     //   +
                     // bid_price(taker) * maker_filledn - ask_pricen(makern) * maker_filledn
-                    if return_quote > Decimal::zero() {
-                        assets::try_unfreeze(accounts, &mr.taker.user_id, quote, return_quote)
-                            .unwrap();
-                    }
-                    if mr.taker.state == State::ConditionalCanceled {
-                        assets::try_unfreeze(
-                            accounts,
-                            &mr.taker.user_id,
-                            quote,
-                            mr.taker.unfilled * mr.taker.price,
-                        )
-                        .unwrap();
+                    match mr.taker.order_type {
+                        OrderType::Limit => {
+                            if return_quote > Decimal::zero() {
+                                assets::try_unfreeze(
+                                    accounts,
+                                    &mr.taker.user_id,
+                                    quote,
+                                    return_quote,
+                                )
+                                .unwrap();
+                            }
+                            if mr.taker.state.releases_unfilled() {
+                                assets::try_unfreeze(
+                                    accounts,
+                                    &mr.taker.user_id,
+                                    quote,
+                                    mr.taker.unfilled * mr.taker.price,
+                                )
+                                .unwrap();
+                            }
+                            if mr.taker.canceled > Decimal::zero() {
+                                assets::try_unfreeze(
+                                    accounts,
+                                    &mr.taker.user_id,
+                                    quote,
+                                    mr.taker.canceled * mr.taker.price,
+                                )
+                                .unwrap();
+                            }
+                        }
+                        // market bid froze the whole quote budget, return what is left
+                        OrderType::Market => {
+                            if mr.taker.unfilled + mr.taker.canceled > Decimal::zero() {
+                                assets::try_unfreeze(
+                                    accounts,
+                                    &mr.taker.user_id,
+                                    quote,
+                                    mr.taker.unfilled + mr.taker.canceled,
+                                )
+                                .unwrap();
+                            }
+                        }
                     }
                     let base_account =
                         assets::get_balance_to_owned(accounts, &mr.taker.user_id, base);
@@ -303,6 +434,7 @@ pub fn clear(
                         quote_available: quote_account.available,
                         base_frozen: base_account.frozen,
                         quote_frozen: quote_account.frozen,
+                        fee: taker_fee,
                         timestamp: time,
                     });
                     cr
@@ -317,6 +449,7 @@ pub fn clear(
 pub mod test {
     use crate::assets;
     use crate::core::*;
+    use crate::fees::{FeeRates, FeeSchedule};
     use crate::matcher::*;
     use crate::orderbook::*;
     use rust_decimal::{prelude::Zero, Decimal};
@@ -360,6 +493,7 @@ pub mod test {
         };
         super::clear(
             &mut accounts,
+            &mut FeeSchedule::default(),
             1,
             &symbol,
             Decimal::zero(),
@@ -410,6 +544,7 @@ pub mod test {
         };
         super::clear(
             &mut accounts,
+            &mut FeeSchedule::default(),
             1,
             &symbol,
             Decimal::zero(),
@@ -453,6 +588,7 @@ pub mod test {
         };
         super::clear(
             &mut accounts,
+            &mut FeeSchedule::default(),
             1,
             &symbol,
             Decimal::zero(),
@@ -485,6 +621,7 @@ pub mod test {
         };
         super::clear(
             &mut accounts,
+            &mut FeeSchedule::default(),
             2,
             &symbol,
             Decimal::zero(),
@@ -527,6 +664,7 @@ pub mod test {
         };
         super::clear(
             &mut accounts,
+            &mut FeeSchedule::default(),
             2,
             &symbol,
             Decimal::zero(),
@@ -561,6 +699,7 @@ pub mod test {
         };
         super::clear(
             &mut accounts,
+            &mut FeeSchedule::default(),
             2,
             &symbol,
             Decimal::zero(),
@@ -596,7 +735,16 @@ pub mod test {
             )],
             taker: Taker::taker_filled(UserId::from_low_u64_be(2), 2, dec!(9999), AskOrBid::Ask),
         };
-        super::clear(&mut accounts, 2, &symbol, dec!(0.001), dec!(0.001), &mr, 0);
+        super::clear(
+            &mut accounts,
+            &mut FeeSchedule::default(),
+            2,
+            &symbol,
+            dec!(0.001),
+            dec!(0.001),
+            &mr,
+            0,
+        );
 
         let b2_100 = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(2), 100);
         assert_eq!(dec!(9990), b2_100.available);
@@ -613,6 +761,65 @@ pub mod test {
         assert_eq!(Decimal::zero(), b1_101.frozen);
     }
 
+    #[test]
+    pub fn test_clearing_on_maker_rebate() {
+        let mut accounts = Accounts::new();
+        let mut fees = FeeSchedule::default();
+        // maker: bid 2 btc price 10000
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(1), 100, dec!(20000));
+        assets::try_freeze(&mut accounts, &UserId::from_low_u64_be(1), 100, dec!(20000)).unwrap();
+        // taker: ask 2 btc price 9999
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(2), 101, dec!(2));
+        assets::try_freeze(&mut accounts, &UserId::from_low_u64_be(2), 101, dec!(2)).unwrap();
+        let symbol = (101, 100);
+        let mr = Match {
+            maker: vec![Maker::maker_filled(
+                UserId::from_low_u64_be(1),
+                1,
+                dec!(10000),
+                dec!(1),
+            )],
+            taker: Taker::taker_filled(UserId::from_low_u64_be(2), 2, dec!(9999), AskOrBid::Ask),
+        };
+        let cr =
+            super::clear(&mut accounts, &mut fees, 2, &symbol, dec!(0.002), dec!(-0.001), &mr, 0);
+        // the maker receives the rebate in quote, funded by the taker fee
+        assert_eq!(dec!(10), cr[0].quote_charge);
+        let b1_100 = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(1), 100);
+        assert_eq!(dec!(10), b1_100.available);
+        assert_eq!(dec!(10000), b1_100.frozen);
+        let b1_101 = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(1), 101);
+        assert_eq!(dec!(1), b1_101.available);
+        let b2_100 = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(2), 100);
+        assert_eq!(dec!(9980), b2_100.available);
+        let system = assets::get_balance_to_owned(&accounts, &SYSTEM, 100);
+        assert_eq!(dec!(10), system.available);
+
+        // the rebate is capped by the taker fee of the same fill
+        fees.update_override(
+            UserId::from_low_u64_be(1),
+            Some(FeeRates {
+                taker_fee: dec!(0.002),
+                maker_fee: dec!(-0.005),
+            }),
+        );
+        let mr = Match {
+            maker: vec![Maker::maker_filled(
+                UserId::from_low_u64_be(1),
+                1,
+                dec!(10000),
+                dec!(1),
+            )],
+            taker: Taker::taker_filled(UserId::from_low_u64_be(2), 3, dec!(9999), AskOrBid::Ask),
+        };
+        let cr = super::clear(&mut accounts, &mut fees, 3, &symbol, dec!(0.002), dec!(0), &mr, 0);
+        assert_eq!(dec!(-0.002), cr[0].fee);
+        let b1_100 = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(1), 100);
+        assert_eq!(dec!(30), b1_100.available);
+        let system = assets::get_balance_to_owned(&accounts, &SYSTEM, 100);
+        assert_eq!(dec!(10), system.available);
+    }
+
     #[test]
     pub fn test_self_trade() {
         let base_scale = 6;
@@ -645,6 +852,8 @@ pub mod test {
             price,
             amount,
             AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
 
         let price = dec!(13333);
@@ -666,10 +875,21 @@ pub mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
 
         let symbol = (101, 100);
-        let out = super::clear(&mut accounts, 2, &symbol, taker_fee, maker_fee, &mr, 0);
+        let out = super::clear(
+            &mut accounts,
+            &mut FeeSchedule::default(),
+            2,
+            &symbol,
+            taker_fee,
+            maker_fee,
+            &mr,
+            0,
+        );
         assert_eq!(out[0].base_delta, Decimal::zero());
         assert_eq!(out[0].quote_delta, Decimal::zero());
         assert_eq!(out[0].base_charge, Decimal::zero());
@@ -720,6 +940,8 @@ pub mod test {
             price,
             amount,
             AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
 
         let price = dec!(13333);
@@ -738,10 +960,21 @@ pub mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
 
         let symbol = (101, 100);
-        let out = super::clear(&mut accounts, 2, &symbol, taker_fee, maker_fee, &mr, 0);
+        let out = super::clear(
+            &mut accounts,
+            &mut FeeSchedule::default(),
+            2,
+            &symbol,
+            taker_fee,
+            maker_fee,
+            &mr,
+            0,
+        );
         assert_eq!(out[0].base_delta, dec!(-0.1));
         assert_eq!(out[0].quote_delta, dec!(1000));
         assert_eq!(out[0].base_charge, Decimal::zero());
@@ -808,6 +1041,8 @@ pub mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
 
         let price = dec!(10000);
@@ -820,10 +1055,21 @@ pub mod test {
             price,
             amount,
             AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
 
         let symbol = (101, 100);
-        let out = super::clear(&mut accounts, 2, &symbol, taker_fee, maker_fee, &mr, 0);
+        let out = super::clear(
+            &mut accounts,
+            &mut FeeSchedule::default(),
+            2,
+            &symbol,
+            taker_fee,
+            maker_fee,
+            &mr,
+            0,
+        );
         // 2: maker bid
         assert_eq!(out[0].base_delta, dec!(0.1));
         assert_eq!(out[0].quote_delta, dec!(-1333.3));
@@ -851,4 +1097,354 @@ pub mod test {
         assert_eq!(system_100, dec!(1.3333));
         assert_eq!(system_101, dec!(0.0001));
     }
+
+    #[test]
+    pub fn test_clearing_on_market_bid() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            dec!(0.001),
+            dec!(0.001),
+            dec!(1),
+            dec!(1),
+            true,
+            true,
+        );
+        let mut accounts = Accounts::new();
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(1), 100, dec!(100));
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(2), 101, dec!(1));
+        assets::try_freeze(&mut accounts, &UserId::from_low_u64_be(2), 101, dec!(1)).unwrap();
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(2),
+            1,
+            dec!(10),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        // budget 15 only spends 10 since the book is exhausted
+        assets::try_freeze(&mut accounts, &UserId::from_low_u64_be(1), 100, dec!(15)).unwrap();
+        let mr = execute_market(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            2,
+            dec!(15),
+            AskOrBid::Bid,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::ConditionalCanceled);
+        let symbol = (101, 100);
+        let out = super::clear(
+            &mut accounts,
+            &mut FeeSchedule::default(),
+            2,
+            &symbol,
+            dec!(0.001),
+            dec!(0.001),
+            &mr,
+            0,
+        );
+        assert_eq!(out[1].base_delta, dec!(1));
+        assert_eq!(out[1].quote_delta, dec!(-10));
+        let b1_100 = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(1), 100);
+        assert_eq!(b1_100.available, dec!(90));
+        assert_eq!(b1_100.frozen, Decimal::zero());
+        let b1_101 = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(1), 101);
+        assert_eq!(b1_101.available, dec!(0.999));
+        let b2_100 = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(2), 100);
+        assert_eq!(b2_100.available, dec!(9.99));
+    }
+
+    #[test]
+    pub fn test_clearing_on_ioc_bid() {
+        let mut accounts = Accounts::new();
+        let symbol = (1, 0);
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(1), 0, dec!(100)).unwrap();
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(2), 1, dec!(1)).unwrap();
+        let mut book = OrderBook::new(
+            5,
+            1,
+            Decimal::zero(),
+            Decimal::zero(),
+            dec!(0.1),
+            dec!(0.1),
+            true,
+            true,
+        );
+        assets::try_freeze(&mut accounts, &UserId::from_low_u64_be(2), 1, dec!(1)).unwrap();
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(2),
+            1001,
+            dec!(9),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assets::try_freeze(&mut accounts, &UserId::from_low_u64_be(1), 0, dec!(20)).unwrap();
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1002,
+            dec!(10),
+            dec!(2),
+            AskOrBid::Bid,
+            TimeInForce::ImmediateOrCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::IocCanceled);
+        super::clear(
+            &mut accounts,
+            &mut FeeSchedule::default(),
+            2,
+            &symbol,
+            Decimal::zero(),
+            Decimal::zero(),
+            &mr,
+            0,
+        );
+        let quote = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(1), 0);
+        assert_eq!(quote.available, dec!(91));
+        assert_eq!(quote.frozen, Decimal::zero());
+        let base = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(1), 1);
+        assert_eq!(base.available, dec!(1));
+    }
+
+    #[test]
+    pub fn test_clearing_on_self_trade_prevention() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            Decimal::zero(),
+            Decimal::zero(),
+            dec!(0.1),
+            dec!(0.1),
+            true,
+            true,
+        );
+        let symbol = (1, 0);
+        let mut accounts = Accounts::new();
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(1), 0, dec!(100)).unwrap();
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(1), 1, dec!(2)).unwrap();
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(2), 1, dec!(3)).unwrap();
+        for (user, id, amount) in [(1, 1, dec!(2)), (2, 2, dec!(3))] {
+            assets::try_freeze(&mut accounts, &UserId::from_low_u64_be(user), 1, amount).unwrap();
+            execute_limit(
+                &mut book,
+                UserId::from_low_u64_be(user),
+                id,
+                dec!(10),
+                amount,
+                AskOrBid::Ask,
+                TimeInForce::GoodTillCancel,
+                SelfTradePrevention::CancelNewest,
+            );
+        }
+        assets::try_freeze(&mut accounts, &UserId::from_low_u64_be(1), 0, dec!(40)).unwrap();
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            3,
+            dec!(10),
+            dec!(4),
+            AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::DecrementAndCancel,
+        );
+        let out = super::clear(
+            &mut accounts,
+            &mut FeeSchedule::default(),
+            3,
+            &symbol,
+            Decimal::zero(),
+            Decimal::zero(),
+            &mr,
+            0,
+        );
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[0].state, State::ConditionalCanceled);
+        assert_eq!(out[0].base_delta, Decimal::zero());
+        assert_eq!(out[1].state, State::PartialFilled);
+        assert_eq!(out[2].state, State::Filled);
+        // 2 decremented and 2 bought
+        let base = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(1), 1);
+        assert_eq!(base.available, dec!(4));
+        assert_eq!(base.frozen, Decimal::zero());
+        let quote = assets::get_balance_to_owned(&accounts, &UserId::from_low_u64_be(1), 0);
+        assert_eq!(quote.available, dec!(80));
+        assert_eq!(quote.frozen, Decimal::zero());
+    }
+
+    #[test]
+    pub fn test_clearing_on_expired_bid() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            Decimal::zero(),
+            Decimal::zero(),
+            dec!(0.1),
+            dec!(0.1),
+            true,
+            true,
+        );
+        let mut accounts = Accounts::new();
+        assets::add_to_available(&mut accounts, &UserId::from_low_u64_be(1), 0, dec!(100)).unwrap();
+        assets::try_freeze(&mut accounts, &UserId::from_low_u64_be(1), 0, dec!(20)).unwrap();
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1,
+            dec!(10),
+            dec!(2),
+            AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        book.set_expiry(1, 100);
+        let mr = expire(&mut book, 100).pop().unwrap();
+        let out =
+            super::clear(
+                &mut accounts,
+                &mut FeeSchedule::default(),
+                2,
+                &(1, 0),
+                Decimal::zero(),
+                Decimal::zero(),
+                &mr,
+                100,
+            );
+        assert_eq!(out[0].state, State::Expired);
+        assert_eq!(out[0].quote_available, dec!(100));
+        assert_eq!(out[0].quote_frozen, Decimal::zero());
+    }
+
+    #[test]
+    pub fn test_clearing_on_uncross() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            Decimal::zero(),
+            Decimal::zero(),
+            dec!(0.1),
+            dec!(0.1),
+            true,
+            true,
+        );
+        book.auction = true;
+        let mut accounts = Accounts::new();
+        let (seller, buyer) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+        assets::add_to_available(&mut accounts, &seller, 1, dec!(100)).unwrap();
+        assets::add_to_available(&mut accounts, &buyer, 0, dec!(100)).unwrap();
+        assets::try_freeze(&mut accounts, &seller, 1, dec!(2)).unwrap();
+        assets::try_freeze(&mut accounts, &buyer, 0, dec!(24)).unwrap();
+        for (id, user, price, ask_or_bid) in
+            [(1, seller, dec!(10), AskOrBid::Ask), (2, buyer, dec!(12), AskOrBid::Bid)]
+        {
+            execute_limit(
+                &mut book,
+                user,
+                id,
+                price,
+                dec!(2),
+                ask_or_bid,
+                TimeInForce::GoodTillCancel,
+                SelfTradePrevention::CancelNewest,
+            );
+        }
+        let mr = uncross(&mut book).pop().unwrap();
+        let out =
+            super::clear(
+                &mut accounts,
+                &mut FeeSchedule::default(),
+                3,
+                &(1, 0),
+                Decimal::zero(),
+                Decimal::zero(),
+                &mr,
+                100,
+            );
+        // the maker gets the auction price, the bid gets back what it froze above that
+        assert_eq!(out[0].quote_delta, dec!(20));
+        assert_eq!(out[0].quote_available, dec!(20));
+        assert_eq!(out[1].state, State::Filled);
+        assert_eq!(out[1].base_available, dec!(2));
+        assert_eq!(out[1].quote_available, dec!(80));
+        assert_eq!(out[1].quote_frozen, Decimal::zero());
+    }
+
+    #[test]
+    pub fn test_clearing_on_oco_bid() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            Decimal::zero(),
+            Decimal::zero(),
+            dec!(0.1),
+            dec!(0.1),
+            true,
+            true,
+        );
+        let mut accounts = Accounts::new();
+        let (buyer, seller) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+        assets::add_to_available(&mut accounts, &buyer, 0, dec!(100)).unwrap();
+        assets::add_to_available(&mut accounts, &seller, 1, dec!(100)).unwrap();
+        // frozen once by the higher price of the two legs
+        assets::try_freeze(&mut accounts, &buyer, 0, dec!(24)).unwrap();
+        assets::try_freeze(&mut accounts, &seller, 1, dec!(0.5)).unwrap();
+        let stop = StopOrder {
+            id: 2,
+            user: buyer,
+            ask_or_bid: AskOrBid::Bid,
+            kind: StopKind::StopLoss,
+            trigger: dec!(11),
+            price: Some(dec!(12)),
+            amount: dec!(2),
+            oco: Some(1),
+            trail: None,
+        };
+        let mut matches = execute_oco(
+            &mut book,
+            buyer,
+            1,
+            dec!(8),
+            dec!(2),
+            AskOrBid::Bid,
+            stop,
+            SelfTradePrevention::CancelNewest,
+        );
+        let mr = execute_limit(
+            &mut book,
+            seller,
+            3,
+            dec!(8),
+            dec!(0.5),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        matches.extend(settle_oco(&mut book, &mr));
+        matches.push(mr);
+        let mr = cancel(&mut book, 2).unwrap();
+        matches.extend(settle_oco(&mut book, &mr));
+        matches.push(mr);
+        for mr in &matches {
+            super::clear(
+                &mut accounts,
+                &mut FeeSchedule::default(),
+                4,
+                &(1, 0),
+                Decimal::zero(),
+                Decimal::zero(),
+                mr,
+                100,
+            );
+        }
+        let quote = assets::get_balance_to_owned(&accounts, &buyer, 0);
+        assert_eq!(quote.available, dec!(96));
+        assert_eq!(quote.frozen, Decimal::zero());
+        assert_eq!(assets::get_balance_to_owned(&accounts, &buyer, 1).available, dec!(0.5));
+    }
 }
@@ -14,11 +14,23 @@
 
 #[cfg(feature = "fusotao")]
 use crate::fusotao::GlobalStates;
-use crate::{assets::Balance, orderbook::OrderBook};
+use crate::{
+    assets::Balance,
+    currency::CurrencyRegistry,
+    fees::FeeSchedule,
+    orderbook::{ClientOrders, OrderBook},
+    snapshot::DataV1,
+    symbol::DelistReport,
+};
 use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::File, io::{BufReader, BufWriter}};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+};
 
 pub use crate::event::InOrOut;
 pub use crate::matcher::{Role, State as OrderState};
@@ -33,6 +45,8 @@ pub type Currency = u32;
 pub type Symbol = (Base, Quote);
 pub type EventId = u64;
 pub type OrderId = u64;
+/// attached by clients, unique per user
+pub type ClientOrderId = String;
 pub type Fee = Decimal;
 pub type Scale = u32;
 pub type Timestamp = u64;
@@ -141,6 +155,12 @@ impl sp_core::crypto::Ss58Codec for B256 {}
 
 pub const SYSTEM: UserId = UserId::zero();
 
+/// bump it with a migration from the previous layout once `Data` changes
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// the snapshots of version 1 have no header and start with the length of the orderbooks
+const SNAPSHOT_MAGIC: [u8; 8] = *b"GALOIS\0\0";
+
 #[must_use]
 pub fn max_number() -> Amount {
     u64::MAX.into()
@@ -150,6 +170,11 @@ pub fn max_number() -> Amount {
 pub struct Data {
     pub orderbooks: HashMap<Symbol, OrderBook>,
     pub accounts: Accounts,
+    pub fees: FeeSchedule,
+    /// the reports of the delisted symbols
+    pub delisted: HashMap<Symbol, DelistReport>,
+    pub currencies: CurrencyRegistry,
+    pub client_orders: ClientOrders,
     #[cfg(feature = "fusotao")]
     pub merkle_tree: GlobalStates,
 }
@@ -161,6 +186,10 @@ impl Data {
         Self {
             orderbooks: HashMap::new(),
             accounts: HashMap::new(),
+            fees: FeeSchedule::default(),
+            delisted: HashMap::new(),
+            currencies: CurrencyRegistry::default(),
+            client_orders: ClientOrders::default(),
             #[cfg(feature = "fusotao")]
             merkle_tree: GlobalStates::default(),
         }
@@ -169,12 +198,29 @@ impl Data {
     pub fn from_raw(file: File) -> anyhow::Result<Self> {
         let reader = BufReader::new(file);
         let mut decompress = ZlibDecoder::new(reader);
-        Ok(bincode::deserialize_from(&mut decompress)?)
+        let mut header = [0u8; 8];
+        decompress.read_exact(&mut header)?;
+        let mut data: Self = if header == SNAPSHOT_MAGIC {
+            let version: u32 = bincode::deserialize_from(&mut decompress)?;
+            anyhow::ensure!(
+                version == SNAPSHOT_VERSION,
+                "unsupported snapshot version {}",
+                version
+            );
+            bincode::deserialize_from(&mut decompress)?
+        } else {
+            let v1: DataV1 = bincode::deserialize_from((&header[..]).chain(decompress))?;
+            v1.try_into()?
+        };
+        data.orderbooks.values_mut().for_each(OrderBook::rebuild_user_indices);
+        Ok(data)
     }
 
     pub fn into_raw(&self, file: File) -> anyhow::Result<()> {
         let writer = BufWriter::new(file);
         let mut compress = ZlibEncoder::new(writer, Compression::best());
+        compress.write_all(&SNAPSHOT_MAGIC)?;
+        bincode::serialize_into(&mut compress, &SNAPSHOT_VERSION)?;
         bincode::serialize_into(&mut compress, &self)?;
         Ok(())
     }
@@ -189,7 +235,7 @@ pub fn test_dump() {
     let v = bincode::serialize(&order).unwrap();
     let des: Order = bincode::deserialize(&v).unwrap();
     assert_eq!(des, order);
-    let orderbook = OrderBook::new(
+    let mut orderbook = OrderBook::new(
         3,
         3,
         dec!(0.001),
@@ -199,23 +245,14 @@ pub fn test_dump() {
         false,
         true,
     );
+    orderbook.insert(order, crate::orderbook::AskOrBid::Bid);
     let v = bincode::serialize(&orderbook).unwrap();
-    let des: OrderBook = bincode::deserialize(&v).unwrap();
+    let mut des: OrderBook = bincode::deserialize(&v).unwrap();
+    assert!(des.user_indices.is_empty());
+    des.rebuild_user_indices();
     assert_eq!(des, orderbook);
     let mut test = Data::new();
-    test.orderbooks.insert(
-        (101, 100),
-        OrderBook::new(
-            3,
-            3,
-            dec!(0.001),
-            dec!(0.001),
-            dec!(0.001),
-            dec!(1.0),
-            false,
-            true,
-        ),
-    );
+    test.orderbooks.insert((101, 100), orderbook);
     let temp_dir = tempdir::TempDir::new(".").unwrap();
     let file_path = temp_dir.path().join("bin.gz");
     let temp_file = File::create(&file_path).unwrap();
@@ -225,6 +262,87 @@ pub fn test_dump() {
     assert_eq!(test.accounts, de.accounts);
 }
 
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_load_v1_snapshot() {
+    use crate::orderbook::{Order, Slot};
+    use crate::snapshot::*;
+    use std::convert::TryFrom;
+    use linked_hash_map::LinkedHashMap;
+    use rust_decimal_macros::dec;
+    use std::collections::BTreeMap;
+
+    let alice = UserId::from_low_u64_be(1);
+    let mut orders = LinkedHashMap::new();
+    for id in vec![3, 2] {
+        let order = OrderV1 {
+            id,
+            user: alice,
+            price: dec!(10),
+            unfilled: dec!(1),
+        };
+        orders.insert(id, order);
+    }
+    let page = OrderPageV1 {
+        orders,
+        amount: dec!(2),
+        price: dec!(10),
+    };
+    let v1 = DataV1 {
+        orderbooks: vec![(
+            (101, 100),
+            OrderBookV1 {
+                asks: BTreeMap::new(),
+                bids: vec![(dec!(10), page)].into_iter().collect(),
+                indices: vec![(3, dec!(10)), (2, dec!(10))].into_iter().collect(),
+                base_scale: 3,
+                quote_scale: 3,
+                taker_fee: dec!(0.001),
+                maker_fee: dec!(0.001),
+                min_amount: dec!(0.001),
+                min_vol: dec!(1),
+                enable_market_order: false,
+                open: true,
+                max_id: 5,
+            },
+        )]
+        .into_iter()
+        .collect(),
+        accounts: HashMap::new(),
+    };
+    let temp_dir = tempdir::TempDir::new(".").unwrap();
+    let file_path = temp_dir.path().join("v1.gz");
+    let mut compress = ZlibEncoder::new(File::create(&file_path).unwrap(), Compression::best());
+    bincode::serialize_into(&mut compress, &v1).unwrap();
+    compress.finish().unwrap();
+
+    let data = Data::from_raw(File::open(&file_path).unwrap()).unwrap();
+    let orderbook = data.orderbooks.get(&(101, 100)).unwrap();
+    // the time priority is kept
+    let ids = orderbook.bids[&dec!(10)].orders.keys().copied().collect::<Vec<_>>();
+    assert_eq!(vec![3, 2], ids);
+    assert_eq!(
+        Order::new(2, alice, dec!(10), dec!(1)),
+        orderbook.bids[&dec!(10)].orders[&2]
+    );
+    assert_eq!(dec!(2), orderbook.bids[&dec!(10)].amount);
+    assert_eq!(5, orderbook.max_id);
+    assert!(orderbook.validate_id(5).is_err());
+    assert_eq!(2, orderbook.user_indices[&alice].len());
+    let slot = Slot {
+        ask_or_bid: AskOrBid::Bid,
+        price: dec!(10),
+    };
+    assert_eq!(Some(&slot), orderbook.indices.get(&3));
+    assert!(data.delisted.is_empty());
+
+    // the legacy indices must agree with the tapes
+    let mut corrupted = v1;
+    let orderbook = corrupted.orderbooks.get_mut(&(101, 100)).unwrap();
+    orderbook.indices.insert(3, dec!(11));
+    assert!(Data::try_from(corrupted).is_err());
+}
+
 #[test]
 #[cfg(not(feature = "fusotao"))]
 pub fn test_debug_b256() {
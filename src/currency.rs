@@ -0,0 +1,145 @@
+// Copyright 2021 UINB Technologies Pte. Ltd.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::core::*;
+use anyhow::{anyhow, ensure};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct CurrencyConfig {
+    /// the max scale of the amounts transferred
+    pub decimals: Scale,
+    pub min_transfer: Amount,
+    pub max_transfer: Option<Amount>,
+    /// a disabled currency can't be transferred or traded
+    pub enabled: bool,
+}
+
+/// the registry is enforced once any currency is registered, so the sequences before it are
+/// replayed as they were executed
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct CurrencyRegistry {
+    pub currencies: HashMap<Currency, CurrencyConfig>,
+}
+
+impl CurrencyRegistry {
+    pub fn get(&self, currency: Currency) -> Option<&CurrencyConfig> {
+        self.currencies.get(&currency)
+    }
+
+    pub fn update(&mut self, currency: Currency, config: CurrencyConfig) {
+        self.currencies.insert(currency, config);
+    }
+
+    fn enabled(&self, currency: Currency) -> anyhow::Result<Option<&CurrencyConfig>> {
+        if self.currencies.is_empty() {
+            return Ok(None);
+        }
+        let config = self
+            .get(currency)
+            .ok_or_else(|| anyhow!("currency {} not registered", currency))?;
+        ensure!(config.enabled, "currency {} disabled", currency);
+        Ok(Some(config))
+    }
+
+    pub fn validate_transfer(&self, currency: Currency, amount: Amount) -> anyhow::Result<()> {
+        if let Some(config) = self.enabled(currency)? {
+            ensure!(
+                amount.normalize().scale() <= config.decimals,
+                "amount {} exceeds decimals {}",
+                amount,
+                config.decimals
+            );
+            ensure!(
+                amount >= config.min_transfer,
+                "amount {} less than min transfer {}",
+                amount,
+                config.min_transfer
+            );
+            if let Some(max) = config.max_transfer {
+                ensure!(amount <= max, "amount {} greater than max transfer {}", amount, max);
+            }
+        }
+        Ok(())
+    }
+
+    /// both currencies of the symbol are registered and enabled, and the scales of the symbol
+    /// fit their decimals
+    pub fn validate_symbol(
+        &self,
+        symbol: &Symbol,
+        base_scale: Scale,
+        quote_scale: Scale,
+    ) -> anyhow::Result<()> {
+        if let Some(base) = self.enabled(symbol.0)? {
+            ensure!(base_scale <= base.decimals, "base scale exceeds decimals");
+        }
+        if let Some(quote) = self.enabled(symbol.1)? {
+            ensure!(quote_scale <= quote.decimals, "quote scale exceeds decimals");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    pub fn test_currency_registry() {
+        let mut registry = CurrencyRegistry::default();
+        // nothing is checked before any currency is registered
+        assert!(registry.validate_transfer(100, dec!(0.123456789)).is_ok());
+        assert!(registry.validate_symbol(&(101, 100), 4, 4).is_ok());
+        registry.update(
+            100,
+            CurrencyConfig {
+                decimals: 6,
+                min_transfer: dec!(1),
+                max_transfer: Some(dec!(1000)),
+                enabled: true,
+            },
+        );
+        assert!(registry.validate_transfer(100, dec!(1.000000)).is_ok());
+        assert!(registry.validate_transfer(100, dec!(1.0000001)).is_err());
+        assert!(registry.validate_transfer(100, dec!(0.5)).is_err());
+        assert!(registry.validate_transfer(100, dec!(1001)).is_err());
+        assert!(registry.validate_transfer(101, dec!(1)).is_err());
+        assert!(registry.validate_symbol(&(101, 100), 4, 4).is_err());
+        registry.update(
+            101,
+            CurrencyConfig {
+                decimals: 8,
+                min_transfer: dec!(0),
+                max_transfer: None,
+                enabled: true,
+            },
+        );
+        assert!(registry.validate_symbol(&(101, 100), 4, 4).is_ok());
+        assert!(registry.validate_symbol(&(101, 100), 4, 7).is_err());
+        registry.update(
+            101,
+            CurrencyConfig {
+                decimals: 8,
+                min_transfer: dec!(0),
+                max_transfer: None,
+                enabled: false,
+            },
+        );
+        assert!(registry.validate_symbol(&(101, 100), 4, 4).is_err());
+        assert!(registry.validate_transfer(101, dec!(1)).is_err());
+    }
+}
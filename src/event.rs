@@ -12,9 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::{assets, clearing, core::*, matcher, orderbook::*, output, sequence, server, snapshot};
+use crate::{
+    assets, clearing, core::*, currency::CurrencyConfig, fees::*, matcher, orderbook::*, output,
+    sequence, server, snapshot, symbol::SymbolConfig,
+};
 use anyhow::anyhow;
 use cfg_if::cfg_if;
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::sync::{
@@ -27,12 +31,37 @@ use thiserror::Error;
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Event {
     Limit(EventId, LimitCmd, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    Market(EventId, MarketCmd, Timestamp),
     Cancel(EventId, CancelCmd, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    Replace(EventId, ReplaceCmd, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    Stop(EventId, StopCmd, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    Oco(EventId, OcoCmd, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    Peg(EventId, PegCmd, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    Quote(EventId, QuoteCmd, Timestamp),
     TransferOut(EventId, AssetsCmd, Timestamp),
     TransferIn(EventId, AssetsCmd, Timestamp),
     UpdateSymbol(EventId, SymbolCmd, Timestamp),
+    UpdateCurrency(EventId, CurrencyCmd, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    UpdateFeeTiers(EventId, FeeTiersCmd, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    UpdateUserFee(EventId, UserFeeCmd, Timestamp),
     #[cfg(not(feature = "fusotao"))]
     CancelAll(EventId, Symbol, Timestamp),
+    /// cancel all orders of the symbol and remove it
+    #[cfg(not(feature = "fusotao"))]
+    Delist(EventId, Symbol, Timestamp),
+    CancelUser(EventId, CancelUserCmd, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    Uncross(EventId, Symbol, Timestamp),
+    #[cfg(not(feature = "fusotao"))]
+    Batch(EventId, BatchCmd, Timestamp),
     // special: `EventId` means dump at `EventId`
     Dump(EventId, Timestamp),
 }
@@ -45,17 +74,146 @@ pub struct LimitCmd {
     pub price: Price,
     pub amount: Amount,
     pub ask_or_bid: AskOrBid,
+    #[serde(default)]
+    pub time_in_force: matcher::TimeInForce,
+    /// fallback to the orderbook's if absent
+    #[serde(default)]
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    /// the visible size of an iceberg order
+    #[serde(default)]
+    pub display: Option<Amount>,
+    /// good-till-time, compared with the timestamps of events
+    #[serde(default)]
+    pub expire_at: Option<Timestamp>,
+    #[serde(default)]
+    pub client_order_id: Option<ClientOrderId>,
     #[cfg(feature = "fusotao")]
     pub nonce: u32,
     #[cfg(feature = "fusotao")]
     pub signature: Vec<u8>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MarketCmd {
+    pub symbol: Symbol,
+    pub user_id: UserId,
+    pub order_id: OrderId,
+    /// base amount of asks or quote volume of bids
+    pub amount: Amount,
+    pub ask_or_bid: AskOrBid,
+    /// fallback to the orderbook's if absent
+    #[serde(default)]
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplaceCmd {
+    pub symbol: Symbol,
+    pub user_id: UserId,
+    pub order_id: OrderId,
+    /// keep the current price if absent
+    pub price: Option<Price>,
+    /// the new unfilled amount, keep the current if absent
+    pub amount: Option<Amount>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StopCmd {
+    pub symbol: Symbol,
+    pub user_id: UserId,
+    pub order_id: OrderId,
+    pub kind: StopKind,
+    pub trigger: Price,
+    /// stop-limit if present, otherwise stop-market
+    pub price: Option<Price>,
+    /// base amount, or quote volume of a stop-market bid
+    pub amount: Amount,
+    pub ask_or_bid: AskOrBid,
+    /// a trailing stop-loss starting from the last price if present, `trigger` is ignored
+    #[serde(default)]
+    pub trail: Option<TrailOffset>,
+}
+
+/// a limit order linked with a stop order, either leg filled or triggered cancels the other
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OcoCmd {
+    pub symbol: Symbol,
+    pub user_id: UserId,
+    pub order_id: OrderId,
+    pub price: Price,
+    pub amount: Amount,
+    pub ask_or_bid: AskOrBid,
+    pub stop_order_id: OrderId,
+    pub kind: StopKind,
+    pub trigger: Price,
+    /// stop-limit if present, otherwise stop-market which is available to asks only
+    pub stop_price: Option<Price>,
+}
+
+/// limit orders and cancels of one user executed in order by one event
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchCmd {
+    pub symbol: Symbol,
+    pub user_id: UserId,
+    /// reject the whole batch if any item fails, otherwise the failed items are skipped
+    pub all_or_nothing: bool,
+    pub orders: Vec<BatchItem>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BatchItem {
+    Limit(LimitCmd),
+    Cancel(CancelCmd),
+}
+
+/// a limit order repriced along with the top of the book
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PegCmd {
+    pub symbol: Symbol,
+    pub user_id: UserId,
+    pub order_id: OrderId,
+    pub amount: Amount,
+    pub ask_or_bid: AskOrBid,
+    pub peg: Peg,
+}
+
+/// the whole ladder of a market maker on a symbol
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuoteCmd {
+    pub symbol: Symbol,
+    pub user_id: UserId,
+    pub bids: Vec<QuoteLevel>,
+    pub asks: Vec<QuoteLevel>,
+}
+
+/// a new order id places a new quote, the order id of the current ladder updates the quote
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct QuoteLevel {
+    pub order_id: OrderId,
+    pub price: Price,
+    pub amount: Amount,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CancelCmd {
     pub symbol: Symbol,
     pub user_id: UserId,
+    /// ignored if `client_order_id` is present
     pub order_id: OrderId,
+    #[serde(default)]
+    pub client_order_id: Option<ClientOrderId>,
+    #[cfg(feature = "fusotao")]
+    pub nonce: u32,
+    #[cfg(feature = "fusotao")]
+    pub signature: Vec<u8>,
+}
+
+/// cancel all orders of the user in one symbol or all symbols
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CancelUserCmd {
+    pub user_id: UserId,
+    pub symbol: Option<Symbol>,
+    /// each cancel is proved with the signature of the command
     #[cfg(feature = "fusotao")]
     pub nonce: u32,
     #[cfg(feature = "fusotao")]
@@ -92,22 +250,132 @@ pub struct AssetsCmd {
     pub extrinsic_hash: Vec<u8>,
 }
 
+/// the volume tiers of a quote currency, the volumes are counted from zero again
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FeeTiersCmd {
+    pub quote: Currency,
+    pub tiers: Vec<FeeTier>,
+}
+
+/// register or replace the config of the currency
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CurrencyCmd {
+    pub currency: Currency,
+    pub config: CurrencyConfig,
+}
+
+/// the fees of the user in all symbols, or the tiers and the fees of the symbols if absent
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserFeeCmd {
+    pub user_id: UserId,
+    pub rates: Option<FeeRates>,
+}
+
+/// the fields absent are left unchanged, see `SymbolConfig::merge`
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SymbolCmd {
     pub symbol: Symbol,
-    pub open: bool,
-    pub base_scale: Scale,
-    pub quote_scale: Scale,
-    pub taker_fee: Fee,
-    pub maker_fee: Fee,
-    pub min_amount: Amount,
-    pub min_vol: Vol,
-    pub enable_market_order: bool,
+    pub open: Option<bool>,
+    pub base_scale: Option<Scale>,
+    pub quote_scale: Option<Scale>,
+    pub taker_fee: Option<Fee>,
+    pub maker_fee: Option<Fee>,
+    pub min_amount: Option<Amount>,
+    pub min_vol: Option<Vol>,
+    pub enable_market_order: Option<bool>,
+    #[serde(default)]
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    #[serde(default)]
+    pub matching_algorithm: Option<MatchingAlgorithm>,
+    #[serde(default)]
+    pub tick_size: Option<Price>,
+    #[serde(default)]
+    pub lot_size: Option<Amount>,
+    #[serde(default)]
+    pub max_amount: Option<Amount>,
+    #[serde(default)]
+    pub max_vol: Option<Vol>,
+    #[serde(default)]
+    pub price_band: Option<Decimal>,
+    /// the circuit breaker is enabled with both `halt_band` and `halt_window`
+    #[serde(default)]
+    pub halt_band: Option<Decimal>,
+    #[serde(default)]
+    pub halt_window: Option<Timestamp>,
+    /// enter the auction, it's left by uncrossing only
+    #[serde(default)]
+    pub auction: Option<bool>,
 }
 
 impl Event {
     pub fn is_trading_cmd(&self) -> bool {
-        matches!(self, Event::Limit(_, _, _)) || matches!(self, Event::Cancel(_, _, _))
+        cfg_if! {
+            if #[cfg(not(feature = "fusotao"))] {
+                matches!(self, Event::Market(_, _, _)) || matches!(self, Event::Limit(_, _, _))
+                    || matches!(self, Event::Cancel(_, _, _)) || matches!(self, Event::Replace(_, _, _))
+                    || matches!(self, Event::Stop(_, _, _)) || matches!(self, Event::Oco(_, _, _))
+                    || matches!(self, Event::Peg(_, _, _)) || matches!(self, Event::Quote(_, _, _))
+                    || matches!(self, Event::Uncross(_, _, _))
+                    || matches!(self, Event::Batch(_, _, _))
+                    || matches!(self, Event::CancelUser(_, _, _))
+            } else {
+                matches!(self, Event::Limit(_, _, _)) || matches!(self, Event::Cancel(_, _, _))
+                    || matches!(self, Event::CancelUser(_, _, _))
+            }
+        }
+    }
+
+    /// timestamps of dumps are generated locally, so they won't drive the expiry
+    pub fn expiring_at(&self) -> Option<(EventId, Timestamp)> {
+        match self {
+            Event::Limit(id, _, time)
+            | Event::Cancel(id, _, time)
+            | Event::CancelUser(id, _, time)
+            | Event::TransferOut(id, _, time)
+            | Event::TransferIn(id, _, time)
+            | Event::UpdateSymbol(id, _, time)
+            | Event::UpdateCurrency(id, _, time) => Some((*id, *time)),
+            #[cfg(not(feature = "fusotao"))]
+            Event::Market(id, _, time)
+            | Event::Replace(id, _, time)
+            | Event::Stop(id, _, time)
+            | Event::Oco(id, _, time)
+            | Event::Peg(id, _, time)
+            | Event::Quote(id, _, time)
+            | Event::UpdateFeeTiers(id, _, time)
+            | Event::UpdateUserFee(id, _, time)
+            | Event::CancelAll(id, _, time)
+            | Event::Delist(id, _, time)
+            | Event::Uncross(id, _, time)
+            | Event::Batch(id, _, time) => Some((*id, *time)),
+            Event::Dump(_, _) => None,
+        }
+    }
+
+    /// the symbol which the event places orders on
+    pub fn placing_on(&self) -> Option<(EventId, Symbol)> {
+        match self {
+            Event::Limit(id, cmd, _) => Some((*id, cmd.symbol)),
+            #[cfg(not(feature = "fusotao"))]
+            Event::Market(id, cmd, _) => Some((*id, cmd.symbol)),
+            #[cfg(not(feature = "fusotao"))]
+            Event::Replace(id, cmd, _) => Some((*id, cmd.symbol)),
+            #[cfg(not(feature = "fusotao"))]
+            Event::Stop(id, cmd, _) => Some((*id, cmd.symbol)),
+            #[cfg(not(feature = "fusotao"))]
+            Event::Oco(id, cmd, _) => Some((*id, cmd.symbol)),
+            #[cfg(not(feature = "fusotao"))]
+            Event::Peg(id, cmd, _) => Some((*id, cmd.symbol)),
+            #[cfg(not(feature = "fusotao"))]
+            Event::Quote(id, cmd, _) => Some((*id, cmd.symbol)),
+            #[cfg(not(feature = "fusotao"))]
+            Event::Batch(id, cmd, _)
+                if cmd.orders.iter().any(|item| matches!(item, BatchItem::Limit(_))) =>
+            {
+                Some((*id, cmd.symbol))
+            }
+            _ => None,
+        }
     }
 
     pub fn is_assets_cmd(&self) -> bool {
@@ -122,6 +390,11 @@ pub enum Inspection {
     QueryOrder(Symbol, OrderId, u64, u64),
     QueryBalance(UserId, Currency, u64, u64),
     QueryAccounts(UserId, u64, u64),
+    /// the resting orders of the user in one symbol or all symbols
+    QueryOpenOrders(UserId, Option<Symbol>, u64, u64),
+    QueryClientOrder(Symbol, UserId, ClientOrderId, u64, u64),
+    /// the config of the symbol
+    QuerySymbol(Symbol, u64, u64),
 }
 
 impl Default for Inspection {
@@ -204,17 +477,45 @@ fn handle_event(
     sender: &OutputChannel,
     #[cfg(feature = "fusotao")] prover: &crate::fusotao::Prover,
 ) -> EventExecutionResult {
+    #[cfg(not(feature = "fusotao"))]
+    if let Some((id, time)) = event.expiring_at() {
+        expire_orders(data, sender, id, time)?;
+    }
+    if let Some((id, symbol)) = event.placing_on() {
+        if let Some(orderbook) = data.orderbooks.get(&symbol) {
+            data.currencies
+                .validate_symbol(&symbol, orderbook.base_scale, orderbook.quote_scale)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+        }
+    }
     match event {
-        Event::Limit(id, cmd, time) => {
+        Event::Limit(id, mut cmd, time) => {
+            prune_client_orders(data, time);
+            let reserved = reserved_client_order(data, &cmd, time);
             let orderbook = data
                 .orderbooks
                 .get_mut(&cmd.symbol)
-                .filter(|b| b.should_accept(cmd.price, cmd.amount, id))
-                .filter(|b| b.find_order(cmd.order_id).is_none())
                 .ok_or(EventsError::EventRejected(
                     id,
-                    anyhow!("order can't be accepted"),
+                    anyhow!("orderbook not exists"),
                 ))?;
+            if let Some((symbol, mr)) = reserved {
+                let out = clearing::clear(
+                    &mut data.accounts,
+                    &mut data.fees,
+                    id,
+                    &symbol,
+                    orderbook.taker_fee,
+                    orderbook.maker_fee,
+                    &mr,
+                    time,
+                );
+                sender.send(out).map_err(|_| EventsError::Interrupted)?;
+                return Ok(());
+            }
+            orderbook
+                .validate_id(id)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
             cfg_if! {
                 if #[cfg(feature = "fusotao")] {
                     log::info!("predicate root={:02x?} before applying {}", data.merkle_tree.root(), id);
@@ -223,19 +524,17 @@ fn handle_event(
                     let taker_quote_before = assets::get_balance_to_owned(&data.accounts, &cmd.user_id, cmd.symbol.1);
                 }
             }
-            let (c, val) = assets::freeze_if(&cmd.symbol, cmd.ask_or_bid, cmd.price, cmd.amount);
-            assets::try_freeze(&mut data.accounts, &cmd.user_id, c, val)
-                .map_err(|e| EventsError::EventRejected(id, e))?;
-            let mr = matcher::execute_limit(
+            let mr = place_limit(
                 orderbook,
-                cmd.user_id,
-                cmd.order_id,
-                cmd.price,
-                cmd.amount,
-                cmd.ask_or_bid,
-            );
+                &mut data.accounts,
+                &mut data.client_orders,
+                &mut cmd,
+                time,
+            )
+            .map_err(|e| EventsError::EventRejected(id, e))?;
             let out = clearing::clear(
                 &mut data.accounts,
+                &mut data.fees,
                 id,
                 &cmd.symbol,
                 orderbook.taker_fee,
@@ -260,34 +559,76 @@ fn handle_event(
                 }
             }
             sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            #[cfg(not(feature = "fusotao"))]
+            settle_oco(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                &mr,
+                time,
+            )?;
+            orderbook.watch_price(time);
+            #[cfg(not(feature = "fusotao"))]
+            trigger_stops(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            #[cfg(not(feature = "fusotao"))]
+            reprice_pegs(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
             Ok(())
         }
-        Event::Cancel(id, cmd, time) => {
-            // 0. symbol exsits
-            // 1. check order's owner
-            let orderbook =
-                data.orderbooks
-                    .get_mut(&cmd.symbol)
-                    .ok_or(EventsError::EventRejected(
-                        id,
-                        anyhow!("orderbook not exists"),
-                    ))?;
+        #[cfg(not(feature = "fusotao"))]
+        Event::Market(id, cmd, time) => {
+            let orderbook = data
+                .orderbooks
+                .get_mut(&cmd.symbol)
+                .ok_or(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not exists"),
+                ))?;
             orderbook
-                .find_order(cmd.order_id)
-                .filter(|o| o.user == cmd.user_id)
-                .ok_or(EventsError::EventRejected(id, anyhow!("order not exists")))?;
-            cfg_if! {
-                if #[cfg(feature = "fusotao")] {
-                    log::info!("predicate root={:02x?} before applying {}", data.merkle_tree.root(), id);
-                    let size = orderbook.size();
-                    let taker_base_before = assets::get_balance_to_owned(&data.accounts, &cmd.user_id, cmd.symbol.0);
-                    let taker_quote_before = assets::get_balance_to_owned(&data.accounts, &cmd.user_id, cmd.symbol.1);
-                }
+                .validate_market(cmd.amount, cmd.ask_or_bid, id)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            if orderbook.find_order(cmd.order_id).is_some() {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("order already exists"),
+                ));
             }
-            let mr = matcher::cancel(orderbook, cmd.order_id)
-                .ok_or(EventsError::EventRejected(id, anyhow!("")))?;
+            let c = match cmd.ask_or_bid {
+                AskOrBid::Ask => cmd.symbol.0,
+                AskOrBid::Bid => cmd.symbol.1,
+            };
+            assets::try_freeze(&mut data.accounts, &cmd.user_id, c, cmd.amount)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            let mr = matcher::execute_market(
+                orderbook,
+                cmd.user_id,
+                cmd.order_id,
+                cmd.amount,
+                cmd.ask_or_bid,
+                cmd.self_trade_prevention
+                    .unwrap_or(orderbook.self_trade_prevention),
+            );
             let out = clearing::clear(
                 &mut data.accounts,
+                &mut data.fees,
                 id,
                 &cmd.symbol,
                 orderbook.taker_fee,
@@ -295,64 +636,931 @@ fn handle_event(
                 &mr,
                 time,
             );
-            cfg_if! {
-                if #[cfg(feature = "fusotao")] {
-                    prover.prove_trade_cmd(
-                        data,
-                        cmd.nonce,
-                        cmd.signature.clone(),
-                        cmd.into(),
-                        size.0,
-                        size.1,
-                        &taker_base_before,
-                        &taker_quote_before,
-                        &out,
-                    );
-                }
-            }
             sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            settle_oco(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                &mr,
+                time,
+            )?;
+            orderbook.watch_price(time);
+            trigger_stops(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            reprice_pegs(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
             Ok(())
         }
         #[cfg(not(feature = "fusotao"))]
-        Event::CancelAll(id, symbol, time) => {
+        Event::Stop(id, cmd, time) => {
             let orderbook = data
                 .orderbooks
-                .get_mut(&symbol)
+                .get_mut(&cmd.symbol)
                 .ok_or(EventsError::EventRejected(
                     id,
                     anyhow!("orderbook not exists"),
                 ))?;
-            let ids = orderbook.indices.keys().copied().collect::<Vec<_>>();
-            let matches = ids
-                .into_iter()
-                .filter_map(|id| matcher::cancel(orderbook, id))
-                .collect::<Vec<_>>();
-            let (taker_fee, maker_fee) = (orderbook.taker_fee, orderbook.maker_fee);
-            matches.iter().for_each(|mr| {
-                let out = clearing::clear(
-                    &mut data.accounts,
+            let trail = match cmd.trail {
+                Some(offset) => {
+                    let extreme = orderbook.last_price.ok_or(EventsError::EventRejected(
+                        id,
+                        anyhow!("no last price to trail"),
+                    ))?;
+                    Some(Trail { offset, extreme })
+                }
+                None => None,
+            };
+            let trigger = trail.map_or(cmd.trigger, |t| t.trigger(cmd.ask_or_bid));
+            match trail {
+                Some(_) => orderbook.validate_market(cmd.amount, cmd.ask_or_bid, id).and_then(|_| {
+                    anyhow::ensure!(
+                        cmd.kind == StopKind::StopLoss && cmd.price.is_none(),
+                        "trailing stops must be stop-loss market orders"
+                    );
+                    anyhow::ensure!(trigger > Price::ZERO, "trail offset exceeds the last price");
+                    Ok(())
+                }),
+                None => orderbook.validate_stop(trigger, cmd.price, cmd.amount, cmd.ask_or_bid, id),
+            }
+            .map_err(|e| EventsError::EventRejected(id, e))?;
+            if orderbook.find_order(cmd.order_id).is_some() {
+                return Err(EventsError::EventRejected(
                     id,
-                    &symbol,
-                    taker_fee,
-                    maker_fee,
-                    mr,
-                    time,
-                );
-                sender.send(out).unwrap();
-            });
+                    anyhow!("order already exists"),
+                ));
+            }
+            let (c, val) = match cmd.price {
+                Some(price) => assets::freeze_if(&cmd.symbol, cmd.ask_or_bid, price, cmd.amount),
+                // the amount of a stop-market bid is measured in quote already
+                None => assets::freeze_if(&cmd.symbol, cmd.ask_or_bid, Price::ONE, cmd.amount),
+            };
+            assets::try_freeze(&mut data.accounts, &cmd.user_id, c, val)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            let stop = StopOrder {
+                id: cmd.order_id,
+                user: cmd.user_id,
+                ask_or_bid: cmd.ask_or_bid,
+                kind: cmd.kind,
+                trigger,
+                price: cmd.price,
+                amount: cmd.amount,
+                oco: None,
+                trail,
+            };
+            let mr = matcher::place_stop(orderbook, stop);
+            let out = clearing::clear(
+                &mut data.accounts,
+                &mut data.fees,
+                id,
+                &cmd.symbol,
+                orderbook.taker_fee,
+                orderbook.maker_fee,
+                &mr,
+                time,
+            );
+            sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            // the trigger might have been crossed already
+            trigger_stops(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            reprice_pegs(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
             Ok(())
         }
-        Event::TransferOut(id, cmd, _) => {
-            cfg_if! {
-                if #[cfg(feature = "fusotao")] {
+        #[cfg(not(feature = "fusotao"))]
+        Event::Oco(id, cmd, time) => {
+            let orderbook = data
+                .orderbooks
+                .get_mut(&cmd.symbol)
+                .ok_or(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not exists"),
+                ))?;
+            orderbook
+                .validate_limit(cmd.price, cmd.amount, id)
+                .and_then(|_| orderbook.validate_band(cmd.price))
+                .and_then(|_| {
+                    let (trigger, price) = (cmd.trigger, cmd.stop_price);
+                    orderbook.validate_stop(trigger, price, cmd.amount, cmd.ask_or_bid, id)
+                })
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            if cmd.ask_or_bid == AskOrBid::Bid && cmd.stop_price.is_none() {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("stop-market bids not supported in OCO"),
+                ));
+            }
+            let ids = [cmd.order_id, cmd.stop_order_id];
+            if cmd.order_id == cmd.stop_order_id
+                || ids.iter().any(|&o| {
+                    orderbook.find_order(o).is_some() || orderbook.stops.get(o).is_some()
+                })
+            {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("order already exists"),
+                ));
+            }
+            let freeze_price = cmd.stop_price.map_or(cmd.price, |p| p.max(cmd.price));
+            let (c, val) = assets::freeze_if(&cmd.symbol, cmd.ask_or_bid, freeze_price, cmd.amount);
+            assets::try_freeze(&mut data.accounts, &cmd.user_id, c, val)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            let stop = StopOrder {
+                id: cmd.stop_order_id,
+                user: cmd.user_id,
+                ask_or_bid: cmd.ask_or_bid,
+                kind: cmd.kind,
+                trigger: cmd.trigger,
+                price: cmd.stop_price,
+                amount: cmd.amount,
+                oco: Some(cmd.order_id),
+                trail: None,
+            };
+            let stp = orderbook.self_trade_prevention;
+            let matches = matcher::execute_oco(
+                orderbook,
+                cmd.user_id,
+                cmd.order_id,
+                cmd.price,
+                cmd.amount,
+                cmd.ask_or_bid,
+                stop,
+                stp,
+            );
+            for mr in matches {
+                let out = clearing::clear(
+                    &mut data.accounts,
+                    &mut data.fees,
+                    id,
+                    &cmd.symbol,
+                    orderbook.taker_fee,
+                    orderbook.maker_fee,
+                    &mr,
+                    time,
+                );
+                sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            }
+            orderbook.watch_price(time);
+            trigger_stops(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            reprice_pegs(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            Ok(())
+        }
+        Event::Cancel(id, cmd, time) => {
+            // 0. symbol exsits
+            // 1. check order's owner
+            let orderbook =
+                data.orderbooks
+                    .get_mut(&cmd.symbol)
+                    .ok_or(EventsError::EventRejected(
+                        id,
+                        anyhow!("orderbook not exists"),
+                    ))?;
+            let order_id = owned_order(orderbook, &data.client_orders, &cmd)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            cfg_if! {
+                if #[cfg(feature = "fusotao")] {
+                    log::info!("predicate root={:02x?} before applying {}", data.merkle_tree.root(), id);
+                    let size = orderbook.size();
+                    let taker_base_before = assets::get_balance_to_owned(&data.accounts, &cmd.user_id, cmd.symbol.0);
+                    let taker_quote_before = assets::get_balance_to_owned(&data.accounts, &cmd.user_id, cmd.symbol.1);
+                }
+            }
+            let mr = matcher::cancel(orderbook, order_id)
+                .ok_or(EventsError::EventRejected(id, anyhow!("")))?;
+            let out = clearing::clear(
+                &mut data.accounts,
+                &mut data.fees,
+                id,
+                &cmd.symbol,
+                orderbook.taker_fee,
+                orderbook.maker_fee,
+                &mr,
+                time,
+            );
+            cfg_if! {
+                if #[cfg(feature = "fusotao")] {
+                    prover.prove_trade_cmd(
+                        data,
+                        cmd.nonce,
+                        cmd.signature.clone(),
+                        cmd.into(),
+                        size.0,
+                        size.1,
+                        &taker_base_before,
+                        &taker_quote_before,
+                        &out,
+                    );
+                }
+            }
+            sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            #[cfg(not(feature = "fusotao"))]
+            settle_oco(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                &mr,
+                time,
+            )?;
+            #[cfg(not(feature = "fusotao"))]
+            reprice_pegs(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            Ok(())
+        }
+        #[cfg(not(feature = "fusotao"))]
+        Event::Replace(id, cmd, time) => {
+            let orderbook = data
+                .orderbooks
+                .get_mut(&cmd.symbol)
+                .ok_or(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not exists"),
+                ))?;
+            let (order, ask_or_bid) = orderbook
+                .find_order_with_side(cmd.order_id)
+                .filter(|(o, _)| o.user == cmd.user_id)
+                .ok_or(EventsError::EventRejected(id, anyhow!("order not exists")))?;
+            if orderbook.oco_groups.contains_key(&cmd.order_id) {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("order in an OCO group can't be replaced"),
+                ));
+            }
+            if orderbook.pegs.contains_key(&cmd.order_id) {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("pegged order can't be replaced"),
+                ));
+            }
+            let price = cmd.price.unwrap_or(order.price);
+            let amount = cmd.amount.unwrap_or(order.total_unfilled());
+            orderbook
+                .validate_replace(price, amount)
+                .and_then(|_| match cmd.price {
+                    Some(price) => orderbook.validate_band(price),
+                    None => Ok(()),
+                })
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            let (c, before) =
+                assets::freeze_if(&cmd.symbol, ask_or_bid, order.price, order.total_unfilled());
+            let (_, after) = assets::freeze_if(&cmd.symbol, ask_or_bid, price, amount);
+            if after > before {
+                assets::try_freeze(&mut data.accounts, &cmd.user_id, c, after - before)
+                    .map_err(|e| EventsError::EventRejected(id, e))?;
+            } else if after < before {
+                assets::try_unfreeze(&mut data.accounts, &cmd.user_id, c, before - after)
+                    .map_err(|e| EventsError::EventRejected(id, e))?;
+            }
+            let mr = matcher::replace(orderbook, cmd.order_id, price, amount)
+                .ok_or(EventsError::EventRejected(id, anyhow!("")))?;
+            let out = clearing::clear(
+                &mut data.accounts,
+                &mut data.fees,
+                id,
+                &cmd.symbol,
+                orderbook.taker_fee,
+                orderbook.maker_fee,
+                &mr,
+                time,
+            );
+            sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            settle_oco(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                &mr,
+                time,
+            )?;
+            orderbook.watch_price(time);
+            trigger_stops(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            reprice_pegs(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            Ok(())
+        }
+        #[cfg(not(feature = "fusotao"))]
+        Event::CancelAll(id, symbol, time) => {
+            let orderbook = data
+                .orderbooks
+                .get_mut(&symbol)
+                .ok_or(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not exists"),
+                ))?;
+            cancel_all(orderbook, &mut data.accounts, &mut data.fees, sender, id, &symbol, time)?;
+            Ok(())
+        }
+        #[cfg(not(feature = "fusotao"))]
+        Event::Delist(id, symbol, time) => {
+            let orderbook = data
+                .orderbooks
+                .get_mut(&symbol)
+                .ok_or(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not exists"),
+                ))?;
+            let mut users = orderbook
+                .user_indices
+                .keys()
+                .copied()
+                .collect::<std::collections::BTreeSet<_>>();
+            users.extend(
+                orderbook
+                    .stops
+                    .indices
+                    .keys()
+                    .filter_map(|id| orderbook.stops.get(*id))
+                    .map(|stop| stop.user),
+            );
+            let frozen = |accounts: &Accounts, user| {
+                (
+                    assets::get_balance_to_owned(accounts, user, symbol.0).frozen,
+                    assets::get_balance_to_owned(accounts, user, symbol.1).frozen,
+                )
+            };
+            let accounts = &data.accounts;
+            let before = users.iter().map(|user| frozen(accounts, user)).collect::<Vec<_>>();
+            let config = SymbolConfig::of(orderbook);
+            let last_price = orderbook.last_price;
+            let canceled = cancel_all(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &symbol,
+                time,
+            )?;
+            data.orderbooks.remove(&symbol);
+            let released = users
+                .iter()
+                .zip(before)
+                .map(|(user, (base, quote))| {
+                    let (base_after, quote_after) = frozen(&data.accounts, user);
+                    crate::symbol::Release {
+                        user_id: *user,
+                        base: base - base_after,
+                        quote: quote - quote_after,
+                    }
+                })
+                .collect::<Vec<_>>();
+            let report = crate::symbol::DelistReport {
+                event_id: id,
+                timestamp: time,
+                config,
+                last_price,
+                canceled,
+                released,
+            };
+            log::info!(
+                "symbol {:?} delisted: {}",
+                symbol,
+                serde_json::to_string(&report).unwrap_or_default()
+            );
+            data.delisted.insert(symbol, report);
+            Ok(())
+        }
+        Event::CancelUser(id, cmd, time) => {
+            let symbols = match cmd.symbol {
+                Some(symbol) if data.orderbooks.contains_key(&symbol) => vec![symbol],
+                Some(_) => {
+                    return Err(EventsError::EventRejected(
+                        id,
+                        anyhow!("orderbook not exists"),
+                    ));
+                }
+                None => {
+                    let mut symbols = data.orderbooks.keys().copied().collect::<Vec<_>>();
+                    symbols.sort_unstable();
+                    symbols
+                }
+            };
+            for symbol in symbols {
+                let ids = data.orderbooks[&symbol].user_orders(&cmd.user_id);
+                for order_id in ids {
+                    let orderbook = data.orderbooks.get_mut(&symbol).unwrap();
+                    cfg_if! {
+                        if #[cfg(feature = "fusotao")] {
+                            let size = orderbook.size();
+                            let taker_base_before = assets::get_balance_to_owned(&data.accounts, &cmd.user_id, symbol.0);
+                            let taker_quote_before = assets::get_balance_to_owned(&data.accounts, &cmd.user_id, symbol.1);
+                        }
+                    }
+                    // the sibling leg of an OCO group is gone with the former one
+                    let mr = match matcher::cancel(orderbook, order_id) {
+                        Some(mr) => mr,
+                        None => continue,
+                    };
+                    let out = clearing::clear(
+                        &mut data.accounts,
+                        &mut data.fees,
+                        id,
+                        &symbol,
+                        orderbook.taker_fee,
+                        orderbook.maker_fee,
+                        &mr,
+                        time,
+                    );
+                    cfg_if! {
+                        if #[cfg(feature = "fusotao")] {
+                            // proved as a single cancel of the order signed by the user
+                            let cancel = CancelCmd {
+                                symbol,
+                                user_id: cmd.user_id,
+                                order_id,
+                                client_order_id: None,
+                                nonce: cmd.nonce,
+                                signature: cmd.signature.clone(),
+                            };
+                            prover.prove_trade_cmd(
+                                data,
+                                cmd.nonce,
+                                cmd.signature.clone(),
+                                cancel.into(),
+                                size.0,
+                                size.1,
+                                &taker_base_before,
+                                &taker_quote_before,
+                                &out,
+                            );
+                        }
+                    }
+                    sender.send(out).map_err(|_| EventsError::Interrupted)?;
+                    #[cfg(not(feature = "fusotao"))]
+                    settle_oco(
+                        orderbook,
+                        &mut data.accounts,
+                        &mut data.fees,
+                        sender,
+                        id,
+                        &symbol,
+                        &mr,
+                        time,
+                    )?;
+                }
+                #[cfg(not(feature = "fusotao"))]
+                reprice_pegs(
+                    data.orderbooks.get_mut(&symbol).unwrap(),
+                    &mut data.accounts,
+                    &mut data.fees,
+                    sender,
+                    id,
+                    &symbol,
+                    time,
+                )?;
+            }
+            Ok(())
+        }
+        #[cfg(not(feature = "fusotao"))]
+        Event::Uncross(id, symbol, time) => {
+            let orderbook = data
+                .orderbooks
+                .get_mut(&symbol)
+                .ok_or(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not exists"),
+                ))?;
+            if !orderbook.auction {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not in auction"),
+                ));
+            }
+            for mr in matcher::uncross(orderbook) {
+                let out = clearing::clear(
+                    &mut data.accounts,
+                    &mut data.fees,
+                    id,
+                    &symbol,
+                    orderbook.taker_fee,
+                    orderbook.maker_fee,
+                    &mr,
+                    time,
+                );
+                sender.send(out).map_err(|_| EventsError::Interrupted)?;
+                settle_oco(
+                    orderbook,
+                    &mut data.accounts,
+                    &mut data.fees,
+                    sender,
+                    id,
+                    &symbol,
+                    &mr,
+                    time,
+                )?;
+            }
+            orderbook.watch_price(time);
+            trigger_stops(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &symbol,
+                time,
+            )?;
+            reprice_pegs(orderbook, &mut data.accounts, &mut data.fees, sender, id, &symbol, time)?;
+            Ok(())
+        }
+        #[cfg(not(feature = "fusotao"))]
+        Event::Batch(id, cmd, time) => {
+            prune_client_orders(data, time);
+            let orderbook = data
+                .orderbooks
+                .get_mut(&cmd.symbol)
+                .ok_or(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not exists"),
+                ))?;
+            orderbook
+                .validate_id(id)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            let savepoint = if cmd.all_or_nothing {
+                Some(savepoint(orderbook, &data.accounts, &data.fees, &data.client_orders, &cmd))
+            } else {
+                None
+            };
+            // the outputs are sent after all items succeed or fail
+            let mut outs = Vec::<Vec<output::Output>>::new();
+            for item in cmd.orders.iter() {
+                let reserved = match item {
+                    BatchItem::Limit(limit) => reserved_client_order(data, limit, time),
+                    BatchItem::Cancel(_) => None,
+                };
+                let orderbook = data.orderbooks.get_mut(&cmd.symbol).unwrap();
+                let result = match (item, reserved) {
+                    (_, Some((symbol, mr))) => Ok(vec![clearing::clear(
+                        &mut data.accounts,
+                        &mut data.fees,
+                        id,
+                        &symbol,
+                        orderbook.taker_fee,
+                        orderbook.maker_fee,
+                        &mr,
+                        time,
+                    )]),
+                    (BatchItem::Limit(limit), None) => batch_limit(
+                        orderbook,
+                        &mut data.accounts,
+                        &mut data.fees,
+                        &mut data.client_orders,
+                        id,
+                        limit,
+                        time,
+                    ),
+                    (BatchItem::Cancel(cancel), None) => batch_cancel(
+                        orderbook,
+                        &mut data.accounts,
+                        &mut data.fees,
+                        &data.client_orders,
+                        id,
+                        cancel,
+                        time,
+                    ),
+                };
+                match result {
+                    Ok(out) => outs.extend(out),
+                    Err(e) if cmd.all_or_nothing => {
+                        let (book, saved) = savepoint.unwrap();
+                        orderbook.restore(book);
+                        for (user, account, volume) in saved {
+                            match account {
+                                Some(account) => data.accounts.insert(user, account),
+                                None => data.accounts.remove(&user),
+                            };
+                            match volume.is_zero() {
+                                true => data.fees.volumes.remove(&(user, cmd.symbol.1)),
+                                false => data.fees.volumes.insert((user, cmd.symbol.1), volume),
+                            };
+                        }
+                        release_client_orders(&mut data.client_orders, &cmd, time);
+                        return Err(EventsError::EventRejected(id, e));
+                    }
+                    Err(e) => {
+                        log::info!("batch item of sequence {} rejected: {:?}", id, e);
+                        if let Some(mr) = batch_rejected(item) {
+                            outs.push(clearing::clear(
+                                &mut data.accounts,
+                                &mut data.fees,
+                                id,
+                                &cmd.symbol,
+                                orderbook.taker_fee,
+                                orderbook.maker_fee,
+                                &mr,
+                                time,
+                            ));
+                        }
+                    }
+                }
+            }
+            for out in outs {
+                sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            }
+            let orderbook = data.orderbooks.get_mut(&cmd.symbol).unwrap();
+            orderbook.watch_price(time);
+            trigger_stops(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            reprice_pegs(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            Ok(())
+        }
+        #[cfg(not(feature = "fusotao"))]
+        Event::Peg(id, cmd, time) => {
+            let orderbook = data
+                .orderbooks
+                .get_mut(&cmd.symbol)
+                .ok_or(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not exists"),
+                ))?;
+            if orderbook.auction {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("pegged orders not accepted in auction"),
+                ));
+            }
+            let price = orderbook
+                .peg_price(&cmd.peg, cmd.ask_or_bid)
+                .ok_or(EventsError::EventRejected(id, anyhow!("no reference price")))?;
+            orderbook
+                .validate_limit(price, cmd.amount, id)
+                .and_then(|_| orderbook.validate_band(price))
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            if orderbook.find_order(cmd.order_id).is_some() {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("order already exists"),
+                ));
+            }
+            let (c, val) = assets::freeze_if(&cmd.symbol, cmd.ask_or_bid, price, cmd.amount);
+            assets::try_freeze(&mut data.accounts, &cmd.user_id, c, val)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            let stp = orderbook.self_trade_prevention;
+            let mr = matcher::execute_limit(
+                orderbook,
+                cmd.user_id,
+                cmd.order_id,
+                price,
+                cmd.amount,
+                cmd.ask_or_bid,
+                matcher::TimeInForce::GoodTillCancel,
+                stp,
+            );
+            if orderbook.indices.contains_key(&cmd.order_id) {
+                orderbook.pegs.insert(cmd.order_id, cmd.peg);
+            }
+            let out = clearing::clear(
+                &mut data.accounts,
+                &mut data.fees,
+                id,
+                &cmd.symbol,
+                orderbook.taker_fee,
+                orderbook.maker_fee,
+                &mr,
+                time,
+            );
+            sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            settle_oco(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                &mr,
+                time,
+            )?;
+            orderbook.watch_price(time);
+            trigger_stops(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            reprice_pegs(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            Ok(())
+        }
+        #[cfg(not(feature = "fusotao"))]
+        Event::Quote(id, cmd, time) => {
+            let orderbook = data
+                .orderbooks
+                .get_mut(&cmd.symbol)
+                .ok_or(EventsError::EventRejected(
+                    id,
+                    anyhow!("orderbook not exists"),
+                ))?;
+            let ladder = orderbook
+                .ladder(&cmd.user_id)
+                .into_iter()
+                .map(|(o, ask_or_bid)| (o.id, o.price, o.total_unfilled(), ask_or_bid))
+                .collect::<Vec<_>>();
+            let levels = cmd
+                .bids
+                .iter()
+                .map(|level| (level, AskOrBid::Bid))
+                .chain(cmd.asks.iter().map(|level| (level, AskOrBid::Ask)))
+                .map(|(level, ask_or_bid)| (level.order_id, level.price, level.amount, ask_or_bid))
+                .collect::<Vec<_>>();
+            check_quote(orderbook, id, &ladder, &levels)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            // only the net change of the ladder is frozen or unfrozen
+            let mut net = std::collections::BTreeMap::<Currency, Amount>::new();
+            for &(_, price, amount, ask_or_bid) in &ladder {
+                let (c, val) = assets::freeze_if(&cmd.symbol, ask_or_bid, price, amount);
+                *net.entry(c).or_default() -= val;
+            }
+            for &(_, price, amount, ask_or_bid) in &levels {
+                let (c, val) = assets::freeze_if(&cmd.symbol, ask_or_bid, price, amount);
+                *net.entry(c).or_default() += val;
+            }
+            let accounts = &data.accounts;
+            if net.iter().any(|(c, val)| {
+                *val > assets::get_balance_to_owned(accounts, &cmd.user_id, *c).available
+            }) {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("insufficient balance"),
+                ));
+            }
+            for (c, val) in net {
+                if val > Amount::ZERO {
+                    assets::try_freeze(&mut data.accounts, &cmd.user_id, c, val)
+                        .map_err(|e| EventsError::EventRejected(id, e))?;
+                } else if val < Amount::ZERO {
+                    assets::try_unfreeze(&mut data.accounts, &cmd.user_id, c, -val)
+                        .map_err(|e| EventsError::EventRejected(id, e))?;
+                }
+            }
+            let ids = ladder.iter().map(|(order_id, _, _, _)| *order_id).collect::<Vec<_>>();
+            let matches = matcher::quote(orderbook, cmd.user_id, &ids, &levels);
+            for mr in matches {
+                let out = clearing::clear(
+                    &mut data.accounts,
+                    &mut data.fees,
+                    id,
+                    &cmd.symbol,
+                    orderbook.taker_fee,
+                    orderbook.maker_fee,
+                    &mr,
+                    time,
+                );
+                sender.send(out).map_err(|_| EventsError::Interrupted)?;
+                settle_oco(
+                    orderbook,
+                    &mut data.accounts,
+                    &mut data.fees,
+                    sender,
+                    id,
+                    &cmd.symbol,
+                    &mr,
+                    time,
+                )?;
+            }
+            let quotes = levels
+                .iter()
+                .map(|(order_id, _, _, _)| *order_id)
+                .filter(|order_id| orderbook.indices.contains_key(order_id))
+                .collect::<std::collections::BTreeSet<_>>();
+            if quotes.is_empty() {
+                orderbook.quotes.remove(&cmd.user_id);
+            } else {
+                orderbook.quotes.insert(cmd.user_id, quotes);
+            }
+            orderbook.watch_price(time);
+            trigger_stops(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            reprice_pegs(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                &cmd.symbol,
+                time,
+            )?;
+            Ok(())
+        }
+        Event::TransferOut(id, cmd, _) => {
+            cfg_if! {
+                if #[cfg(feature = "fusotao")] {
                     log::info!("predicate root={:02x?} before applying {}", data.merkle_tree.root(), id);
                     let before = assets::get_balance_to_owned(&data.accounts, &cmd.user_id, cmd.currency);
-                    match assets::deduct_available(
-                        &mut data.accounts,
-                        &cmd.user_id,
-                        cmd.currency,
-                        cmd.amount,
-                    ) {
+                    let deducted = data
+                        .currencies
+                        .validate_transfer(cmd.currency, cmd.amount)
+                        .and_then(|_| {
+                            assets::deduct_available(
+                                &mut data.accounts,
+                                &cmd.user_id,
+                                cmd.currency,
+                                cmd.amount,
+                            )
+                        });
+                    match deducted {
                         Ok(after) => {
                             prover.prove_assets_cmd(&mut data.merkle_tree, id, cmd, &before, &after);
                             Ok(())
@@ -363,6 +1571,9 @@ fn handle_event(
                         }
                     }
                 } else {
+                    data.currencies
+                        .validate_transfer(cmd.currency, cmd.amount)
+                        .map_err(|e| EventsError::EventRejected(id, e))?;
                     assets::deduct_available(
                         &mut data.accounts,
                         &cmd.user_id,
@@ -375,6 +1586,7 @@ fn handle_event(
         }
         Event::TransferIn(id, cmd, _) => {
             cfg_if! {
+                // the transfers in are settled on chain already
                 if #[cfg(feature = "fusotao")] {
                     log::info!("predicate root={:02x?} before applying {}", data.merkle_tree.root(), id);
                     let before = assets::get_balance_to_owned(&data.accounts, &cmd.user_id, cmd.currency);
@@ -387,6 +1599,9 @@ fn handle_event(
                     prover.prove_assets_cmd(&mut data.merkle_tree, id, cmd, &before, &after);
                     Ok(())
                 } else {
+                    data.currencies
+                        .validate_transfer(cmd.currency, cmd.amount)
+                        .map_err(|e| EventsError::EventRejected(id, e))?;
                     assets::add_to_available(
                         &mut data.accounts,
                         &cmd.user_id,
@@ -397,30 +1612,56 @@ fn handle_event(
                 }
             }
         }
-        Event::UpdateSymbol(_, cmd, _) => {
-            if !data.orderbooks.contains_key(&cmd.symbol) {
-                let orderbook = OrderBook::new(
-                    cmd.base_scale,
-                    cmd.quote_scale,
-                    cmd.taker_fee,
-                    cmd.maker_fee,
-                    cmd.min_amount,
-                    cmd.min_vol,
-                    cmd.enable_market_order,
-                    cmd.open,
-                );
-                data.orderbooks.insert(cmd.symbol, orderbook);
-            } else {
-                let orderbook = data.orderbooks.get_mut(&cmd.symbol).unwrap();
-                orderbook.base_scale = cmd.base_scale;
-                orderbook.quote_scale = cmd.quote_scale;
-                orderbook.taker_fee = cmd.taker_fee;
-                orderbook.maker_fee = cmd.maker_fee;
-                orderbook.min_amount = cmd.min_amount;
-                orderbook.min_vol = cmd.min_vol;
-                orderbook.enable_market_order = cmd.enable_market_order;
-                orderbook.open = cmd.open;
+        Event::UpdateSymbol(id, cmd, _) => {
+            let current = data.orderbooks.get(&cmd.symbol).map(SymbolConfig::of);
+            let config = SymbolConfig::merge(current, &cmd)
+                .and_then(|config| {
+                    data.currencies.validate_symbol(
+                        &cmd.symbol,
+                        config.base_scale,
+                        config.quote_scale,
+                    )?;
+                    Ok(config)
+                })
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+            let orderbook = data.orderbooks.entry(cmd.symbol).or_insert_with(|| {
+                OrderBook::new(
+                    config.base_scale,
+                    config.quote_scale,
+                    config.taker_fee,
+                    config.maker_fee,
+                    config.min_amount,
+                    config.min_vol,
+                    config.enable_market_order,
+                    config.open,
+                )
+            });
+            config.apply(orderbook);
+            Ok(())
+        }
+        Event::UpdateCurrency(id, cmd, _) => {
+            let decimals = cmd.config.decimals;
+            let exceeded = data.orderbooks.iter().any(|(symbol, orderbook)| {
+                (symbol.0 == cmd.currency && orderbook.base_scale > decimals)
+                    || (symbol.1 == cmd.currency && orderbook.quote_scale > decimals)
+            });
+            if exceeded {
+                return Err(EventsError::EventRejected(
+                    id,
+                    anyhow!("scales of the symbols exceed decimals"),
+                ));
             }
+            data.currencies.update(cmd.currency, cmd.config);
+            Ok(())
+        }
+        #[cfg(not(feature = "fusotao"))]
+        Event::UpdateFeeTiers(_, cmd, _) => {
+            data.fees.update_tiers(cmd.quote, cmd.tiers);
+            Ok(())
+        }
+        #[cfg(not(feature = "fusotao"))]
+        Event::UpdateUserFee(_, cmd, _) => {
+            data.fees.update_override(cmd.user_id, cmd.rates);
             Ok(())
         }
         Event::Dump(id, time) => {
@@ -430,17 +1671,568 @@ fn handle_event(
     }
 }
 
+/// the checks of a limit order except the event id
+fn check_limit(orderbook: &OrderBook, cmd: &LimitCmd, time: Timestamp) -> anyhow::Result<()> {
+    orderbook.validate_order(cmd.price, cmd.amount, cmd.display)?;
+    orderbook.validate_band(cmd.price)?;
+    anyhow::ensure!(
+        orderbook.find_order(cmd.order_id).is_none() && orderbook.stops.get(cmd.order_id).is_none(),
+        "order already exists"
+    );
+    anyhow::ensure!(
+        !matches!(cmd.expire_at, Some(t) if t <= time),
+        "order already expired"
+    );
+    anyhow::ensure!(
+        !orderbook.auction
+            || !matches!(
+                cmd.time_in_force,
+                matcher::TimeInForce::ImmediateOrCancel | matcher::TimeInForce::FillOrKill
+            ),
+        "IOC/FOK orders not accepted in auction"
+    );
+    Ok(())
+}
+
+/// the id of the order or stop order to cancel if it's owned by the user
+fn owned_order(
+    orderbook: &OrderBook,
+    client_orders: &ClientOrders,
+    cmd: &CancelCmd,
+) -> anyhow::Result<OrderId> {
+    let order_id = match &cmd.client_order_id {
+        Some(client_order_id) => client_orders
+            .get(&cmd.user_id, client_order_id)
+            .filter(|c| c.symbol == cmd.symbol)
+            .ok_or(anyhow!("order not exists"))?
+            .order_id,
+        None => cmd.order_id,
+    };
+    orderbook
+        .find_order(order_id)
+        .map(|o| o.user)
+        .or_else(|| orderbook.stops.get(order_id).map(|s| s.user))
+        .filter(|user| *user == cmd.user_id)
+        .ok_or(anyhow!("order not exists"))?;
+    Ok(order_id)
+}
+
+fn client_order(cmd: &LimitCmd, time: Timestamp) -> ClientOrder {
+    ClientOrder {
+        symbol: cmd.symbol,
+        order_id: cmd.order_id,
+        ask_or_bid: cmd.ask_or_bid,
+        price: cmd.price,
+        amount: cmd.amount,
+        placed_at: time,
+    }
+}
+
+/// the answer to a retry carries the original order
+fn duplicated(user_id: UserId, client_order: &ClientOrder) -> matcher::Match {
+    let c = client_order;
+    matcher::Match {
+        maker: vec![],
+        taker: matcher::Taker::taker(
+            Order::new(c.order_id, user_id, c.price, c.amount),
+            client_order.ask_or_bid,
+            matcher::State::Duplicated,
+        ),
+    }
+}
+
+/// a copy of what the batch could touch, i.e. the parts of the orderbook, and the accounts and
+/// the fee volumes of the users in them
+#[cfg(not(feature = "fusotao"))]
+fn savepoint(
+    orderbook: &OrderBook,
+    accounts: &Accounts,
+    fees: &FeeSchedule,
+    client_orders: &ClientOrders,
+    cmd: &BatchCmd,
+) -> (Savepoint, Vec<(UserId, Option<Account>, Vol)>) {
+    let mut placing = Vec::new();
+    let mut canceling = Vec::new();
+    for item in cmd.orders.iter() {
+        match item {
+            BatchItem::Limit(limit) => {
+                placing.push((limit.order_id, limit.ask_or_bid, limit.price))
+            }
+            BatchItem::Cancel(cancel) => {
+                canceling.extend(owned_order(orderbook, client_orders, cancel).ok())
+            }
+        }
+    }
+    let savepoint = orderbook.savepoint(&placing, &canceling);
+    let mut users = savepoint.users().collect::<std::collections::HashSet<_>>();
+    users.insert(cmd.user_id);
+    users.insert(SYSTEM);
+    let saved = users
+        .into_iter()
+        .map(|user| (user, accounts.get(&user).cloned(), fees.volume(&user, cmd.symbol.1)))
+        .collect();
+    (savepoint, saved)
+}
+
+/// the client order ids placed by the batch, which are released if it fails entirely
+#[cfg(not(feature = "fusotao"))]
+fn release_client_orders(client_orders: &mut ClientOrders, cmd: &BatchCmd, time: Timestamp) {
+    for item in cmd.orders.iter() {
+        if let BatchItem::Limit(limit) = item {
+            let client_order_id = match &limit.client_order_id {
+                Some(client_order_id) => client_order_id,
+                None => continue,
+            };
+            let placed = client_orders
+                .get(&limit.user_id, client_order_id)
+                .map_or(false, |c| c.order_id == limit.order_id && c.placed_at == time);
+            if placed {
+                client_orders.remove(&limit.user_id, client_order_id);
+            }
+        }
+    }
+}
+
+fn resting(
+    orderbooks: &std::collections::HashMap<Symbol, OrderBook>,
+    client_order: &ClientOrder,
+) -> bool {
+    orderbooks
+        .get(&client_order.symbol)
+        .map_or(false, |o| o.indices.contains_key(&client_order.order_id))
+}
+
+/// the client order ids out of the window are released lazily, only to save memory
+fn prune_client_orders(data: &mut Data, time: Timestamp) {
+    let orderbooks = &data.orderbooks;
+    data.client_orders.prune(time, |c| resting(orderbooks, c));
+}
+
+/// the answer to a retry if the client order id is reserved by the user in any symbol, i.e.
+/// within the window or while the order is resting
+fn reserved_client_order(
+    data: &Data,
+    cmd: &LimitCmd,
+    time: Timestamp,
+) -> Option<(Symbol, matcher::Match)> {
+    let client_order_id = cmd.client_order_id.as_ref()?;
+    data.client_orders
+        .get(&cmd.user_id, client_order_id)
+        .filter(|c| c.placed_at + CLIENT_ORDER_WINDOW >= time || resting(&data.orderbooks, c))
+        .map(|c| (c.symbol, duplicated(cmd.user_id, c)))
+}
+
+/// check, freeze and match a limit order except the event id, nothing is changed if it fails,
+/// a post-only-reprice order is repriced in place
+fn place_limit(
+    orderbook: &mut OrderBook,
+    accounts: &mut Accounts,
+    client_orders: &mut ClientOrders,
+    cmd: &mut LimitCmd,
+    time: Timestamp,
+) -> anyhow::Result<matcher::Match> {
+    if cmd.time_in_force == matcher::TimeInForce::PostOnlyReprice && !orderbook.auction {
+        cmd.price = orderbook.post_only_price(cmd.ask_or_bid, cmd.price);
+    }
+    check_limit(orderbook, cmd, time)?;
+    let (c, val) = assets::freeze_if(&cmd.symbol, cmd.ask_or_bid, cmd.price, cmd.amount);
+    assets::try_freeze(accounts, &cmd.user_id, c, val)?;
+    let stp = cmd.self_trade_prevention.unwrap_or(orderbook.self_trade_prevention);
+    let mr = match cmd.display {
+        Some(display) => matcher::execute_iceberg(
+            orderbook,
+            cmd.user_id,
+            cmd.order_id,
+            cmd.price,
+            cmd.amount,
+            display,
+            cmd.ask_or_bid,
+            cmd.time_in_force,
+            stp,
+        ),
+        None => matcher::execute_limit(
+            orderbook,
+            cmd.user_id,
+            cmd.order_id,
+            cmd.price,
+            cmd.amount,
+            cmd.ask_or_bid,
+            cmd.time_in_force,
+            stp,
+        ),
+    };
+    if let Some(expire_at) = cmd.expire_at {
+        orderbook.set_expiry(cmd.order_id, expire_at);
+    }
+    if let Some(client_order_id) = cmd.client_order_id.clone() {
+        client_orders.insert(cmd.user_id, client_order_id, client_order(cmd, time));
+    }
+    Ok(mr)
+}
+
+#[cfg(not(feature = "fusotao"))]
+fn batch_limit(
+    orderbook: &mut OrderBook,
+    accounts: &mut Accounts,
+    fees: &mut FeeSchedule,
+    client_orders: &mut ClientOrders,
+    id: EventId,
+    cmd: &LimitCmd,
+    time: Timestamp,
+) -> anyhow::Result<Vec<Vec<output::Output>>> {
+    let mr = place_limit(orderbook, accounts, client_orders, &mut cmd.clone(), time)?;
+    Ok(batch_clear(orderbook, accounts, fees, id, &cmd.symbol, mr, time))
+}
+
+#[cfg(not(feature = "fusotao"))]
+fn batch_cancel(
+    orderbook: &mut OrderBook,
+    accounts: &mut Accounts,
+    fees: &mut FeeSchedule,
+    client_orders: &ClientOrders,
+    id: EventId,
+    cmd: &CancelCmd,
+    time: Timestamp,
+) -> anyhow::Result<Vec<Vec<output::Output>>> {
+    let order_id = owned_order(orderbook, client_orders, cmd)?;
+    let mr = matcher::cancel(orderbook, order_id).ok_or(anyhow!("order not exists"))?;
+    Ok(batch_clear(orderbook, accounts, fees, id, &cmd.symbol, mr, time))
+}
+
+/// clear the match and the OCO siblings it touched
+#[cfg(not(feature = "fusotao"))]
+fn batch_clear(
+    orderbook: &mut OrderBook,
+    accounts: &mut Accounts,
+    fees: &mut FeeSchedule,
+    id: EventId,
+    symbol: &Symbol,
+    mr: matcher::Match,
+    time: Timestamp,
+) -> Vec<Vec<output::Output>> {
+    let siblings = matcher::settle_oco(orderbook, &mr);
+    std::iter::once(mr)
+        .chain(siblings)
+        .map(|mr| {
+            clearing::clear(
+                accounts,
+                fees,
+                id,
+                symbol,
+                orderbook.taker_fee,
+                orderbook.maker_fee,
+                &mr,
+                time,
+            )
+        })
+        .collect()
+}
+
+/// a rejected cancel is only logged, its order is gone or not the user's
+#[cfg(not(feature = "fusotao"))]
+fn batch_rejected(item: &BatchItem) -> Option<matcher::Match> {
+    match item {
+        BatchItem::Limit(cmd) => Some(matcher::Match {
+            maker: vec![],
+            taker: matcher::Taker::taker(
+                Order::new(cmd.order_id, cmd.user_id, cmd.price, cmd.amount),
+                cmd.ask_or_bid,
+                matcher::State::Rejected,
+            ),
+        }),
+        BatchItem::Cancel(_) => None,
+    }
+}
+
+/// the expired orders are cleared with the id of the event driving the expiry
+#[cfg(not(feature = "fusotao"))]
+fn expire_orders(
+    data: &mut Data,
+    sender: &OutputChannel,
+    id: EventId,
+    time: Timestamp,
+) -> EventExecutionResult {
+    for (symbol, orderbook) in data.orderbooks.iter_mut() {
+        for mr in matcher::expire(orderbook, time) {
+            let out = clearing::clear(
+                &mut data.accounts,
+                &mut data.fees,
+                id,
+                symbol,
+                orderbook.taker_fee,
+                orderbook.maker_fee,
+                &mr,
+                time,
+            );
+            sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            settle_oco(
+                orderbook,
+                &mut data.accounts,
+                &mut data.fees,
+                sender,
+                id,
+                symbol,
+                &mr,
+                time,
+            )?;
+        }
+        reprice_pegs(orderbook, &mut data.accounts, &mut data.fees, sender, id, symbol, time)?;
+    }
+    Ok(())
+}
+
+/// cancel all orders and stop orders of the orderbook, return the number of orders canceled
+#[cfg(not(feature = "fusotao"))]
+fn cancel_all(
+    orderbook: &mut OrderBook,
+    accounts: &mut Accounts,
+    fees: &mut FeeSchedule,
+    sender: &OutputChannel,
+    id: EventId,
+    symbol: &Symbol,
+    time: Timestamp,
+) -> Result<usize, EventsError> {
+    let ids = orderbook
+        .indices
+        .keys()
+        .chain(orderbook.stops.indices.keys())
+        .copied()
+        .collect::<Vec<_>>();
+    let mut matches = Vec::<matcher::Match>::new();
+    for id in ids {
+        if let Some(mr) = matcher::cancel(orderbook, id) {
+            let settled = matcher::settle_oco(orderbook, &mr);
+            matches.push(mr);
+            matches.extend(settled);
+        }
+    }
+    for mr in matches.iter() {
+        let out = clearing::clear(
+            accounts,
+            fees,
+            id,
+            symbol,
+            orderbook.taker_fee,
+            orderbook.maker_fee,
+            mr,
+            time,
+        );
+        sender.send(out).map_err(|_| EventsError::Interrupted)?;
+    }
+    Ok(matches.len())
+}
+
+/// the sibling legs of the OCO groups touched by the match are cleared with the same event id
+#[cfg(not(feature = "fusotao"))]
+fn settle_oco(
+    orderbook: &mut OrderBook,
+    accounts: &mut Accounts,
+    fees: &mut FeeSchedule,
+    sender: &OutputChannel,
+    id: EventId,
+    symbol: &Symbol,
+    mr: &matcher::Match,
+    time: Timestamp,
+) -> EventExecutionResult {
+    for mr in matcher::settle_oco(orderbook, mr) {
+        let out = clearing::clear(
+            accounts,
+            fees,
+            id,
+            symbol,
+            orderbook.taker_fee,
+            orderbook.maker_fee,
+            &mr,
+            time,
+        );
+        sender.send(out).map_err(|_| EventsError::Interrupted)?;
+    }
+    Ok(())
+}
+
+/// the triggered orders are cleared with the same event id
+#[cfg(not(feature = "fusotao"))]
+fn trigger_stops(
+    orderbook: &mut OrderBook,
+    accounts: &mut Accounts,
+    fees: &mut FeeSchedule,
+    sender: &OutputChannel,
+    id: EventId,
+    symbol: &Symbol,
+    time: Timestamp,
+) -> EventExecutionResult {
+    // a halted or auctioning orderbook keeps the stops until it's resumed
+    if !orderbook.open || orderbook.auction {
+        return Ok(());
+    }
+    for mr in matcher::trigger_stops(orderbook) {
+        let out = clearing::clear(
+            accounts,
+            fees,
+            id,
+            symbol,
+            orderbook.taker_fee,
+            orderbook.maker_fee,
+            &mr,
+            time,
+        );
+        sender.send(out).map_err(|_| EventsError::Interrupted)?;
+    }
+    orderbook.watch_price(time);
+    Ok(())
+}
+
+/// the levels are valid limit orders not crossing each other, and the new order ids are new
+#[cfg(not(feature = "fusotao"))]
+fn check_quote(
+    orderbook: &OrderBook,
+    id: EventId,
+    ladder: &[(OrderId, Price, Amount, AskOrBid)],
+    levels: &[(OrderId, Price, Amount, AskOrBid)],
+) -> anyhow::Result<()> {
+    orderbook.validate_id(id)?;
+    let mut ids = std::collections::BTreeSet::new();
+    for &(order_id, price, amount, ask_or_bid) in levels {
+        orderbook.validate_order(price, amount, None)?;
+        orderbook.validate_band(price)?;
+        anyhow::ensure!(ids.insert(order_id), "duplicated order id {} in quote", order_id);
+        match ladder.iter().find(|(o, _, _, _)| *o == order_id) {
+            Some(&(_, _, _, side)) => {
+                anyhow::ensure!(side == ask_or_bid, "quote {} can't change side", order_id)
+            }
+            None => anyhow::ensure!(
+                orderbook.find_order(order_id).is_none() && orderbook.stops.get(order_id).is_none(),
+                "order already exists"
+            ),
+        }
+    }
+    let best = |side| levels.iter().filter(move |(_, _, _, s)| *s == side).map(|l| l.1);
+    if let (Some(bid), Some(ask)) = (best(AskOrBid::Bid).max(), best(AskOrBid::Ask).min()) {
+        anyhow::ensure!(bid < ask, "quote crosses itself");
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct OpenOrder<'a> {
+    symbol: Symbol,
+    ask_or_bid: AskOrBid,
+    #[serde(flatten)]
+    order: &'a Order,
+}
+
+/// move the pegged orders along with the reference prices, an order stays if the extra funds
+/// to freeze are insufficient
+#[cfg(not(feature = "fusotao"))]
+fn reprice_pegs(
+    orderbook: &mut OrderBook,
+    accounts: &mut Accounts,
+    fees: &mut FeeSchedule,
+    sender: &OutputChannel,
+    id: EventId,
+    symbol: &Symbol,
+    time: Timestamp,
+) -> EventExecutionResult {
+    // the trades of the repriced orders move the reference prices and trigger the stops, so
+    // the orders are repriced again until nothing trades or the orderbook is halted
+    while orderbook.open
+        && !orderbook.auction
+        && reprice_pegs_once(orderbook, accounts, fees, sender, id, symbol, time)?
+    {
+        trigger_stops(orderbook, accounts, fees, sender, id, symbol, time)?;
+    }
+    orderbook.watch_price(time);
+    Ok(())
+}
+
+/// whether any of the repriced orders traded
+#[cfg(not(feature = "fusotao"))]
+fn reprice_pegs_once(
+    orderbook: &mut OrderBook,
+    accounts: &mut Accounts,
+    fees: &mut FeeSchedule,
+    sender: &OutputChannel,
+    id: EventId,
+    symbol: &Symbol,
+    time: Timestamp,
+) -> Result<bool, EventsError> {
+    let mut traded = false;
+    let pegs = orderbook.pegs.iter().map(|(id, peg)| (*id, *peg)).collect::<Vec<_>>();
+    for (order_id, peg) in pegs {
+        let (order, ask_or_bid) = match orderbook.find_order_with_side(order_id) {
+            Some((order, ask_or_bid)) => (order.clone(), ask_or_bid),
+            None => {
+                orderbook.pegs.remove(&order_id);
+                continue;
+            }
+        };
+        let price = match orderbook.peg_price(&peg, ask_or_bid) {
+            Some(price) if price != order.price => price,
+            _ => continue,
+        };
+        let amount = order.total_unfilled();
+        let (c, before) = assets::freeze_if(symbol, ask_or_bid, order.price, amount);
+        let (_, after) = assets::freeze_if(symbol, ask_or_bid, price, amount);
+        if after > before {
+            if assets::try_freeze(accounts, &order.user, c, after - before).is_err() {
+                continue;
+            }
+        } else if after < before {
+            assets::try_unfreeze(accounts, &order.user, c, before - after)
+                .map_err(|e| EventsError::EventRejected(id, e))?;
+        }
+        if let Some(mr) = matcher::replace(orderbook, order_id, price, amount) {
+            traded |= !mr.maker.is_empty();
+            let out = clearing::clear(
+                accounts,
+                fees,
+                id,
+                symbol,
+                orderbook.taker_fee,
+                orderbook.maker_fee,
+                &mr,
+                time,
+            );
+            sender.send(out).map_err(|_| EventsError::Interrupted)?;
+            settle_oco(orderbook, accounts, fees, sender, id, symbol, &mr, time)?;
+        }
+    }
+    Ok(traded)
+}
+
 fn do_inspect(inspection: Inspection, data: &Data) -> EventExecutionResult {
     match inspection {
         Inspection::QueryOrder(symbol, order_id, session, req_id) => {
             let v = match data.orderbooks.get(&symbol) {
-                Some(orderbook) => orderbook.find_order(order_id).map_or(vec![], |order| {
-                    serde_json::to_vec(order).unwrap_or_default()
-                }),
+                Some(orderbook) => match orderbook.find_order(order_id) {
+                    Some(order) => serde_json::to_vec(order).unwrap_or_default(),
+                    None => orderbook.stops.get(order_id).map_or(vec![], |stop| {
+                        serde_json::to_vec(stop).unwrap_or_default()
+                    }),
+                },
                 None => vec![],
             };
             server::publish(server::Message::with_payload(session, req_id, v));
         }
+        Inspection::QueryClientOrder(symbol, user_id, client_order_id, session, req_id) => {
+            let v = data
+                .client_orders
+                .get(&user_id, &client_order_id)
+                .filter(|c| c.symbol == symbol)
+                .and_then(|c| data.orderbooks.get(&symbol)?.find_order(c.order_id))
+                .map_or(vec![], |order| serde_json::to_vec(order).unwrap_or_default());
+            server::publish(server::Message::with_payload(session, req_id, v));
+        }
+        Inspection::QuerySymbol(symbol, session, req_id) => {
+            let v = match (data.orderbooks.get(&symbol), data.delisted.get(&symbol)) {
+                (Some(orderbook), _) => {
+                    serde_json::to_vec(&SymbolConfig::of(orderbook)).unwrap_or_default()
+                }
+                (None, Some(report)) => serde_json::to_vec(report).unwrap_or_default(),
+                (None, None) => vec![],
+            };
+            server::publish(server::Message::with_payload(session, req_id, v));
+        }
         Inspection::QueryBalance(user_id, currency, session, req_id) => {
             let a = assets::get_balance_to_owned(&data.accounts, &user_id, currency);
             let v = serde_json::to_vec(&a).unwrap_or_default();
@@ -451,6 +2243,25 @@ fn do_inspect(inspection: Inspection, data: &Data) -> EventExecutionResult {
             let v = serde_json::to_vec(&a).unwrap_or_default();
             server::publish(server::Message::with_payload(session, req_id, v));
         }
+        Inspection::QueryOpenOrders(user_id, symbol, session, req_id) => {
+            let orders = data
+                .orderbooks
+                .iter()
+                .filter(|(s, _)| symbol.is_none() || symbol == Some(**s))
+                .flat_map(|(s, orderbook)| {
+                    orderbook
+                        .open_orders(&user_id)
+                        .into_iter()
+                        .map(move |(order, ask_or_bid)| OpenOrder {
+                            symbol: *s,
+                            ask_or_bid,
+                            order,
+                        })
+                })
+                .collect::<Vec<_>>();
+            let v = serde_json::to_vec(&orders).unwrap_or_default();
+            server::publish(server::Message::with_payload(session, req_id, v));
+        }
         Inspection::UpdateDepth => {
             let writing = data
                 .orderbooks
@@ -490,3 +2301,742 @@ pub fn test_serialize() {
         .unwrap()
     );
 }
+
+/// the data with the symbols of the same config
+#[cfg(all(test, not(feature = "fusotao")))]
+fn test_data(symbols: &[Symbol]) -> Data {
+    use rust_decimal_macros::dec;
+    let mut data = Data::new();
+    for symbol in symbols {
+        data.orderbooks.insert(
+            *symbol,
+            OrderBook::new(4, 4, dec!(0.001), dec!(0.001), dec!(0.1), dec!(1), true, true),
+        );
+    }
+    data
+}
+
+/// a good-till-cancel limit order of amount 1
+#[cfg(all(test, not(feature = "fusotao")))]
+fn test_limit(
+    symbol: Symbol,
+    user_id: UserId,
+    order_id: OrderId,
+    price: Price,
+    ask_or_bid: AskOrBid,
+) -> LimitCmd {
+    LimitCmd {
+        symbol,
+        user_id,
+        order_id,
+        price,
+        amount: Amount::ONE,
+        ask_or_bid,
+        time_in_force: matcher::TimeInForce::GoodTillCancel,
+        self_trade_prevention: None,
+        display: None,
+        expire_at: None,
+        client_order_id: None,
+    }
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_batch() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol]);
+    let user = UserId::from_low_u64_be(1);
+    assets::add_to_available(&mut data.accounts, &user, 100, dec!(100)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let limit = |order_id, amount| {
+        BatchItem::Limit(LimitCmd {
+            amount,
+            ..test_limit(symbol, user, order_id, dec!(10), AskOrBid::Bid)
+        })
+    };
+    let cancel = |order_id| {
+        BatchItem::Cancel(CancelCmd {
+            symbol,
+            user_id: user,
+            order_id,
+            client_order_id: None,
+        })
+    };
+    let batch = |all_or_nothing, orders| BatchCmd {
+        symbol,
+        user_id: user,
+        all_or_nothing,
+        orders,
+    };
+    // best-effort: the failed limit orders are rejected, the failed cancels are only logged
+    let orders = vec![limit(1, dec!(1)), limit(2, dec!(100)), cancel(99)];
+    handle_event(Event::Batch(10, batch(false, orders), 0), &mut data, &tx).unwrap();
+    let states = rx.try_iter().flatten().map(|o| o.state).collect::<Vec<_>>();
+    assert_eq!(vec![matcher::State::Submitted, matcher::State::Rejected], states);
+    assert_eq!(dec!(10), assets::get_balance_to_owned(&data.accounts, &user, 100).frozen);
+    // all-or-nothing: the canceled order is restored
+    let orders = vec![cancel(1), limit(3, dec!(2)), limit(4, dec!(9))];
+    assert!(handle_event(Event::Batch(11, batch(true, orders), 0), &mut data, &tx).is_err());
+    assert_eq!(0, rx.try_iter().count());
+    let orderbook = data.orderbooks.get(&symbol).unwrap();
+    assert!(orderbook.find_order(1).is_some());
+    assert!(orderbook.find_order(3).is_none());
+    assert_eq!(dec!(10), assets::get_balance_to_owned(&data.accounts, &user, 100).frozen);
+    let orders = vec![cancel(1), limit(3, dec!(2)), limit(4, dec!(8))];
+    handle_event(Event::Batch(12, batch(true, orders), 0), &mut data, &tx).unwrap();
+    assert_eq!(3, rx.try_iter().count());
+    let orderbook = data.orderbooks.get(&symbol).unwrap();
+    assert!(orderbook.find_order(1).is_none());
+    assert!(orderbook.find_order(4).is_some());
+    assert_eq!(dec!(100), assets::get_balance_to_owned(&data.accounts, &user, 100).frozen);
+    // all-or-nothing: the crossed pages and the accounts of the makers are restored
+    let maker = UserId::from_low_u64_be(2);
+    assets::add_to_available(&mut data.accounts, &maker, 101, dec!(1)).unwrap();
+    assets::add_to_available(&mut data.accounts, &user, 100, dec!(11)).unwrap();
+    let ask = test_limit(symbol, maker, 13, dec!(11), AskOrBid::Ask);
+    handle_event(Event::Limit(13, ask.clone(), 0), &mut data, &tx).unwrap();
+    rx.try_iter().count();
+    let (orderbook, accounts) = (data.orderbooks[&symbol].clone(), data.accounts.clone());
+    let client_orders = data.client_orders.clone();
+    let bid = LimitCmd {
+        user_id: user,
+        order_id: 14,
+        ask_or_bid: AskOrBid::Bid,
+        client_order_id: Some("b".to_string()),
+        ..ask
+    };
+    let orders = vec![BatchItem::Limit(bid), cancel(99)];
+    assert!(handle_event(Event::Batch(14, batch(true, orders), 0), &mut data, &tx).is_err());
+    assert_eq!(0, rx.try_iter().count());
+    assert_eq!(orderbook, data.orderbooks[&symbol]);
+    assert_eq!(accounts, data.accounts);
+    assert_eq!(client_orders, data.client_orders);
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_batch_fills_maker_twice() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol]);
+    let (maker, taker) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+    assets::add_to_available(&mut data.accounts, &maker, 101, dec!(2)).unwrap();
+    assets::add_to_available(&mut data.accounts, &taker, 100, dec!(100)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let ask = LimitCmd {
+        amount: dec!(2),
+        ..test_limit(symbol, maker, 1, dec!(10), AskOrBid::Ask)
+    };
+    handle_event(Event::Limit(1, ask, 0), &mut data, &tx).unwrap();
+    let stop = StopCmd {
+        symbol,
+        user_id: taker,
+        order_id: 2,
+        kind: StopKind::TakeProfit,
+        trigger: dec!(5),
+        price: Some(dec!(5)),
+        amount: dec!(1),
+        ask_or_bid: AskOrBid::Bid,
+        trail: None,
+    };
+    handle_event(Event::Stop(2, stop, 0), &mut data, &tx).unwrap();
+    rx.try_iter().count();
+    let limit = |order_id| {
+        BatchItem::Limit(test_limit(symbol, taker, order_id, dec!(10), AskOrBid::Bid))
+    };
+    let batch = BatchCmd {
+        symbol,
+        user_id: taker,
+        all_or_nothing: false,
+        orders: vec![limit(3), limit(2), limit(4)],
+    };
+    handle_event(Event::Batch(5, batch, 0), &mut data, &tx).unwrap();
+    let outs = rx.try_iter().flatten().collect::<Vec<_>>();
+    // the id of the pending stop order is taken
+    let rejected = outs.iter().find(|o| o.order_id == 2).unwrap();
+    assert_eq!(matcher::State::Rejected, rejected.state);
+    let makers = outs
+        .iter()
+        .filter(|o| o.order_id == 1)
+        .map(|o| (o.state, o.base_delta, o.base_frozen))
+        .collect::<Vec<_>>();
+    let expected = vec![
+        (matcher::State::PartialFilled, dec!(-1), dec!(1)),
+        (matcher::State::Filled, dec!(-1), dec!(0)),
+    ];
+    assert_eq!(expected, makers);
+    assert!(data.orderbooks[&symbol].stops.get(2).is_some());
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_cancel_user() {
+    use rust_decimal_macros::dec;
+    let mut data = test_data(&[(101, 100), (102, 100)]);
+    let (alice, bob) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+    assets::add_to_available(&mut data.accounts, &alice, 100, dec!(100)).unwrap();
+    assets::add_to_available(&mut data.accounts, &bob, 100, dec!(100)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let orders = [
+        (1, (101, 100), alice),
+        (2, (101, 100), bob),
+        (3, (101, 100), alice),
+        (4, (102, 100), alice),
+    ];
+    for (order_id, symbol, user_id) in orders.iter() {
+        let cmd = test_limit(*symbol, *user_id, *order_id, dec!(10), AskOrBid::Bid);
+        handle_event(Event::Limit(*order_id, cmd, 0), &mut data, &tx).unwrap();
+    }
+    assert_eq!(4, rx.try_iter().count());
+    let cmd = CancelUserCmd {
+        user_id: alice,
+        symbol: Some((101, 100)),
+    };
+    handle_event(Event::CancelUser(5, cmd, 0), &mut data, &tx).unwrap();
+    let canceled = rx.try_iter().flatten().map(|o| (o.order_id, o.state)).collect::<Vec<_>>();
+    assert_eq!(vec![(1, matcher::State::Canceled), (3, matcher::State::Canceled)], canceled);
+    assert_eq!(dec!(10), assets::get_balance_to_owned(&data.accounts, &alice, 100).frozen);
+    let cmd = CancelUserCmd {
+        user_id: alice,
+        symbol: None,
+    };
+    handle_event(Event::CancelUser(6, cmd, 0), &mut data, &tx).unwrap();
+    assert_eq!(vec![4], rx.try_iter().flatten().map(|o| o.order_id).collect::<Vec<_>>());
+    assert_eq!(dec!(0), assets::get_balance_to_owned(&data.accounts, &alice, 100).frozen);
+    assert!(data.orderbooks[&(101, 100)].find_order(2).is_some());
+}
+
+#[test]
+#[cfg(feature = "fusotao")]
+pub fn test_cancel_user_proved() {
+    use crate::fusotao::{FusoCommand, Prover};
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = Data::new();
+    data.orderbooks.insert(
+        symbol,
+        OrderBook::new(4, 4, dec!(0.001), dec!(0.001), dec!(0.1), dec!(1), true, true),
+    );
+    let user = UserId::from_low_u64_be(1);
+    assets::add_to_available(&mut data.accounts, &user, 100, dec!(100)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (proof_tx, proof_rx) = std::sync::mpsc::channel();
+    let prover = Prover::new(proof_tx);
+    for order_id in 1..=2 {
+        let cmd = LimitCmd {
+            symbol,
+            user_id: user,
+            order_id,
+            price: dec!(10),
+            amount: dec!(1),
+            ask_or_bid: AskOrBid::Bid,
+            time_in_force: matcher::TimeInForce::GoodTillCancel,
+            self_trade_prevention: None,
+            display: None,
+            expire_at: None,
+            client_order_id: None,
+            nonce: order_id as u32,
+            signature: vec![0],
+        };
+        handle_event(Event::Limit(order_id, cmd, 0), &mut data, &tx, &prover).unwrap();
+    }
+    let cmd = CancelUserCmd {
+        user_id: user,
+        symbol: None,
+        nonce: 3,
+        signature: vec![1],
+    };
+    handle_event(Event::CancelUser(3, cmd, 0), &mut data, &tx, &prover).unwrap();
+    let canceled = rx
+        .try_iter()
+        .flatten()
+        .filter(|o| o.state == matcher::State::Canceled)
+        .map(|o| o.order_id)
+        .collect::<Vec<_>>();
+    assert_eq!(vec![1, 2], canceled);
+    assert_eq!(dec!(0), assets::get_balance_to_owned(&data.accounts, &user, 100).frozen);
+    // each cancel is proved with the signature of the command
+    let proofs = proof_rx.try_iter().skip(2).collect::<Vec<_>>();
+    assert_eq!(2, proofs.len());
+    for proof in proofs {
+        assert_eq!((3, 3, vec![1]), (proof.event_id, proof.nonce, proof.signature));
+        assert!(matches!(proof.cmd, FusoCommand::Cancel(_, _)));
+    }
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_client_order_id() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol, (102, 100)]);
+    let user = UserId::from_low_u64_be(1);
+    assets::add_to_available(&mut data.accounts, &user, 100, dec!(100)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let limit = |order_id| LimitCmd {
+        client_order_id: Some("a".to_string()),
+        ..test_limit(symbol, user, order_id, dec!(10), AskOrBid::Bid)
+    };
+    handle_event(Event::Limit(10, limit(10), 0), &mut data, &tx).unwrap();
+    handle_event(Event::Limit(11, limit(11), 1), &mut data, &tx).unwrap();
+    let outs = rx.try_iter().flatten().map(|o| (o.order_id, o.state)).collect::<Vec<_>>();
+    assert_eq!(vec![(10, matcher::State::Submitted), (10, matcher::State::Duplicated)], outs);
+    assert_eq!(dec!(10), assets::get_balance_to_owned(&data.accounts, &user, 100).frozen);
+    // the client order ids are unique per user across the symbols
+    let other = LimitCmd {
+        symbol: (102, 100),
+        ask_or_bid: AskOrBid::Ask,
+        ..limit(12)
+    };
+    handle_event(Event::Limit(12, other, 1), &mut data, &tx).unwrap();
+    let out = rx.try_recv().unwrap();
+    assert_eq!((10, matcher::State::Duplicated), (out[0].order_id, out[0].state));
+    assert_eq!((symbol, AskOrBid::Bid), (out[0].symbol, out[0].ask_or_bid));
+    assert_eq!(dec!(10), out[0].price);
+    assert!(!data.orderbooks[&(102, 100)].indices.contains_key(&12));
+    let cancel = CancelCmd {
+        symbol,
+        user_id: user,
+        order_id: 0,
+        client_order_id: Some("a".to_string()),
+    };
+    handle_event(Event::Cancel(13, cancel, 2), &mut data, &tx).unwrap();
+    let outs = rx.try_iter().flatten().map(|o| (o.order_id, o.state)).collect::<Vec<_>>();
+    assert_eq!(vec![(10, matcher::State::Canceled)], outs);
+    // the client order id is still reserved within the window
+    handle_event(Event::Limit(14, limit(14), 3), &mut data, &tx).unwrap();
+    assert_eq!(matcher::State::Duplicated, rx.try_recv().unwrap()[0].state);
+    let after_window = CLIENT_ORDER_WINDOW + 1;
+    handle_event(Event::Limit(15, limit(15), after_window), &mut data, &tx).unwrap();
+    assert_eq!(matcher::State::Submitted, rx.try_recv().unwrap()[0].state);
+    // the resting order keeps its client order id after the window
+    handle_event(Event::Limit(16, limit(16), after_window * 3), &mut data, &tx).unwrap();
+    let out = rx.try_recv().unwrap();
+    assert_eq!((15, matcher::State::Duplicated), (out[0].order_id, out[0].state));
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_expire() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol]);
+    let user = UserId::from_low_u64_be(1);
+    assets::add_to_available(&mut data.accounts, &user, 100, dec!(100)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let limit = LimitCmd {
+        expire_at: Some(100),
+        ..test_limit(symbol, user, 10, dec!(10), AskOrBid::Bid)
+    };
+    handle_event(Event::Limit(10, limit.clone(), 0), &mut data, &tx).unwrap();
+    // the order is expired before the sequence is executed, even if it's rejected
+    assert!(handle_event(Event::Limit(11, limit.clone(), 200), &mut data, &tx).is_err());
+    assert!(data.orderbooks[&symbol].find_order(10).is_none());
+    let limit = LimitCmd {
+        order_id: 12,
+        expire_at: None,
+        ..limit
+    };
+    handle_event(Event::Limit(12, limit, 200), &mut data, &tx).unwrap();
+    let outs = rx
+        .try_iter()
+        .flatten()
+        .map(|o| (o.event_id, o.order_id, o.state))
+        .collect::<Vec<_>>();
+    let expected = vec![
+        (10, 10, matcher::State::Submitted),
+        (11, 10, matcher::State::Expired),
+        (12, 12, matcher::State::Submitted),
+    ];
+    assert_eq!(expected, outs);
+    assert_eq!(dec!(10), assets::get_balance_to_owned(&data.accounts, &user, 100).frozen);
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_pegged_order() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol]);
+    let (maker, pegger) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+    assets::add_to_available(&mut data.accounts, &maker, 100, dec!(100)).unwrap();
+    assets::add_to_available(&mut data.accounts, &maker, 101, dec!(100)).unwrap();
+    assets::add_to_available(&mut data.accounts, &pegger, 100, dec!(100)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let limit = |order_id, price, ask_or_bid| {
+        test_limit(symbol, maker, order_id, price, ask_or_bid)
+    };
+    handle_event(Event::Limit(1, limit(1, dec!(12), AskOrBid::Ask), 0), &mut data, &tx).unwrap();
+    handle_event(Event::Limit(2, limit(2, dec!(10), AskOrBid::Bid), 0), &mut data, &tx).unwrap();
+    let peg = PegCmd {
+        symbol,
+        user_id: pegger,
+        order_id: 3,
+        amount: dec!(2),
+        ask_or_bid: AskOrBid::Bid,
+        peg: Peg {
+            reference: PegReference::Primary,
+            offset: dec!(0.5),
+            cap: Some(dec!(11)),
+        },
+    };
+    handle_event(Event::Peg(3, peg, 0), &mut data, &tx).unwrap();
+    let orderbook = data.orderbooks.get(&symbol).unwrap();
+    assert_eq!(dec!(10.5), orderbook.find_order(3).unwrap().price);
+    assert_eq!(dec!(21), assets::get_balance_to_owned(&data.accounts, &pegger, 100).frozen);
+    // the pegged order itself is not a reference
+    handle_event(Event::Limit(4, limit(4, dec!(10.8), AskOrBid::Bid), 1), &mut data, &tx).unwrap();
+    let orderbook = data.orderbooks.get(&symbol).unwrap();
+    assert_eq!(dec!(11), orderbook.find_order(3).unwrap().price);
+    assert_eq!(dec!(22), assets::get_balance_to_owned(&data.accounts, &pegger, 100).frozen);
+    let cancel = |order_id| CancelCmd {
+        symbol,
+        user_id: maker,
+        order_id,
+        client_order_id: None,
+    };
+    handle_event(Event::Cancel(5, cancel(4), 2), &mut data, &tx).unwrap();
+    handle_event(Event::Cancel(6, cancel(2), 2), &mut data, &tx).unwrap();
+    // no reference price left, the order stays where it was
+    let orderbook = data.orderbooks.get(&symbol).unwrap();
+    assert_eq!(dec!(10.5), orderbook.find_order(3).unwrap().price);
+    handle_event(Event::Cancel(7, cancel(1), 3), &mut data, &tx).unwrap();
+    let orderbook = data.orderbooks.get(&symbol).unwrap();
+    assert_eq!(dec!(10.5), orderbook.find_order(3).unwrap().price);
+    let outs = rx.try_iter().flatten().filter(|o| o.order_id == 3).count();
+    assert_eq!(3, outs);
+    assert_eq!(dec!(21), assets::get_balance_to_owned(&data.accounts, &pegger, 100).frozen);
+    // a repriced order filling an OCO limit leg cancels the stop leg
+    let oco = OcoCmd {
+        symbol,
+        user_id: maker,
+        order_id: 8,
+        price: dec!(12),
+        amount: dec!(1),
+        ask_or_bid: AskOrBid::Ask,
+        stop_order_id: 9,
+        kind: StopKind::StopLoss,
+        trigger: dec!(9),
+        stop_price: Some(dec!(9)),
+    };
+    handle_event(Event::Oco(8, oco, 4), &mut data, &tx).unwrap();
+    handle_event(Event::Limit(10, limit(10, dec!(10), AskOrBid::Bid), 4), &mut data, &tx).unwrap();
+    let peg = PegCmd {
+        symbol,
+        user_id: pegger,
+        order_id: 11,
+        amount: dec!(1),
+        ask_or_bid: AskOrBid::Bid,
+        peg: Peg {
+            reference: PegReference::Primary,
+            offset: dec!(1.5),
+            cap: None,
+        },
+    };
+    handle_event(Event::Peg(11, peg, 4), &mut data, &tx).unwrap();
+    assert_eq!(dec!(11.5), data.orderbooks[&symbol].find_order(11).unwrap().price);
+    let bid = limit(12, dec!(10.5), AskOrBid::Bid);
+    handle_event(Event::Limit(12, bid, 5), &mut data, &tx).unwrap();
+    let orderbook = data.orderbooks.get(&symbol).unwrap();
+    assert!(orderbook.find_order(8).is_none() && orderbook.find_order(11).is_none());
+    assert!(orderbook.stops.get(9).is_none());
+    let outs = rx.try_iter().flatten().map(|o| (o.order_id, o.state)).collect::<Vec<_>>();
+    assert!(outs.contains(&(9, matcher::State::OcoCanceled)));
+    assert_eq!(dec!(0), assets::get_balance_to_owned(&data.accounts, &maker, 101).frozen);
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_trailing_stop() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol]);
+    let (maker, taker, trader) = (
+        UserId::from_low_u64_be(1),
+        UserId::from_low_u64_be(2),
+        UserId::from_low_u64_be(3),
+    );
+    for user in [maker, taker] {
+        assets::add_to_available(&mut data.accounts, &user, 100, dec!(1000)).unwrap();
+        assets::add_to_available(&mut data.accounts, &user, 101, dec!(100)).unwrap();
+    }
+    assets::add_to_available(&mut data.accounts, &trader, 101, dec!(1)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let limit = |user_id, order_id, price, ask_or_bid| {
+        test_limit(symbol, user_id, order_id, price, ask_or_bid)
+    };
+    let trade = |data: &mut Data, id, price| {
+        let ask = limit(maker, id, price, AskOrBid::Ask);
+        handle_event(Event::Limit(id, ask, 0), data, &tx).unwrap();
+        let bid = limit(taker, id + 1, price, AskOrBid::Bid);
+        handle_event(Event::Limit(id + 1, bid, 0), data, &tx).unwrap();
+        data.orderbooks.get(&symbol).unwrap().stops.get(3).map(|s| s.trigger)
+    };
+    assert_eq!(None, trade(&mut data, 1, dec!(10)));
+    let stop = StopCmd {
+        symbol,
+        user_id: trader,
+        order_id: 3,
+        kind: StopKind::StopLoss,
+        trigger: Price::ZERO,
+        price: None,
+        amount: dec!(1),
+        ask_or_bid: AskOrBid::Ask,
+        trail: Some(TrailOffset::Amount(dec!(1))),
+    };
+    handle_event(Event::Stop(3, stop, 0), &mut data, &tx).unwrap();
+    assert_eq!(dec!(1), assets::get_balance_to_owned(&data.accounts, &trader, 101).frozen);
+    assert_eq!(Some(dec!(11)), trade(&mut data, 4, dec!(12)));
+    // the trigger never moves back
+    assert_eq!(Some(dec!(11)), trade(&mut data, 6, dec!(11.5)));
+    let bid = limit(maker, 8, dec!(10.5), AskOrBid::Bid);
+    handle_event(Event::Limit(8, bid, 0), &mut data, &tx).unwrap();
+    assert_eq!(None, trade(&mut data, 9, dec!(11)));
+    let outs = rx.try_iter().flatten().filter(|o| o.order_id == 3).collect::<Vec<_>>();
+    assert_eq!(matcher::State::Filled, outs.last().unwrap().state);
+    assert_eq!(Decimal::ZERO, assets::get_balance_to_owned(&data.accounts, &trader, 101).frozen);
+    let orderbook = data.orderbooks.get(&symbol).unwrap();
+    assert!(orderbook.stops.is_empty());
+    assert!(orderbook.stops.trailing.is_empty());
+    assert_eq!(Some(dec!(10.5)), orderbook.last_price);
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_quote() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol]);
+    let (mm, taker) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+    assets::add_to_available(&mut data.accounts, &mm, 100, dec!(1000)).unwrap();
+    assets::add_to_available(&mut data.accounts, &mm, 101, dec!(100)).unwrap();
+    assets::add_to_available(&mut data.accounts, &taker, 100, dec!(1000)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let level = |order_id, price, amount| QuoteLevel {
+        order_id,
+        price,
+        amount,
+    };
+    let quote = |bids, asks| QuoteCmd {
+        symbol,
+        user_id: mm,
+        bids,
+        asks,
+    };
+    let frozen = |data: &Data, c| assets::get_balance_to_owned(&data.accounts, &mm, c).frozen;
+    let cmd = quote(
+        vec![level(1, dec!(9.9), dec!(1)), level(2, dec!(9.8), dec!(2))],
+        vec![level(3, dec!(10.1), dec!(1))],
+    );
+    handle_event(Event::Quote(10, cmd, 0), &mut data, &tx).unwrap();
+    assert_eq!((dec!(29.5), dec!(1)), (frozen(&data, 100), frozen(&data, 101)));
+    rx.try_iter().for_each(drop);
+    let cmd = quote(
+        vec![level(1, dec!(9.9), dec!(1)), level(2, dec!(9.7), dec!(2))],
+        vec![level(4, dec!(10.2), dec!(2))],
+    );
+    handle_event(Event::Quote(20, cmd, 1), &mut data, &tx).unwrap();
+    let outs = rx.try_iter().flatten().map(|o| (o.order_id, o.state)).collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            (1, matcher::State::QuoteKept),
+            (3, matcher::State::QuoteDropped),
+            (2, matcher::State::Replaced),
+            (4, matcher::State::Submitted),
+        ],
+        outs
+    );
+    assert_eq!((dec!(29.3), dec!(2)), (frozen(&data, 100), frozen(&data, 101)));
+    let orderbook = data.orderbooks.get(&symbol).unwrap();
+    assert_eq!(vec![1, 2, 4], orderbook.user_orders(&mm));
+    // a ladder crossing itself is rejected as a whole
+    let cmd = quote(vec![level(5, dec!(10.3), dec!(1))], vec![level(4, dec!(10.2), dec!(2))]);
+    assert!(handle_event(Event::Quote(30, cmd, 2), &mut data, &tx).is_err());
+    let bid = LimitCmd {
+        amount: dec!(2),
+        ..test_limit(symbol, taker, 40, dec!(10.2), AskOrBid::Bid)
+    };
+    handle_event(Event::Limit(40, bid, 3), &mut data, &tx).unwrap();
+    assert_eq!(dec!(0), frozen(&data, 101));
+    rx.try_iter().for_each(drop);
+    // the filled quote is no longer in the ladder
+    handle_event(Event::Quote(50, quote(vec![], vec![]), 4), &mut data, &tx).unwrap();
+    let outs = rx.try_iter().flatten().map(|o| (o.order_id, o.state)).collect::<Vec<_>>();
+    assert_eq!(
+        vec![(1, matcher::State::QuoteDropped), (2, matcher::State::QuoteDropped)],
+        outs
+    );
+    assert_eq!(dec!(0), frozen(&data, 100));
+    assert!(data.orderbooks.get(&symbol).unwrap().quotes.is_empty());
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_fee_tiers() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol]);
+    let (maker, taker) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+    let orderbook = data.orderbooks.get_mut(&symbol).unwrap();
+    orderbook.taker_fee = dec!(0.003);
+    orderbook.maker_fee = dec!(0.003);
+    for user in [maker, taker] {
+        assets::add_to_available(&mut data.accounts, &user, 100, dec!(1000)).unwrap();
+        assets::add_to_available(&mut data.accounts, &user, 101, dec!(100)).unwrap();
+    }
+    let (tx, rx) = std::sync::mpsc::channel();
+    let tier = |volume, taker_fee, maker_fee| FeeTier {
+        volume,
+        taker_fee,
+        maker_fee,
+    };
+    let tiers = FeeTiersCmd {
+        quote: 100,
+        tiers: vec![tier(dec!(0), dec!(0.002), dec!(0.001)), tier(dec!(10), dec!(0.001), dec!(0))],
+    };
+    handle_event(Event::UpdateFeeTiers(1, tiers, 0), &mut data, &tx).unwrap();
+    let limit = |user_id, order_id, ask_or_bid| {
+        test_limit(symbol, user_id, order_id, dec!(10), ask_or_bid)
+    };
+    let trade = |data: &mut Data, id| {
+        handle_event(Event::Limit(id, limit(maker, id, AskOrBid::Ask), 0), data, &tx).unwrap();
+        handle_event(Event::Limit(id + 1, limit(taker, id + 1, AskOrBid::Bid), 0), data, &tx)
+            .unwrap();
+        let outs = rx.try_iter().flatten().collect::<Vec<_>>();
+        let fill = outs.iter().find(|o| o.role == Role::Taker && o.order_id == id + 1).unwrap();
+        let fee = outs.iter().find(|o| o.role == Role::Maker).unwrap().fee;
+        (fill.fee, fee, fill.base_charge)
+    };
+    // the rates are taken before the volume of the trade is counted
+    assert_eq!((dec!(0.002), dec!(0.001), dec!(-0.002)), trade(&mut data, 10));
+    assert_eq!(dec!(10), data.fees.volume(&taker, 100));
+    assert_eq!((dec!(0.001), dec!(0), dec!(-0.001)), trade(&mut data, 20));
+    let vip = UserFeeCmd {
+        user_id: taker,
+        rates: Some(FeeRates {
+            taker_fee: dec!(0.0005),
+            maker_fee: dec!(0),
+        }),
+    };
+    handle_event(Event::UpdateUserFee(30, vip, 0), &mut data, &tx).unwrap();
+    assert_eq!((dec!(0.0005), dec!(0), dec!(-0.0005)), trade(&mut data, 40));
+    let tiers = FeeTiersCmd {
+        quote: 100,
+        tiers: vec![],
+    };
+    handle_event(Event::UpdateFeeTiers(50, tiers, 0), &mut data, &tx).unwrap();
+    let vip = UserFeeCmd {
+        user_id: taker,
+        rates: None,
+    };
+    handle_event(Event::UpdateUserFee(51, vip, 0), &mut data, &tx).unwrap();
+    assert_eq!((dec!(0.003), dec!(0.003), dec!(-0.003)), trade(&mut data, 60));
+    assert!(data.fees.volumes.is_empty());
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_delist() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol]);
+    let (alice, bob) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+    assets::add_to_available(&mut data.accounts, &alice, 100, dec!(100)).unwrap();
+    assets::add_to_available(&mut data.accounts, &bob, 101, dec!(10)).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let limit = |user_id, order_id, price, ask_or_bid| {
+        test_limit(symbol, user_id, order_id, price, ask_or_bid)
+    };
+    handle_event(Event::Limit(1, limit(alice, 1, dec!(10), AskOrBid::Bid), 0), &mut data, &tx)
+        .unwrap();
+    handle_event(Event::Limit(2, limit(bob, 2, dec!(11), AskOrBid::Ask), 0), &mut data, &tx)
+        .unwrap();
+    let stop = StopCmd {
+        symbol,
+        user_id: bob,
+        order_id: 3,
+        kind: StopKind::StopLoss,
+        trigger: dec!(9),
+        price: Some(dec!(9)),
+        amount: dec!(2),
+        ask_or_bid: AskOrBid::Ask,
+        trail: None,
+    };
+    handle_event(Event::Stop(3, stop, 0), &mut data, &tx).unwrap();
+    assert_eq!(3, rx.try_iter().count());
+    handle_event(Event::Delist(4, symbol, 0), &mut data, &tx).unwrap();
+    let canceled = rx.try_iter().flatten().map(|o| (o.order_id, o.state)).collect::<Vec<_>>();
+    assert_eq!(3, canceled.len());
+    assert!(canceled.iter().all(|(_, state)| *state == matcher::State::Canceled));
+    assert!(!data.orderbooks.contains_key(&symbol));
+    assert_eq!(dec!(0), assets::get_balance_to_owned(&data.accounts, &alice, 100).frozen);
+    assert_eq!(dec!(10), assets::get_balance_to_owned(&data.accounts, &bob, 101).available);
+    let report = &data.delisted[&symbol];
+    assert_eq!(3, report.canceled);
+    let released = report
+        .released
+        .iter()
+        .map(|r| (r.user_id, r.base, r.quote))
+        .collect::<Vec<_>>();
+    assert_eq!(vec![(alice, dec!(0), dec!(10)), (bob, dec!(3), dec!(0))], released);
+    assert!(handle_event(Event::Delist(5, symbol, 0), &mut data, &tx).is_err());
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_currency_registry() {
+    use rust_decimal_macros::dec;
+    let symbol = (101, 100);
+    let mut data = test_data(&[symbol]);
+    let user = UserId::from_low_u64_be(1);
+    let (tx, rx) = std::sync::mpsc::channel();
+    let transfer_in = |amount| AssetsCmd {
+        user_id: user,
+        in_or_out: InOrOut::In,
+        currency: 100,
+        amount,
+    };
+    let currency = |currency, enabled| CurrencyCmd {
+        currency,
+        config: CurrencyConfig {
+            decimals: 6,
+            min_transfer: dec!(1),
+            max_transfer: None,
+            enabled,
+        },
+    };
+    handle_event(Event::UpdateCurrency(1, currency(100, true), 0), &mut data, &tx).unwrap();
+    assert!(handle_event(Event::TransferIn(2, transfer_in(dec!(0.5)), 0), &mut data, &tx).is_err());
+    assert!(handle_event(Event::TransferIn(3, transfer_in(dec!(1.0000001)), 0), &mut data, &tx)
+        .is_err());
+    handle_event(Event::TransferIn(4, transfer_in(dec!(100)), 0), &mut data, &tx).unwrap();
+    assert_eq!(dec!(100), assets::get_balance_to_owned(&data.accounts, &user, 100).available);
+    let limit = |order_id| test_limit(symbol, user, order_id, dec!(10), AskOrBid::Bid);
+    // the base currency is unknown
+    assert!(handle_event(Event::Limit(5, limit(5), 0), &mut data, &tx).is_err());
+    handle_event(Event::UpdateCurrency(6, currency(101, true), 0), &mut data, &tx).unwrap();
+    handle_event(Event::Limit(7, limit(7), 0), &mut data, &tx).unwrap();
+    handle_event(Event::UpdateCurrency(8, currency(101, false), 0), &mut data, &tx).unwrap();
+    assert!(handle_event(Event::Limit(9, limit(9), 0), &mut data, &tx).is_err());
+    assert_eq!(1, rx.try_iter().count());
+    // the cancels are always accepted, in batches as well
+    let cancel = CancelCmd {
+        symbol,
+        user_id: user,
+        order_id: 7,
+        client_order_id: None,
+    };
+    let batch = BatchCmd {
+        symbol,
+        user_id: user,
+        all_or_nothing: true,
+        orders: vec![BatchItem::Cancel(cancel)],
+    };
+    handle_event(Event::Batch(10, batch, 0), &mut data, &tx).unwrap();
+    assert!(data.orderbooks[&symbol].find_order(7).is_none());
+    // the listed symbols must stay within the decimals
+    let mut fewer = currency(101, true);
+    fewer.config.decimals = 2;
+    assert!(handle_event(Event::UpdateCurrency(11, fewer, 0), &mut data, &tx).is_err());
+}
@@ -0,0 +1,143 @@
+// Copyright 2021 UINB Technologies Pte. Ltd.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::core::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// a negative `maker_fee` is a rebate paid out of the taker fee of the same fill
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct FeeRates {
+    pub taker_fee: Fee,
+    pub maker_fee: Fee,
+}
+
+/// the rates applied once the quote volume traded by a user reaches `volume`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct FeeTier {
+    pub volume: Vol,
+    pub taker_fee: Fee,
+    pub maker_fee: Fee,
+}
+
+/// the fees of a user are the override if present, otherwise the highest tier reached in the
+/// quote currency, otherwise the fees of the symbol
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct FeeSchedule {
+    /// the tiers of each quote currency in ascending order of volume
+    pub tiers: HashMap<Currency, Vec<FeeTier>>,
+    pub overrides: HashMap<UserId, FeeRates>,
+    /// the quote volumes traded since the tiers of the currency were updated, only the
+    /// currencies with tiers are counted
+    pub volumes: HashMap<(UserId, Currency), Vol>,
+}
+
+/// a negative maker fee is a rebate, which must be covered by the taker fee of the same fill
+pub fn valid_fees(taker_fee: Fee, maker_fee: Fee) -> bool {
+    taker_fee.is_sign_positive()
+        && taker_fee < Fee::ONE
+        && maker_fee < Fee::ONE
+        && maker_fee >= -taker_fee
+}
+
+impl FeeSchedule {
+    pub fn rates(&self, user: &UserId, quote: Currency, symbol_rates: FeeRates) -> FeeRates {
+        if let Some(rates) = self.overrides.get(user) {
+            return *rates;
+        }
+        let volume = self.volume(user, quote);
+        self.tiers
+            .get(&quote)
+            .and_then(|tiers| tiers.iter().rev().find(|tier| tier.volume <= volume))
+            .map_or(symbol_rates, |tier| FeeRates {
+                taker_fee: tier.taker_fee,
+                maker_fee: tier.maker_fee,
+            })
+    }
+
+    pub fn volume(&self, user: &UserId, quote: Currency) -> Vol {
+        self.volumes.get(&(*user, quote)).copied().unwrap_or_default()
+    }
+
+    pub fn add_volume(&mut self, user: &UserId, quote: Currency, vol: Vol) {
+        if self.tiers.contains_key(&quote) && !vol.is_zero() {
+            *self.volumes.entry((*user, quote)).or_default() += vol;
+        }
+    }
+
+    /// replace the tiers of the quote currency and count the volumes from zero, no tiers means
+    /// the fees of the symbols
+    pub fn update_tiers(&mut self, quote: Currency, mut tiers: Vec<FeeTier>) {
+        self.volumes.retain(|(_, c), _| *c != quote);
+        tiers.sort_by_key(|tier| tier.volume);
+        if tiers.is_empty() {
+            self.tiers.remove(&quote);
+        } else {
+            self.tiers.insert(quote, tiers);
+        }
+    }
+
+    /// no rates means removing the override
+    pub fn update_override(&mut self, user: UserId, rates: Option<FeeRates>) {
+        match rates {
+            Some(rates) => self.overrides.insert(user, rates),
+            None => self.overrides.remove(&user),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    pub fn test_fee_schedule() {
+        let mut fees = FeeSchedule::default();
+        let (vip, user) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+        let symbol_rates = FeeRates {
+            taker_fee: dec!(0.002),
+            maker_fee: dec!(0.001),
+        };
+        fees.add_volume(&user, 100, dec!(1000));
+        assert_eq!(fees.rates(&user, 100, symbol_rates), symbol_rates);
+        assert_eq!(fees.volume(&user, 100), dec!(0));
+        let tier = |volume, taker_fee, maker_fee| FeeTier {
+            volume,
+            taker_fee,
+            maker_fee,
+        };
+        fees.update_tiers(
+            100,
+            vec![tier(dec!(1000), dec!(0.001), dec!(0)), tier(dec!(0), dec!(0.002), dec!(0.001))],
+        );
+        fees.add_volume(&user, 100, dec!(999));
+        assert_eq!(fees.rates(&user, 100, symbol_rates).taker_fee, dec!(0.002));
+        fees.add_volume(&user, 100, dec!(1));
+        assert_eq!(fees.rates(&user, 100, symbol_rates).taker_fee, dec!(0.001));
+        // the tiers of the other quote currencies don't apply
+        assert_eq!(fees.rates(&user, 101, symbol_rates), symbol_rates);
+        let rates = FeeRates {
+            taker_fee: dec!(0.0005),
+            maker_fee: dec!(0.0001),
+        };
+        fees.update_override(vip, Some(rates));
+        assert_eq!(fees.rates(&vip, 101, symbol_rates), rates);
+        fees.update_override(vip, None);
+        assert_eq!(fees.rates(&vip, 100, symbol_rates).taker_fee, dec!(0.002));
+        fees.update_tiers(100, vec![]);
+        assert_eq!(fees.volume(&user, 100), dec!(0));
+        assert_eq!(fees.rates(&user, 100, symbol_rates), symbol_rates);
+    }
+}
@@ -507,6 +507,7 @@ mod test {
                 orderbooks: orderbooks,
                 accounts: all,
                 merkle_tree: merkle_tree,
+                ..Data::new()
             };
 
             let size = data.orderbooks.get(&(1, 0)).unwrap().size();
@@ -517,6 +518,11 @@ mod test {
                 price: dec!(100),
                 amount: dec!(0.11),
                 ask_or_bid: AskOrBid::Ask,
+                time_in_force: matcher::TimeInForce::GoodTillCancel,
+                self_trade_prevention: None,
+                display: None,
+                expire_at: None,
+                client_order_id: None,
                 nonce: 1,
                 signature: vec![0],
             };
@@ -534,8 +540,11 @@ mod test {
                 cmd2.price,
                 cmd2.amount,
                 cmd2.ask_or_bid,
+                cmd2.time_in_force,
+                SelfTradePrevention::CancelNewest,
             );
-            let cr = clearing::clear(&mut data.accounts, 3, &(1, 0), tf, mf, &mr, 0);
+            let cr =
+                clearing::clear(&mut data.accounts, &mut data.fees, 3, &(1, 0), tf, mf, &mr, 0);
             pp.prove_trade_cmd(
                 &mut data,
                 cmd2.nonce,
@@ -556,6 +565,11 @@ mod test {
                 price: dec!(90),
                 amount: dec!(0.01),
                 ask_or_bid: AskOrBid::Bid,
+                time_in_force: matcher::TimeInForce::GoodTillCancel,
+                self_trade_prevention: None,
+                display: None,
+                expire_at: None,
+                client_order_id: None,
                 nonce: 1,
                 signature: vec![0],
             };
@@ -573,8 +587,11 @@ mod test {
                 cmd2.price,
                 cmd2.amount,
                 cmd2.ask_or_bid,
+                cmd2.time_in_force,
+                SelfTradePrevention::CancelNewest,
             );
-            let cr = clearing::clear(&mut data.accounts, 5, &(1, 0), tf, mf, &mr, 0);
+            let cr =
+                clearing::clear(&mut data.accounts, &mut data.fees, 5, &(1, 0), tf, mf, &mr, 0);
             pp.prove_trade_cmd(
                 &mut data,
                 cmd2.nonce,
@@ -595,6 +612,11 @@ mod test {
                 price: dec!(100),
                 amount: dec!(0.11),
                 ask_or_bid: AskOrBid::Ask,
+                time_in_force: matcher::TimeInForce::GoodTillCancel,
+                self_trade_prevention: None,
+                display: None,
+                expire_at: None,
+                client_order_id: None,
                 nonce: 1,
                 signature: vec![0],
             };
@@ -612,8 +634,11 @@ mod test {
                 cmd2.price,
                 cmd2.amount,
                 cmd2.ask_or_bid,
+                cmd2.time_in_force,
+                SelfTradePrevention::CancelNewest,
             );
-            let cr = clearing::clear(&mut data.accounts, 6, &(1, 0), tf, mf, &mr, 0);
+            let cr =
+                clearing::clear(&mut data.accounts, &mut data.fees, 6, &(1, 0), tf, mf, &mr, 0);
             pp.prove_trade_cmd(
                 &mut data,
                 cmd2.nonce,
@@ -634,6 +659,11 @@ mod test {
                 price: dec!(110),
                 amount: dec!(0.5),
                 ask_or_bid: AskOrBid::Bid,
+                time_in_force: matcher::TimeInForce::GoodTillCancel,
+                self_trade_prevention: None,
+                display: None,
+                expire_at: None,
+                client_order_id: None,
                 nonce: 1,
                 signature: vec![0],
             };
@@ -651,8 +681,11 @@ mod test {
                 cmd2.price,
                 cmd2.amount,
                 cmd2.ask_or_bid,
+                cmd2.time_in_force,
+                SelfTradePrevention::CancelNewest,
             );
-            let cr = clearing::clear(&mut data.accounts, 7, &(1, 0), tf, mf, &mr, 0);
+            let cr =
+                clearing::clear(&mut data.accounts, &mut data.fees, 7, &(1, 0), tf, mf, &mr, 0);
             pp.prove_trade_cmd(
                 &mut data,
                 cmd2.nonce,
@@ -674,6 +707,11 @@ mod test {
                 price: dec!(88),
                 amount: dec!(0.3),
                 ask_or_bid: AskOrBid::Ask,
+                time_in_force: matcher::TimeInForce::GoodTillCancel,
+                self_trade_prevention: None,
+                display: None,
+                expire_at: None,
+                client_order_id: None,
                 nonce: 1,
                 signature: vec![0],
             };
@@ -691,8 +729,11 @@ mod test {
                 cmd2.price,
                 cmd2.amount,
                 cmd2.ask_or_bid,
+                cmd2.time_in_force,
+                SelfTradePrevention::CancelNewest,
             );
-            let cr = clearing::clear(&mut data.accounts, 8, &(1, 0), tf, mf, &mr, 0);
+            let cr =
+                clearing::clear(&mut data.accounts, &mut data.fees, 8, &(1, 0), tf, mf, &mr, 0);
             pp.prove_trade_cmd(
                 &mut data,
                 cmd2.nonce,
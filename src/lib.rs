@@ -24,8 +24,10 @@ pub mod assets;
 pub mod clearing;
 pub mod config;
 pub mod core;
+pub mod currency;
 pub mod db;
 pub mod event;
+pub mod fees;
 #[cfg(feature = "fusotao")]
 pub mod fusotao;
 pub mod matcher;
@@ -34,3 +36,4 @@ pub mod output;
 pub mod sequence;
 pub mod server;
 pub mod snapshot;
+pub mod symbol;
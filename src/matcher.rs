@@ -13,7 +13,11 @@
 // limitations under the License.
 
 use crate::core::*;
-use crate::orderbook::{AskOrBid, Order, OrderBook, OrderPage};
+use crate::orderbook::{
+    AskOrBid, MatchingAlgorithm, Order, OrderBook, OrderPage, SelfTradePrevention, StopOrder,
+};
+use rust_decimal::RoundingStrategy;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum State {
@@ -22,6 +26,90 @@ pub enum State {
     Filled,
     PartialFilled,
     ConditionalCanceled,
+    IocCanceled,
+    FokKilled,
+    PostOnlyRejected,
+    Replaced,
+    Decremented,
+    Triggering,
+    Expired,
+    /// the sibling of a filled, canceled or triggered OCO leg, `canceled` of the taker is the
+    /// frozen funds released rather than an amount
+    OcoCanceled,
+    /// the stop leg follows the partially filled limit leg of the OCO group, `canceled` of the
+    /// taker is the frozen funds released
+    OcoReduced,
+    /// an item of a best-effort batch failed, nothing is changed
+    Rejected,
+    /// a retry of the order with the same client order id, nothing is changed
+    Duplicated,
+    /// the quote stays as it was in a new ladder, the freeze change is applied by the ladder
+    QuoteKept,
+    /// the quote is absent from a new ladder, the freeze change is applied by the ladder
+    QuoteDropped,
+}
+
+impl State {
+    /// the unfilled part of taker in these states won't be placed, so it should be unfrozen
+    pub const fn releases_unfilled(&self) -> bool {
+        matches!(
+            self,
+            State::ConditionalCanceled
+                | State::IocCanceled
+                | State::FokKilled
+                | State::PostOnlyRejected
+        )
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum TimeInForce {
+    GoodTillCancel,
+    ImmediateOrCancel,
+    FillOrKill,
+    PostOnly,
+    /// the price should be moved to one tick away from the opposite best by the caller,
+    /// see `OrderBook::post_only_price`
+    PostOnlyReprice,
+}
+
+impl Default for TimeInForce {
+    fn default() -> Self {
+        Self::GoodTillCancel
+    }
+}
+
+impl Into<u32> for TimeInForce {
+    fn into(self) -> u32 {
+        match self {
+            TimeInForce::GoodTillCancel => 0,
+            TimeInForce::ImmediateOrCancel => 1,
+            TimeInForce::FillOrKill => 2,
+            TimeInForce::PostOnly => 3,
+            TimeInForce::PostOnlyReprice => 4,
+        }
+    }
+}
+
+impl std::convert::TryFrom<u32> for TimeInForce {
+    type Error = anyhow::Error;
+
+    fn try_from(x: u32) -> anyhow::Result<Self> {
+        match x {
+            0 => Ok(TimeInForce::GoodTillCancel),
+            1 => Ok(TimeInForce::ImmediateOrCancel),
+            2 => Ok(TimeInForce::FillOrKill),
+            3 => Ok(TimeInForce::PostOnly),
+            4 => Ok(TimeInForce::PostOnlyReprice),
+            _ => Err(anyhow::anyhow!("invalid time in force")),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum OrderType {
+    Limit,
+    Market,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -47,6 +135,19 @@ impl Into<u32> for State {
             State::Filled => 2,
             State::PartialFilled => 3,
             State::ConditionalCanceled => 4,
+            State::IocCanceled => 5,
+            State::FokKilled => 6,
+            State::PostOnlyRejected => 7,
+            State::Replaced => 8,
+            State::Decremented => 9,
+            State::Triggering => 10,
+            State::Expired => 11,
+            State::OcoCanceled => 12,
+            State::OcoReduced => 13,
+            State::Rejected => 14,
+            State::Duplicated => 15,
+            State::QuoteKept => 16,
+            State::QuoteDropped => 17,
         }
     }
 }
@@ -59,6 +160,10 @@ pub struct Taker {
     pub unfilled: Amount,
     pub ask_or_bid: AskOrBid,
     pub state: State,
+    pub order_type: OrderType,
+    /// the part decremented by self-trade prevention, which is excluded from `unfilled`,
+    /// measured in quote for market bids
+    pub canceled: Amount,
 }
 
 impl Taker {
@@ -67,9 +172,48 @@ impl Taker {
             user_id: order.user,
             order_id: order.id,
             price: order.price,
-            unfilled: order.unfilled,
+            unfilled: order.total_unfilled(),
             ask_or_bid: ask_or_bid,
             state,
+            order_type: OrderType::Limit,
+            canceled: Amount::ZERO,
+        }
+    }
+
+    /// market taker has no price, the `unfilled` of a market bid is the unused quote budget
+    pub const fn market(
+        user_id: UserId,
+        order_id: u64,
+        unfilled: Amount,
+        ask_or_bid: AskOrBid,
+        state: State,
+    ) -> Self {
+        Self {
+            user_id,
+            order_id,
+            price: Price::ZERO,
+            unfilled,
+            ask_or_bid,
+            state,
+            order_type: OrderType::Market,
+            canceled: Amount::ZERO,
+        }
+    }
+
+    pub fn with_canceled(mut self, canceled: Amount) -> Self {
+        self.canceled = canceled;
+        self
+    }
+
+    /// an untriggered stop order
+    pub fn stop(stop: &StopOrder, state: State) -> Self {
+        match stop.price {
+            Some(price) => Self::taker(
+                Order::new(stop.id, stop.user, price, stop.amount),
+                stop.ask_or_bid,
+                state,
+            ),
+            None => Self::market(stop.user, stop.id, stop.amount, stop.ask_or_bid, state),
         }
     }
 
@@ -86,6 +230,8 @@ impl Taker {
             unfilled: Amount::ZERO,
             ask_or_bid,
             state: State::Filled,
+            order_type: OrderType::Limit,
+            canceled: Amount::ZERO,
         }
     }
 
@@ -103,6 +249,8 @@ impl Taker {
             unfilled,
             ask_or_bid,
             state: State::PartialFilled,
+            order_type: OrderType::Limit,
+            canceled: Amount::ZERO,
         }
     }
 
@@ -120,6 +268,8 @@ impl Taker {
             unfilled,
             ask_or_bid,
             state: State::Canceled,
+            order_type: OrderType::Limit,
+            canceled: Amount::ZERO,
         }
     }
 }
@@ -131,6 +281,8 @@ pub struct Maker {
     pub price: Price,
     pub filled: Amount,
     pub state: State,
+    /// the part canceled by self-trade prevention
+    pub canceled: Amount,
 }
 
 impl Maker {
@@ -146,6 +298,7 @@ impl Maker {
             price,
             filled,
             state: State::Filled,
+            canceled: Amount::ZERO,
         }
     }
 
@@ -161,8 +314,35 @@ impl Maker {
             price,
             filled,
             state: State::PartialFilled,
+            canceled: Amount::ZERO,
+        }
+    }
+
+    /// the maker is removed from the orderbook if nothing left, otherwise it's decremented
+    pub const fn maker_canceled(
+        user_id: UserId,
+        order_id: u64,
+        price: Price,
+        canceled: Amount,
+        removed: bool,
+    ) -> Self {
+        Self {
+            user_id,
+            order_id,
+            price,
+            filled: Amount::ZERO,
+            state: if removed {
+                State::ConditionalCanceled
+            } else {
+                State::Decremented
+            },
+            canceled,
         }
     }
+
+    pub fn is_removed(&self) -> bool {
+        matches!(self.state, State::Filled | State::ConditionalCanceled)
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -178,58 +358,259 @@ pub fn execute_limit(
     price: Price,
     amount: Amount,
     ask_or_bid: AskOrBid,
+    time_in_force: TimeInForce,
+    stp: SelfTradePrevention,
 ) -> Match {
     let mut makers = Vec::<Maker>::new();
     let mut order = Order::new(order_id, user_id, price, amount);
+    let mut canceled = Amount::ZERO;
+    let (algorithm, base_scale) = (book.matching_algorithm, book.base_scale);
+    if book.auction {
+        book.insert(order.clone(), ask_or_bid);
+        return Match {
+            maker: makers,
+            taker: Taker::taker(order, ask_or_bid, State::Submitted),
+        };
+    }
+    match time_in_force {
+        TimeInForce::PostOnly | TimeInForce::PostOnlyReprice
+            if book.get_best_if_match(ask_or_bid, &price).is_some() =>
+        {
+            return Match {
+                maker: makers,
+                taker: Taker::taker(order, ask_or_bid, State::PostOnlyRejected),
+            };
+        }
+        TimeInForce::FillOrKill if !fillable(book, &order, ask_or_bid, stp) => {
+            return Match {
+                maker: makers,
+                taker: Taker::taker(order, ask_or_bid, State::FokKilled),
+            };
+        }
+        _ => {}
+    }
     loop {
         if order.is_filled() {
             return Match {
                 maker: makers,
-                taker: Taker::taker(order, ask_or_bid, State::Filled),
+                taker: Taker::taker(order, ask_or_bid, State::Filled).with_canceled(canceled),
             };
         }
         if let Some(mut best) = book.get_best_if_match(ask_or_bid, &order.price) {
             let page = best.get_mut();
-            let (mut traded, self_traded) = take(page, &mut order);
+            let (mut traded, decremented, self_traded) =
+                take(page, &mut order, stp, algorithm, base_scale);
             if page.is_empty() {
                 best.remove();
             }
             traded.iter().for_each(|m| {
-                if m.state == State::Filled {
-                    book.indices.remove(&m.order_id);
+                if m.is_removed() {
+                    book.forget(m.order_id, &m.user_id);
                 }
             });
+            if let Some(m) = traded.iter().rev().find(|m| !m.filled.is_zero()) {
+                book.last_price.replace(m.price);
+            }
             makers.append(&mut traded);
+            canceled += decremented;
             if self_traded {
                 return Match {
-                    taker: Taker::taker(order, ask_or_bid, State::ConditionalCanceled),
+                    taker: Taker::taker(order, ask_or_bid, State::ConditionalCanceled)
+                        .with_canceled(canceled),
                     maker: makers,
                 };
             }
         } else {
+            if matches!(
+                time_in_force,
+                TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill
+            ) {
+                return Match {
+                    taker: Taker::taker(order, ask_or_bid, State::IocCanceled)
+                        .with_canceled(canceled),
+                    maker: makers,
+                };
+            }
             book.insert(order.clone(), ask_or_bid);
             return Match {
-                taker: match makers.is_empty() {
+                taker: match makers.iter().all(|m| m.filled.is_zero()) {
                     true => Taker::taker(order, ask_or_bid, State::Submitted),
                     false => Taker::taker(order, ask_or_bid, State::PartialFilled),
-                },
+                }
+                .with_canceled(canceled),
+                maker: makers,
+            };
+        }
+    }
+}
+
+/// whether the taker could be filled entirely, the matching stops at the first order of
+/// the taker itself unless the self-trade prevention cancels the resting ones
+fn fillable(
+    book: &OrderBook,
+    taker: &Order,
+    ask_or_bid: AskOrBid,
+    stp: SelfTradePrevention,
+) -> bool {
+    let pages: Box<dyn Iterator<Item = &OrderPage>> = match ask_or_bid {
+        AskOrBid::Bid => Box::new(book.asks.values().take_while(|p| p.price <= taker.price)),
+        AskOrBid::Ask => Box::new(book.bids.values().rev().take_while(|p| p.price >= taker.price)),
+    };
+    let mut sum = Amount::ZERO;
+    for maker in pages.flat_map(|p| p.orders.values()) {
+        if maker.user == taker.user {
+            if stp == SelfTradePrevention::CancelOldest {
+                continue;
+            }
+            return false;
+        }
+        sum += maker.total_unfilled();
+        if sum >= taker.unfilled {
+            return true;
+        }
+    }
+    false
+}
+
+pub fn execute_market(
+    book: &mut OrderBook,
+    user_id: UserId,
+    order_id: u64,
+    amount: Amount,
+    ask_or_bid: AskOrBid,
+    stp: SelfTradePrevention,
+) -> Match {
+    let mut makers = Vec::<Maker>::new();
+    // base amount of asks, quote budget of bids
+    let mut remain = amount;
+    let mut canceled = Amount::ZERO;
+    let (algorithm, base_scale) = (book.matching_algorithm, book.base_scale);
+    loop {
+        if remain.is_zero() {
+            return Match {
+                maker: makers,
+                taker: Taker::market(user_id, order_id, remain, ask_or_bid, State::Filled)
+                    .with_canceled(canceled),
+            };
+        }
+        if let Some(mut best) = book.get_best(ask_or_bid) {
+            let page = best.get_mut();
+            let price = page.price;
+            let mut order = match ask_or_bid {
+                AskOrBid::Ask => Order::new(order_id, user_id, price, remain),
+                AskOrBid::Bid => {
+                    let affordable = remain
+                        .checked_div(price)
+                        .map(|a| a.round_dp_with_strategy(base_scale, RoundingStrategy::ToZero))
+                        .unwrap_or(page.amount);
+                    // the rest of budget is too small to buy any
+                    if affordable.is_zero() {
+                        return Match {
+                            maker: makers,
+                            taker: Taker::market(
+                                user_id,
+                                order_id,
+                                remain,
+                                ask_or_bid,
+                                State::Filled,
+                            )
+                            .with_canceled(canceled),
+                        };
+                    }
+                    Order::new(order_id, user_id, price, affordable)
+                }
+            };
+            let base = order.unfilled;
+            let (mut traded, decremented, self_traded) =
+                take(page, &mut order, stp, algorithm, base_scale);
+            if page.is_empty() {
+                best.remove();
+            }
+            traded.iter().for_each(|m| {
+                if m.is_removed() {
+                    book.forget(m.order_id, &m.user_id);
+                }
+            });
+            if let Some(m) = traded.iter().rev().find(|m| !m.filled.is_zero()) {
+                book.last_price.replace(m.price);
+            }
+            // the decremented part is taken out of `remain` as well
+            remain -= match ask_or_bid {
+                AskOrBid::Ask => base - order.unfilled,
+                AskOrBid::Bid => (base - order.unfilled) * price,
+            };
+            canceled += match ask_or_bid {
+                AskOrBid::Ask => decremented,
+                AskOrBid::Bid => decremented * price,
+            };
+            makers.append(&mut traded);
+            if self_traded {
+                return Match {
+                    maker: makers,
+                    taker: Taker::market(
+                        user_id,
+                        order_id,
+                        remain,
+                        ask_or_bid,
+                        State::ConditionalCanceled,
+                    )
+                    .with_canceled(canceled),
+                };
+            }
+        } else {
+            // market orders never rest in the orderbook
+            return Match {
                 maker: makers,
+                taker: Taker::market(
+                    user_id,
+                    order_id,
+                    remain,
+                    ask_or_bid,
+                    State::ConditionalCanceled,
+                )
+                .with_canceled(canceled),
             };
         }
     }
 }
 
-fn take(page: &mut OrderPage, taker: &mut Order) -> (Vec<Maker>, bool) {
+/// returns the makers, the amount decremented from the taker and whether the taker should stop
+fn take(
+    page: &mut OrderPage,
+    taker: &mut Order,
+    stp: SelfTradePrevention,
+    algorithm: MatchingAlgorithm,
+    base_scale: Scale,
+) -> (Vec<Maker>, Amount, bool) {
+    if algorithm == MatchingAlgorithm::ProRata {
+        return take_pro_rata(page, taker, stp, base_scale);
+    }
     let mut matches = Vec::<Maker>::new();
+    let mut decremented = Amount::ZERO;
     while !taker.is_filled() && !page.is_empty() {
-        let mut oldest = page.orders.entries().next().unwrap();
-        if oldest.get().user == taker.user {
-            return (matches, true);
+        let oldest = page.orders.values().next().unwrap();
+        if oldest.user == taker.user {
+            let id = oldest.id;
+            if prevent_self_trade(page, id, taker, stp, &mut matches, &mut decremented) {
+                return (matches, decremented, true);
+            }
+            continue;
         }
+        let mut oldest = page.orders.entries().next().unwrap();
         let m = if taker.unfilled >= oldest.get().unfilled {
-            let maker = oldest.get().clone();
-            oldest.remove();
-            Maker::maker_filled(maker.user, maker.id, maker.price, maker.unfilled)
+            let mut maker = oldest.remove();
+            let filled = maker.unfilled;
+            maker.fill(filled);
+            if maker.hidden.is_zero() {
+                Maker::maker_filled(maker.user, maker.id, maker.price, filled)
+            } else {
+                // the refilled iceberg goes to the back of the queue
+                let visible = maker.refill();
+                page.amount += visible;
+                let m = Maker::maker_so_far(maker.user, maker.id, maker.price, filled);
+                page.orders.insert(maker.id, maker);
+                m
+            }
         } else {
             let maker = oldest.get_mut();
             maker.fill(taker.unfilled);
@@ -239,14 +620,564 @@ fn take(page: &mut OrderPage, taker: &mut Order) -> (Vec<Maker>, bool) {
         page.decr_size(&m.filled);
         matches.push(m);
     }
-    (matches, false)
+    (matches, decremented, false)
+}
+
+/// cancel or decrement the maker of the taker's user, return true if the taker should stop
+fn prevent_self_trade(
+    page: &mut OrderPage,
+    maker_id: OrderId,
+    taker: &mut Order,
+    stp: SelfTradePrevention,
+    matches: &mut Vec<Maker>,
+    decremented: &mut Amount,
+) -> bool {
+    match stp {
+        SelfTradePrevention::CancelNewest => true,
+        SelfTradePrevention::CancelOldest | SelfTradePrevention::CancelBoth => {
+            let maker = page.orders.remove(&maker_id).unwrap();
+            page.decr_size(&maker.unfilled);
+            matches.push(Maker::maker_canceled(
+                maker.user,
+                maker.id,
+                maker.price,
+                maker.total_unfilled(),
+                true,
+            ));
+            stp == SelfTradePrevention::CancelBoth
+        }
+        SelfTradePrevention::DecrementAndCancel => {
+            let maker = page.orders.get_mut(&maker_id).unwrap();
+            if maker.total_unfilled() < taker.unfilled {
+                let maker = page.orders.remove(&maker_id).unwrap();
+                page.decr_size(&maker.unfilled);
+                taker.fill(maker.total_unfilled());
+                *decremented += maker.total_unfilled();
+                matches.push(Maker::maker_canceled(
+                    maker.user,
+                    maker.id,
+                    maker.price,
+                    maker.total_unfilled(),
+                    true,
+                ));
+                false
+            } else {
+                let delta = taker.unfilled;
+                let visible = maker.reduce(delta);
+                let removed = maker.is_filled();
+                matches.push(Maker::maker_canceled(
+                    maker.user,
+                    maker.id,
+                    maker.price,
+                    delta,
+                    removed,
+                ));
+                if removed {
+                    page.orders.remove(&maker_id);
+                }
+                page.decr_size(&visible);
+                true
+            }
+        }
+    }
+}
+
+/// all the orders of the page trade at once, so the self-trade prevention is applied to the
+/// orders of the taker's user before allocating
+fn take_pro_rata(
+    page: &mut OrderPage,
+    taker: &mut Order,
+    stp: SelfTradePrevention,
+    base_scale: Scale,
+) -> (Vec<Maker>, Amount, bool) {
+    let mut matches = Vec::<Maker>::new();
+    let mut decremented = Amount::ZERO;
+    let own = page
+        .orders
+        .values()
+        .filter(|o| o.user == taker.user)
+        .map(|o| o.id)
+        .collect::<Vec<_>>();
+    for id in own {
+        // the other orders of the user are canceled as well by `CancelBoth`
+        let stop = prevent_self_trade(page, id, taker, stp, &mut matches, &mut decremented);
+        if stop && stp != SelfTradePrevention::CancelBoth {
+            return (matches, decremented, true);
+        }
+    }
+    if stp == SelfTradePrevention::CancelBoth && !matches.is_empty() {
+        return (matches, decremented, true);
+    }
+    let top = match page.orders.keys().next() {
+        Some(id) if !taker.is_filled() => *id,
+        _ => return (matches, decremented, false),
+    };
+    let filled = taker.unfilled.min(page.orders[&top].unfilled);
+    matches.push(fill_maker(page, top, filled));
+    taker.fill(filled);
+    let rest = page
+        .orders
+        .values()
+        .filter(|o| o.id != top)
+        .map(|o| (o.id, o.unfilled))
+        .collect::<Vec<_>>();
+    let total = rest.iter().map(|(_, unfilled)| *unfilled).sum::<Amount>();
+    if taker.is_filled() || total.is_zero() {
+        return (matches, decremented, false);
+    }
+    let remain = taker.unfilled.min(total);
+    let mut allocated = rest
+        .iter()
+        .map(|(_, unfilled)| {
+            (remain * unfilled / total).round_dp_with_strategy(base_scale, RoundingStrategy::ToZero)
+        })
+        .collect::<Vec<_>>();
+    // the rounding remainder goes to the orders in time priority, one unit of `base_scale` each
+    let unit = Amount::new(1, base_scale);
+    let mut left = remain - allocated.iter().sum::<Amount>();
+    while !left.is_zero() {
+        for (i, (_, unfilled)) in rest.iter().enumerate() {
+            let delta = unit.min(left).min(unfilled - allocated[i]);
+            allocated[i] += delta;
+            left -= delta;
+        }
+    }
+    for ((id, _), filled) in rest.iter().zip(allocated) {
+        if !filled.is_zero() {
+            matches.push(fill_maker(page, *id, filled));
+            taker.fill(filled);
+        }
+    }
+    (matches, decremented, false)
+}
+
+/// the filled iceberg is refilled and goes to the back of the queue
+fn fill_maker(page: &mut OrderPage, order_id: OrderId, filled: Amount) -> Maker {
+    page.decr_size(&filled);
+    let maker = page.orders.get_mut(&order_id).unwrap();
+    maker.fill(filled);
+    if !maker.is_filled() {
+        return Maker::maker_so_far(maker.user, maker.id, maker.price, filled);
+    }
+    let mut maker = page.orders.remove(&order_id).unwrap();
+    if maker.hidden.is_zero() {
+        return Maker::maker_filled(maker.user, maker.id, maker.price, filled);
+    }
+    page.amount += maker.refill();
+    let m = Maker::maker_so_far(maker.user, maker.id, maker.price, filled);
+    page.orders.insert(maker.id, maker);
+    m
 }
 
+/// canceling either leg of an OCO group cancels its limit leg, the stop leg is left to
+/// `settle_oco`
 pub fn cancel(orderbook: &mut OrderBook, order_id: u64) -> Option<Match> {
-    orderbook.remove(order_id).map(|(order, from)| Match {
+    if let Some(limit_id) = orderbook.stops.get(order_id).and_then(|stop| stop.oco) {
+        return cancel(orderbook, limit_id);
+    }
+    match orderbook.remove(order_id) {
+        Some((order, from)) => Some(Match {
+            maker: vec![],
+            taker: Taker::taker(order, from, State::Canceled),
+        }),
+        None => orderbook.stops.remove(order_id).map(|stop| Match {
+            maker: vec![],
+            taker: Taker::stop(&stop, State::Canceled),
+        }),
+    }
+}
+
+/// remove the orders expired at `now`
+pub fn expire(orderbook: &mut OrderBook, now: Timestamp) -> Vec<Match> {
+    let mut matches = Vec::<Match>::new();
+    while let Some(order_id) = orderbook.pop_expired(now) {
+        if let Some((order, from)) = orderbook.remove(order_id) {
+            matches.push(Match {
+                maker: vec![],
+                taker: Taker::taker(order, from, State::Expired),
+            });
+        }
+    }
+    matches
+}
+
+pub fn place_stop(orderbook: &mut OrderBook, stop: StopOrder) -> Match {
+    let taker = Taker::stop(&stop, State::Triggering);
+    orderbook.insert_stop(stop);
+    Match {
         maker: vec![],
-        taker: Taker::taker(order, from, State::Canceled),
-    })
+        taker,
+    }
+}
+
+/// fire the stop orders crossed by the last price one by one, including those crossed by the
+/// triggered ones
+pub fn trigger_stops(orderbook: &mut OrderBook) -> Vec<Match> {
+    let mut matches = Vec::<Match>::new();
+    while let Some(stop) = orderbook.last_price.and_then(|last_price| {
+        orderbook.stops.trail(last_price);
+        orderbook.stops.pop_triggered(last_price)
+    }) {
+        if let Some(limit_id) = stop.oco {
+            // the funds of the limit leg are handed over to the triggered stop leg
+            if let Some((order, side)) = orderbook.remove(limit_id) {
+                let (_, limit_price) = orderbook.oco_groups.remove(&limit_id).unwrap_or_default();
+                let released = match side {
+                    AskOrBid::Ask => order.total_unfilled() - stop.amount,
+                    AskOrBid::Bid => {
+                        order.total_unfilled() * limit_price
+                            + oco_reserve(&stop, limit_price, stop.amount)
+                            - stop.amount * stop.price.unwrap_or(limit_price)
+                    }
+                };
+                matches.push(Match {
+                    maker: vec![],
+                    taker: Taker::taker(order, side, State::OcoCanceled).with_canceled(released),
+                });
+            }
+        }
+        let stp = orderbook.self_trade_prevention;
+        let mr = match stop.price {
+            Some(price) => execute_limit(
+                orderbook,
+                stop.user,
+                stop.id,
+                price,
+                stop.amount,
+                stop.ask_or_bid,
+                TimeInForce::GoodTillCancel,
+                stp,
+            ),
+            None => execute_market(
+                orderbook,
+                stop.user,
+                stop.id,
+                stop.amount,
+                stop.ask_or_bid,
+                stp,
+            ),
+        };
+        let settled = settle_oco(orderbook, &mr);
+        matches.push(mr);
+        matches.extend(settled);
+    }
+    matches
+}
+
+/// the frozen funds a bid stop leg needs beyond its limit leg, asks share the same base
+fn oco_reserve(stop: &StopOrder, limit_price: Price, amount: Amount) -> Amount {
+    match (stop.ask_or_bid, stop.price) {
+        (AskOrBid::Bid, Some(price)) if price > limit_price => amount * (price - limit_price),
+        _ => Amount::ZERO,
+    }
+}
+
+/// the OCO group is frozen once by `amount` of an ask or `amount * max(price, stop price)` of
+/// a bid, the stop leg is placed first so the limit leg is settled like a resting one
+pub fn execute_oco(
+    book: &mut OrderBook,
+    user_id: UserId,
+    order_id: u64,
+    price: Price,
+    amount: Amount,
+    ask_or_bid: AskOrBid,
+    stop: StopOrder,
+    stp: SelfTradePrevention,
+) -> Vec<Match> {
+    book.oco_groups.insert(order_id, (stop.id, price));
+    let placed = place_stop(book, stop);
+    let mr = execute_limit(
+        book,
+        user_id,
+        order_id,
+        price,
+        amount,
+        ask_or_bid,
+        TimeInForce::GoodTillCancel,
+        stp,
+    );
+    let settled = settle_oco(book, &mr);
+    let mut matches = vec![placed, mr];
+    matches.extend(settled);
+    matches
+}
+
+/// cancel or reduce the stop legs whose limit legs are filled or canceled in the match
+pub fn settle_oco(book: &mut OrderBook, mr: &Match) -> Vec<Match> {
+    let mut matches = Vec::<Match>::new();
+    let touched = std::iter::once(mr.taker.order_id).chain(mr.maker.iter().map(|m| m.order_id));
+    for limit_id in touched {
+        let (stop_id, limit_price) = match book.oco_groups.get(&limit_id) {
+            Some(group) => *group,
+            None => continue,
+        };
+        let unfilled = book.find_order(limit_id).map_or(Amount::ZERO, |o| o.total_unfilled());
+        if unfilled.is_zero() {
+            book.oco_groups.remove(&limit_id);
+            if let Some(stop) = book.stops.remove(stop_id) {
+                let released = oco_reserve(&stop, limit_price, stop.amount);
+                matches.push(Match {
+                    maker: vec![],
+                    taker: Taker::stop(&stop, State::OcoCanceled).with_canceled(released),
+                });
+            }
+        } else if let Some(stop) = book.stops.get_mut(stop_id).filter(|s| s.amount > unfilled) {
+            let released = oco_reserve(stop, limit_price, stop.amount - unfilled);
+            stop.amount = unfilled;
+            matches.push(Match {
+                maker: vec![],
+                taker: Taker::stop(stop, State::OcoReduced).with_canceled(released),
+            });
+        }
+    }
+    matches
+}
+
+/// decreasing the amount only keeps the queue position, otherwise the order is taken out and
+/// matched again with the new price and amount
+pub fn replace(
+    orderbook: &mut OrderBook,
+    order_id: u64,
+    price: Price,
+    amount: Amount,
+) -> Option<Match> {
+    let (order, ask_or_bid) = orderbook.find_order_with_side(order_id)?;
+    if order.price == price && order.total_unfilled() >= amount {
+        let order = orderbook.reduce_order(order_id, amount)?;
+        return Some(Match {
+            maker: vec![],
+            taker: Taker::taker(order, ask_or_bid, State::Replaced),
+        });
+    }
+    let (order, ask_or_bid) = orderbook.remove(order_id)?;
+    let stp = orderbook.self_trade_prevention;
+    let mut mr = execute_limit(
+        orderbook,
+        order.user,
+        order_id,
+        price,
+        amount,
+        ask_or_bid,
+        TimeInForce::GoodTillCancel,
+        stp,
+    );
+    if mr.taker.state == State::Submitted {
+        mr.taker.state = State::Replaced;
+    }
+    // an iceberg stays an iceberg
+    if !order.display.is_zero() {
+        orderbook.hide(order_id, order.display);
+    }
+    Some(mr)
+}
+
+/// replace the ladder of a market maker with `levels` of order id, price, amount and side, the
+/// ladder orders absent from `levels` are dropped, the others are kept or reduced in place if
+/// possible, otherwise they're pulled and placed again after the other changes, so the ladder
+/// never trades with its old quotes
+pub fn quote(
+    orderbook: &mut OrderBook,
+    user_id: UserId,
+    ladder: &[OrderId],
+    levels: &[(OrderId, Price, Amount, AskOrBid)],
+) -> Vec<Match> {
+    let mut matches = Vec::<Match>::new();
+    let mut pulled = Vec::<OrderId>::new();
+    for &order_id in ladder {
+        let (order, ask_or_bid) = match orderbook.find_order_with_side(order_id) {
+            Some((order, ask_or_bid)) => (order.clone(), ask_or_bid),
+            None => continue,
+        };
+        let (state, amount) = match levels.iter().find(|level| level.0 == order_id) {
+            None => (State::QuoteDropped, Amount::ZERO),
+            Some(&(_, price, amount, _)) if price == order.price => {
+                match order.total_unfilled().cmp(&amount) {
+                    std::cmp::Ordering::Equal => (State::QuoteKept, amount),
+                    std::cmp::Ordering::Greater => (State::Replaced, amount),
+                    std::cmp::Ordering::Less => (State::Submitted, amount),
+                }
+            }
+            Some(&(_, _, amount, _)) => (State::Submitted, amount),
+        };
+        let taker = match state {
+            State::QuoteDropped => orderbook
+                .remove(order_id)
+                .map(|(order, _)| Taker::taker(order, ask_or_bid, state)),
+            State::QuoteKept => Some(Taker::taker(order, ask_or_bid, state)),
+            State::Replaced => orderbook
+                .reduce_order(order_id, amount)
+                .map(|order| Taker::taker(order, ask_or_bid, state)),
+            // placed again with the new price or the larger amount below
+            _ => {
+                orderbook.remove(order_id);
+                pulled.push(order_id);
+                None
+            }
+        };
+        if let Some(taker) = taker {
+            matches.push(Match {
+                maker: vec![],
+                taker,
+            });
+        }
+    }
+    let stp = orderbook.self_trade_prevention;
+    for &(order_id, price, amount, ask_or_bid) in levels {
+        let replaced = pulled.contains(&order_id);
+        if !replaced && ladder.contains(&order_id) {
+            continue;
+        }
+        let mut mr = execute_limit(
+            orderbook,
+            user_id,
+            order_id,
+            price,
+            amount,
+            ask_or_bid,
+            TimeInForce::GoodTillCancel,
+            stp,
+        );
+        if replaced && mr.taker.state == State::Submitted {
+            mr.taker.state = State::Replaced;
+        }
+        matches.push(mr);
+    }
+    matches
+}
+
+/// the price maximising the executable volume of a crossed book, the ties are broken by the
+/// minimum surplus, then the market pressure and then the distance to the last price
+pub fn auction_price(book: &OrderBook) -> Option<(Price, Amount)> {
+    let total = |page: &OrderPage| page.orders.values().map(|o| o.total_unfilled()).sum::<Amount>();
+    let mut candidates = book
+        .bids
+        .keys()
+        .chain(book.asks.keys())
+        .map(|p| {
+            let bid: Amount = book.bids.range(p..).map(|(_, page)| total(page)).sum();
+            let ask: Amount = book.asks.range(..=p).map(|(_, page)| total(page)).sum();
+            (*p, bid, ask)
+        })
+        .collect::<Vec<_>>();
+    let volume = candidates.iter().map(|(_, bid, ask)| *bid.min(ask)).max()?;
+    if volume.is_zero() {
+        return None;
+    }
+    candidates.retain(|(_, bid, ask)| *bid.min(ask) == volume);
+    let surplus = candidates.iter().map(|(_, bid, ask)| (bid - ask).abs()).min()?;
+    candidates.retain(|(_, bid, ask)| (bid - ask).abs() == surplus);
+    candidates.sort_by_key(|(p, _, _)| *p);
+    candidates.dedup_by(|x, y| x.0 == y.0);
+    let price = if candidates.iter().all(|(_, bid, ask)| bid > ask) {
+        candidates.last()?.0
+    } else if candidates.iter().all(|(_, bid, ask)| bid < ask) {
+        candidates.first()?.0
+    } else {
+        match book.last_price {
+            Some(last) => candidates.iter().min_by_key(|(p, _, _)| (p - last).abs())?.0,
+            None => candidates.first()?.0,
+        }
+    };
+    Some((price, volume))
+}
+
+/// match the crossed orders at the auction price and switch to continuous trading, the bids
+/// are taken as the takers in price-time priority
+pub fn uncross(book: &mut OrderBook) -> Vec<Match> {
+    let mut matches = Vec::<Match>::new();
+    book.auction = false;
+    let price = match auction_price(book) {
+        Some((price, _)) => price,
+        None => return matches,
+    };
+    let stp = book.self_trade_prevention;
+    let (algorithm, base_scale) = (book.matching_algorithm, book.base_scale);
+    while let Some(bid) = book
+        .bids
+        .range(price..)
+        .next_back()
+        .and_then(|(_, page)| page.orders.values().next())
+        .cloned()
+    {
+        let mut taker = Order::new(bid.id, bid.user, bid.price, bid.total_unfilled());
+        let mut makers = Vec::<Maker>::new();
+        let mut canceled = Amount::ZERO;
+        let mut self_traded = false;
+        while !taker.is_filled() && !self_traded {
+            let mut best = match book.get_best_if_match(AskOrBid::Bid, &price) {
+                Some(best) => best,
+                None => break,
+            };
+            let page = best.get_mut();
+            let (mut traded, decremented, stop) =
+                take(page, &mut taker, stp, algorithm, base_scale);
+            if page.is_empty() {
+                best.remove();
+            }
+            traded.iter_mut().for_each(|m| {
+                if m.is_removed() {
+                    book.forget(m.order_id, &m.user_id);
+                }
+                if !m.filled.is_zero() {
+                    m.price = price;
+                }
+            });
+            makers.append(&mut traded);
+            canceled += decremented;
+            self_traded = stop;
+        }
+        if makers.is_empty() && !self_traded {
+            break;
+        }
+        let state = if self_traded {
+            book.remove(bid.id);
+            State::ConditionalCanceled
+        } else if taker.is_filled() {
+            book.remove(bid.id);
+            State::Filled
+        } else {
+            book.reduce_order(bid.id, taker.unfilled);
+            if makers.iter().all(|m| m.filled.is_zero()) {
+                State::Submitted
+            } else {
+                State::PartialFilled
+            }
+        };
+        if makers.iter().any(|m| !m.filled.is_zero()) {
+            book.last_price.replace(price);
+        }
+        matches.push(Match {
+            maker: makers,
+            taker: Taker::taker(taker, AskOrBid::Bid, state).with_canceled(canceled),
+        });
+    }
+    matches
+}
+
+/// an iceberg order matches as a whole, only `display` of the rest is visible once it's placed
+pub fn execute_iceberg(
+    book: &mut OrderBook,
+    user_id: UserId,
+    order_id: u64,
+    price: Price,
+    amount: Amount,
+    display: Amount,
+    ask_or_bid: AskOrBid,
+    time_in_force: TimeInForce,
+    stp: SelfTradePrevention,
+) -> Match {
+    let mr = execute_limit(
+        book,
+        user_id,
+        order_id,
+        price,
+        amount,
+        ask_or_bid,
+        time_in_force,
+        stp,
+    );
+    book.hide(order_id, display);
+    mr
 }
 
 #[cfg(test)]
@@ -282,6 +1213,8 @@ mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         assert_eq!(State::Submitted, mr.taker.state);
         assert!(mr.maker.is_empty());
@@ -334,6 +1267,8 @@ mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         assert_eq!(State::Submitted, mr.taker.state);
         assert!(mr.maker.is_empty());
@@ -347,6 +1282,8 @@ mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         assert_eq!(State::Submitted, mr.taker.state);
         assert!(mr.maker.is_empty());
@@ -359,6 +1296,8 @@ mod test {
             price,
             amount,
             AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         assert_eq!(State::Filled, mr.taker.state);
         assert!(!mr.maker.is_empty());
@@ -401,6 +1340,8 @@ mod test {
             price,
             amount,
             AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         assert_eq!(State::Submitted, mr.taker.state);
         assert!(book.get_best_if_match(AskOrBid::Bid, &dec!(0.11)).is_none());
@@ -492,6 +1433,8 @@ mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         let mr = execute_limit(
             &mut book,
@@ -500,6 +1443,8 @@ mod test {
             price,
             amount,
             AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         assert_eq!(mr.taker.state, State::ConditionalCanceled);
         assert!(mr.maker.is_empty());
@@ -535,6 +1480,8 @@ mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         execute_limit(
             &mut book,
@@ -543,6 +1490,8 @@ mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         let mr = execute_limit(
             &mut book,
@@ -551,6 +1500,8 @@ mod test {
             price,
             amount * dec!(2),
             AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         assert_eq!(mr.taker.state, State::ConditionalCanceled);
         assert_eq!(mr.taker.unfilled, amount);
@@ -589,9 +1540,850 @@ mod test {
             price,
             amount,
             AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
         );
         assert!(book.find_order(1001).is_some());
         assert_eq!(State::Submitted, mr.taker.state);
         assert!(mr.maker.is_empty());
     }
+
+    #[test]
+    pub fn test_market_order() {
+        let base_scale = 5;
+        let quote_scale = 1;
+        let taker_fee = dec!(0.001);
+        let maker_fee = dec!(0.001);
+        let min_amount = dec!(1);
+        let min_vol = dec!(1);
+        let mut book = OrderBook::new(
+            base_scale,
+            quote_scale,
+            taker_fee,
+            maker_fee,
+            min_amount,
+            min_vol,
+            true,
+            true,
+        );
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(2),
+            1001,
+            dec!(10),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(2),
+            1002,
+            dec!(11),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        // spend 15 quote, 10 for 1 at 10 and 4.99994 for 0.45454 at 11
+        let mr = execute_market(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1003,
+            dec!(15),
+            AskOrBid::Bid,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::Filled);
+        assert_eq!(mr.taker.order_type, OrderType::Market);
+        assert_eq!(mr.taker.unfilled, dec!(0.00006));
+        assert_eq!(mr.maker.len(), 2);
+        assert_eq!(
+            Maker::maker_filled(UserId::from_low_u64_be(2), 1001, dec!(10), dec!(1)),
+            mr.maker[0]
+        );
+        assert_eq!(
+            Maker::maker_so_far(UserId::from_low_u64_be(2), 1002, dec!(11), dec!(0.45454)),
+            mr.maker[1]
+        );
+        assert!(book.find_order(1003).is_none());
+        assert_eq!(book.find_order(1002).unwrap().unfilled, dec!(0.54546));
+
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(2),
+            1004,
+            dec!(9),
+            dec!(1),
+            AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        // the book runs out, the rest is canceled rather than placed
+        let mr = execute_market(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1005,
+            dec!(3),
+            AskOrBid::Ask,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::ConditionalCanceled);
+        assert_eq!(mr.taker.unfilled, dec!(2));
+        assert_eq!(
+            vec![Maker::maker_filled(UserId::from_low_u64_be(2), 1004, dec!(9), dec!(1))],
+            mr.maker
+        );
+        assert!(book.bids.is_empty());
+        assert!(book.find_order(1005).is_none());
+    }
+
+    #[test]
+    pub fn test_time_in_force() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            dec!(0.001),
+            dec!(0.001),
+            dec!(1),
+            dec!(1),
+            true,
+            true,
+        );
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(2),
+            1001,
+            dec!(10),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(2),
+            1002,
+            dec!(11),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        // post-only crossing the best ask is rejected without touching the book
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1003,
+            dec!(10),
+            dec!(1),
+            AskOrBid::Bid,
+            TimeInForce::PostOnly,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::PostOnlyRejected);
+        assert!(mr.maker.is_empty());
+        assert!(book.find_order(1003).is_none());
+        assert_eq!(book.post_only_price(AskOrBid::Bid, dec!(10.5)), dec!(9.9));
+        assert_eq!(book.post_only_price(AskOrBid::Bid, dec!(9.5)), dec!(9.5));
+
+        // fill-or-kill can't be filled entirely at 10
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1004,
+            dec!(10),
+            dec!(1.5),
+            AskOrBid::Bid,
+            TimeInForce::FillOrKill,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::FokKilled);
+        assert!(mr.maker.is_empty());
+        assert_eq!(book.find_order(1001).unwrap().unfilled, dec!(1));
+
+        // immediate-or-cancel takes what it can and drops the rest
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1005,
+            dec!(10),
+            dec!(1.5),
+            AskOrBid::Bid,
+            TimeInForce::ImmediateOrCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::IocCanceled);
+        assert_eq!(mr.taker.unfilled, dec!(0.5));
+        assert_eq!(
+            vec![Maker::maker_filled(UserId::from_low_u64_be(2), 1001, dec!(10), dec!(1))],
+            mr.maker
+        );
+        assert!(book.find_order(1005).is_none());
+
+        // fill-or-kill fully filled across levels
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1006,
+            dec!(11),
+            dec!(1),
+            AskOrBid::Bid,
+            TimeInForce::FillOrKill,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::Filled);
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    pub fn test_replace() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            dec!(0.001),
+            dec!(0.001),
+            dec!(1),
+            dec!(1),
+            true,
+            true,
+        );
+        for (user, id) in [(1, 1001), (3, 1002)] {
+            execute_limit(
+                &mut book,
+                UserId::from_low_u64_be(user),
+                id,
+                dec!(10),
+                dec!(5),
+                AskOrBid::Bid,
+                TimeInForce::GoodTillCancel,
+                SelfTradePrevention::CancelNewest,
+            );
+        }
+        // decreasing keeps the queue position
+        let mr = replace(&mut book, 1001, dec!(10), dec!(2)).unwrap();
+        assert_eq!(mr.taker.state, State::Replaced);
+        assert_eq!(mr.taker.unfilled, dec!(2));
+        let page = book.bids.get(&dec!(10)).unwrap();
+        assert_eq!(page.amount, dec!(7));
+        assert_eq!(page.orders.front().unwrap().0, &1001);
+
+        // increasing goes to the back of the queue
+        let mr = replace(&mut book, 1001, dec!(10), dec!(3)).unwrap();
+        assert_eq!(mr.taker.state, State::Replaced);
+        let page = book.bids.get(&dec!(10)).unwrap();
+        assert_eq!(page.amount, dec!(8));
+        assert_eq!(page.orders.front().unwrap().0, &1002);
+
+        // a new price crossing the book is matched right away
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(2),
+            1003,
+            dec!(11),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        let mr = replace(&mut book, 1001, dec!(11), dec!(3)).unwrap();
+        assert_eq!(mr.taker.state, State::PartialFilled);
+        assert_eq!(
+            vec![Maker::maker_filled(UserId::from_low_u64_be(2), 1003, dec!(11), dec!(1))],
+            mr.maker
+        );
+        assert_eq!(book.find_order(1001).unwrap().unfilled, dec!(2));
+        assert!(book.bids.get(&dec!(10)).unwrap().orders.get(&1001).is_none());
+        assert!(replace(&mut book, 1003, dec!(11), dec!(1)).is_none());
+    }
+
+    #[test]
+    pub fn test_self_trade_prevention_modes() {
+        let setup = || {
+            let mut book = OrderBook::new(
+                5,
+                1,
+                dec!(0.001),
+                dec!(0.001),
+                dec!(1),
+                dec!(1),
+                true,
+                true,
+            );
+            // user 1 rests 2 at the front, user 2 rests 3 behind it
+            for (user, id, amount) in [(1, 1001, dec!(2)), (2, 1002, dec!(3))] {
+                execute_limit(
+                    &mut book,
+                    UserId::from_low_u64_be(user),
+                    id,
+                    dec!(10),
+                    amount,
+                    AskOrBid::Ask,
+                    TimeInForce::GoodTillCancel,
+                    SelfTradePrevention::CancelNewest,
+                );
+            }
+            book
+        };
+        let bid = |book: &mut OrderBook, amount, stp| {
+            execute_limit(
+                book,
+                UserId::from_low_u64_be(1),
+                1003,
+                dec!(10),
+                amount,
+                AskOrBid::Bid,
+                TimeInForce::GoodTillCancel,
+                stp,
+            )
+        };
+
+        let mut book = setup();
+        let mr = bid(&mut book, dec!(3), SelfTradePrevention::CancelOldest);
+        assert_eq!(mr.taker.state, State::Filled);
+        assert_eq!(
+            vec![
+                Maker::maker_canceled(UserId::from_low_u64_be(1), 1001, dec!(10), dec!(2), true),
+                Maker::maker_filled(UserId::from_low_u64_be(2), 1002, dec!(10), dec!(3)),
+            ],
+            mr.maker
+        );
+        assert!(book.find_order(1001).is_none());
+        assert!(book.asks.is_empty());
+
+        let mut book = setup();
+        let mr = bid(&mut book, dec!(4), SelfTradePrevention::CancelBoth);
+        assert_eq!(mr.taker.state, State::ConditionalCanceled);
+        assert_eq!(mr.taker.unfilled, dec!(4));
+        assert_eq!(mr.maker.len(), 1);
+        assert_eq!(mr.maker[0].state, State::ConditionalCanceled);
+        assert!(book.find_order(1001).is_none());
+        assert_eq!(book.asks.get(&dec!(10)).unwrap().amount, dec!(3));
+
+        // the taker is larger, the maker is canceled and the taker keeps matching
+        let mut book = setup();
+        let mr = bid(&mut book, dec!(4), SelfTradePrevention::DecrementAndCancel);
+        assert_eq!(mr.taker.state, State::Filled);
+        assert_eq!(mr.taker.canceled, dec!(2));
+        assert_eq!(mr.maker[0].state, State::ConditionalCanceled);
+        assert_eq!(mr.maker[1].filled, dec!(2));
+        assert_eq!(book.find_order(1002).unwrap().unfilled, dec!(1));
+
+        // the maker is larger, the maker is decremented and the taker is canceled
+        let mut book = setup();
+        let mr = bid(&mut book, dec!(1.5), SelfTradePrevention::DecrementAndCancel);
+        assert_eq!(mr.taker.state, State::ConditionalCanceled);
+        assert_eq!(mr.taker.unfilled, dec!(1.5));
+        assert_eq!(
+            vec![Maker::maker_canceled(
+                UserId::from_low_u64_be(1),
+                1001,
+                dec!(10),
+                dec!(1.5),
+                false
+            )],
+            mr.maker
+        );
+        assert_eq!(mr.maker[0].state, State::Decremented);
+        assert_eq!(book.find_order(1001).unwrap().unfilled, dec!(0.5));
+        assert_eq!(book.asks.get(&dec!(10)).unwrap().amount, dec!(3.5));
+    }
+
+    #[test]
+    pub fn test_stop_orders() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            dec!(0.001),
+            dec!(0.001),
+            dec!(1),
+            dec!(1),
+            true,
+            true,
+        );
+        for (id, price) in [(1001, dec!(9)), (1002, dec!(8))] {
+            execute_limit(
+                &mut book,
+                UserId::from_low_u64_be(2),
+                id,
+                price,
+                dec!(1),
+                AskOrBid::Bid,
+                TimeInForce::GoodTillCancel,
+                SelfTradePrevention::CancelNewest,
+            );
+        }
+        // stop-loss ask at 9 turns into a market ask
+        let mr = place_stop(
+            &mut book,
+            StopOrder {
+                id: 1003,
+                user: UserId::from_low_u64_be(1),
+                ask_or_bid: AskOrBid::Ask,
+                kind: StopKind::StopLoss,
+                trigger: dec!(9),
+                price: None,
+                amount: dec!(1),
+                oco: None,
+                trail: None,
+            },
+        );
+        assert_eq!(mr.taker.state, State::Triggering);
+        // stop-loss ask at 8 with limit 8
+        place_stop(
+            &mut book,
+            StopOrder {
+                id: 1004,
+                user: UserId::from_low_u64_be(1),
+                ask_or_bid: AskOrBid::Ask,
+                kind: StopKind::StopLoss,
+                trigger: dec!(8),
+                price: Some(dec!(8)),
+                amount: dec!(2),
+                oco: None,
+                trail: None,
+            },
+        );
+        // take-profit ask at 12 won't be triggered
+        place_stop(
+            &mut book,
+            StopOrder {
+                id: 1005,
+                user: UserId::from_low_u64_be(1),
+                ask_or_bid: AskOrBid::Ask,
+                kind: StopKind::TakeProfit,
+                trigger: dec!(12),
+                price: None,
+                amount: dec!(1),
+                oco: None,
+                trail: None,
+            },
+        );
+        assert!(trigger_stops(&mut book).is_empty());
+        assert!(book.find_order(1003).is_none());
+
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(3),
+            1006,
+            dec!(10),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::Submitted);
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(3),
+            1007,
+            dec!(9),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::Filled);
+        assert_eq!(book.last_price, Some(dec!(9)));
+        // 1003 trades at 8 which triggers 1004 in the same round
+        let triggered = trigger_stops(&mut book);
+        assert_eq!(triggered.len(), 2);
+        assert_eq!(triggered[0].taker.order_id, 1003);
+        assert_eq!(triggered[0].taker.state, State::Filled);
+        assert_eq!(triggered[1].taker.order_id, 1004);
+        assert_eq!(triggered[1].taker.state, State::Submitted);
+        assert_eq!(book.find_order(1004).unwrap().unfilled, dec!(2));
+        assert_eq!(book.last_price, Some(dec!(8)));
+
+        let mr = cancel(&mut book, 1005).unwrap();
+        assert_eq!(mr.taker.state, State::Canceled);
+        assert_eq!(mr.taker.order_type, OrderType::Market);
+        assert!(book.stops.is_empty());
+    }
+
+    #[test]
+    pub fn test_iceberg() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            dec!(0.001),
+            dec!(0.001),
+            dec!(1),
+            dec!(1),
+            true,
+            true,
+        );
+        let mr = execute_iceberg(
+            &mut book,
+            UserId::from_low_u64_be(2),
+            1001,
+            dec!(10),
+            dec!(10),
+            dec!(2),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::Submitted);
+        assert_eq!(mr.taker.unfilled, dec!(10));
+        execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(3),
+            1002,
+            dec!(10),
+            dec!(1),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(book.as_depth(32, (1, 0)).asks, vec![(dec!(10.0), dec!(3.00000), dec!(3))]);
+
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1003,
+            dec!(10),
+            dec!(3),
+            AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::Filled);
+        // the refilled iceberg is queued behind 1002
+        assert_eq!(
+            vec![
+                Maker::maker_so_far(UserId::from_low_u64_be(2), 1001, dec!(10), dec!(2)),
+                Maker::maker_filled(UserId::from_low_u64_be(3), 1002, dec!(10), dec!(1)),
+            ],
+            mr.maker
+        );
+        let iceberg = book.find_order(1001).unwrap();
+        assert_eq!(iceberg.unfilled, dec!(2));
+        assert_eq!(iceberg.hidden, dec!(6));
+        assert_eq!(book.asks.get(&dec!(10)).unwrap().amount, dec!(2));
+
+        // the hidden reserve matches as well
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(1),
+            1004,
+            dec!(10),
+            dec!(5),
+            AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::Filled);
+        assert_eq!(mr.maker.len(), 3);
+        assert_eq!(book.find_order(1001).unwrap().total_unfilled(), dec!(3));
+        assert_eq!(book.asks.get(&dec!(10)).unwrap().amount, dec!(1));
+
+        let mr = cancel(&mut book, 1001).unwrap();
+        assert_eq!(mr.taker.unfilled, dec!(3));
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    pub fn test_expire() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            dec!(0.001),
+            dec!(0.001),
+            dec!(1),
+            dec!(1),
+            true,
+            true,
+        );
+        for (id, expire_at) in [(1001, 100), (1002, 200), (1003, 100)] {
+            execute_limit(
+                &mut book,
+                UserId::from_low_u64_be(1),
+                id,
+                dec!(10),
+                dec!(1),
+                AskOrBid::Bid,
+                TimeInForce::GoodTillCancel,
+                SelfTradePrevention::CancelNewest,
+            );
+            book.set_expiry(id, expire_at);
+        }
+        cancel(&mut book, 1003);
+        assert!(expire(&mut book, 99).is_empty());
+        let expired = expire(&mut book, 150);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(
+            Taker::taker(
+                Order::new(1001, UserId::from_low_u64_be(1), dec!(10), dec!(1)),
+                AskOrBid::Bid,
+                State::Expired
+            ),
+            expired[0].taker
+        );
+        assert!(book.find_order(1001).is_none());
+        assert!(book.find_order(1002).is_some());
+        assert_eq!(book.expiries.len(), 1);
+    }
+
+    #[test]
+    pub fn test_auction() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            dec!(0.001),
+            dec!(0.001),
+            dec!(1),
+            dec!(1),
+            true,
+            true,
+        );
+        book.auction = true;
+        let orders = [
+            (1, 1, dec!(10), dec!(3), AskOrBid::Ask),
+            (2, 1, dec!(11), dec!(2), AskOrBid::Ask),
+            (3, 1, dec!(13), dec!(5), AskOrBid::Ask),
+            (4, 2, dec!(12), dec!(4), AskOrBid::Bid),
+            (5, 2, dec!(11), dec!(2), AskOrBid::Bid),
+            (6, 2, dec!(9), dec!(1), AskOrBid::Bid),
+        ];
+        for (id, user, price, amount, ask_or_bid) in orders {
+            let mr = execute_limit(
+                &mut book,
+                UserId::from_low_u64_be(user),
+                id,
+                price,
+                amount,
+                ask_or_bid,
+                TimeInForce::GoodTillCancel,
+                SelfTradePrevention::CancelNewest,
+            );
+            assert!(mr.maker.is_empty());
+            assert_eq!(mr.taker.state, State::Submitted);
+        }
+        assert_eq!(book.get_best_bid(), Some(dec!(12)));
+        assert_eq!(book.get_best_ask(), Some(dec!(10)));
+        assert_eq!(auction_price(&book), Some((dec!(11), dec!(5))));
+
+        let matches = uncross(&mut book);
+        assert!(!book.auction);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].taker.order_id, 4);
+        assert_eq!(matches[0].taker.state, State::Filled);
+        assert_eq!(
+            matches[0].maker,
+            vec![
+                Maker::maker_filled(UserId::from_low_u64_be(1), 1, dec!(11), dec!(3)),
+                Maker::maker_so_far(UserId::from_low_u64_be(1), 2, dec!(11), dec!(1)),
+            ]
+        );
+        assert_eq!(matches[1].taker.order_id, 5);
+        assert_eq!(matches[1].taker.state, State::PartialFilled);
+        assert_eq!(matches[1].taker.unfilled, dec!(1));
+        assert_eq!(
+            matches[1].maker,
+            vec![Maker::maker_filled(UserId::from_low_u64_be(1), 2, dec!(11), dec!(1))]
+        );
+        assert_eq!(book.last_price, Some(dec!(11)));
+        assert_eq!(book.get_best_bid(), Some(dec!(11)));
+        assert_eq!(book.get_best_ask(), Some(dec!(13)));
+        assert_eq!(book.find_order(5).unwrap().unfilled, dec!(1));
+        assert!(book.find_order(4).is_none());
+    }
+
+    #[test]
+    pub fn test_pro_rata() {
+        let mut book = OrderBook::new(
+            2,
+            1,
+            dec!(0.001),
+            dec!(0.001),
+            dec!(0.01),
+            dec!(0.01),
+            true,
+            true,
+        );
+        book.matching_algorithm = MatchingAlgorithm::ProRata;
+        for (id, amount) in [(1, dec!(4)), (2, dec!(3)), (3, dec!(6)), (4, dec!(1))] {
+            execute_limit(
+                &mut book,
+                UserId::from_low_u64_be(id),
+                id,
+                dec!(10),
+                amount,
+                AskOrBid::Ask,
+                TimeInForce::GoodTillCancel,
+                SelfTradePrevention::CancelNewest,
+            );
+        }
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(9),
+            5,
+            dec!(10),
+            dec!(7.05),
+            AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(mr.taker.state, State::Filled);
+        // 3.05 is allocated by 3:6:1 after the top order, the remainder 0.01 goes to the oldest
+        assert_eq!(
+            mr.maker,
+            vec![
+                Maker::maker_filled(UserId::from_low_u64_be(1), 1, dec!(10), dec!(4)),
+                Maker::maker_so_far(UserId::from_low_u64_be(2), 2, dec!(10), dec!(0.92)),
+                Maker::maker_so_far(UserId::from_low_u64_be(3), 3, dec!(10), dec!(1.83)),
+                Maker::maker_so_far(UserId::from_low_u64_be(4), 4, dec!(10), dec!(0.30)),
+            ]
+        );
+        assert_eq!(book.asks.get(&dec!(10)).unwrap().amount, dec!(6.95));
+
+        let mr = execute_limit(
+            &mut book,
+            UserId::from_low_u64_be(3),
+            6,
+            dec!(10),
+            dec!(1),
+            AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelOldest,
+        );
+        assert_eq!(mr.taker.state, State::Filled);
+        assert_eq!(
+            mr.maker,
+            vec![
+                Maker::maker_canceled(UserId::from_low_u64_be(3), 3, dec!(10), dec!(4.17), true),
+                Maker::maker_so_far(UserId::from_low_u64_be(2), 2, dec!(10), dec!(1)),
+            ]
+        );
+        assert!(book.find_order(3).is_none());
+        assert_eq!(book.asks.get(&dec!(10)).unwrap().amount, dec!(1.78));
+    }
+
+    #[test]
+    pub fn test_oco() {
+        let mut book = OrderBook::new(
+            5,
+            1,
+            dec!(0.001),
+            dec!(0.001),
+            dec!(0.1),
+            dec!(0.1),
+            true,
+            true,
+        );
+        let user = |id| UserId::from_low_u64_be(id);
+        // take-profit at 12 and stop-loss at 9 of the same 2
+        let stop = StopOrder {
+            id: 2,
+            user: user(1),
+            ask_or_bid: AskOrBid::Ask,
+            kind: StopKind::StopLoss,
+            trigger: dec!(9),
+            price: None,
+            amount: dec!(2),
+            oco: Some(1),
+            trail: None,
+        };
+        let matches = execute_oco(
+            &mut book,
+            user(1),
+            1,
+            dec!(12),
+            dec!(2),
+            AskOrBid::Ask,
+            stop,
+            SelfTradePrevention::CancelNewest,
+        );
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].taker.state, State::Triggering);
+        assert_eq!(matches[1].taker.state, State::Submitted);
+
+        let mr = execute_limit(
+            &mut book,
+            user(2),
+            3,
+            dec!(12),
+            dec!(1),
+            AskOrBid::Bid,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        let settled = settle_oco(&mut book, &mr);
+        assert_eq!(settled.len(), 1);
+        assert_eq!(settled[0].taker.order_id, 2);
+        assert_eq!(settled[0].taker.state, State::OcoReduced);
+        assert_eq!(settled[0].taker.unfilled, dec!(1));
+        assert_eq!(settled[0].taker.canceled, dec!(0));
+
+        // a trade at 9 triggers the stop leg which takes over the limit leg
+        let orders = [(4, 3, dec!(2), AskOrBid::Bid), (5, 4, dec!(1), AskOrBid::Ask)];
+        for (id, u, amount, side) in orders {
+            execute_limit(
+                &mut book,
+                user(u),
+                id,
+                dec!(9),
+                amount,
+                side,
+                TimeInForce::GoodTillCancel,
+                SelfTradePrevention::CancelNewest,
+            );
+        }
+        let triggered = trigger_stops(&mut book);
+        assert_eq!(triggered.len(), 2);
+        assert_eq!(triggered[0].taker.order_id, 1);
+        assert_eq!(triggered[0].taker.state, State::OcoCanceled);
+        assert_eq!(triggered[0].taker.canceled, dec!(0));
+        assert_eq!(triggered[1].taker.order_id, 2);
+        assert_eq!(triggered[1].taker.state, State::Filled);
+        assert!(book.find_order(1).is_none());
+        assert!(book.oco_groups.is_empty());
+
+        // the bid stop leg at 12 reserves 4 per unit beyond the limit leg at 8
+        let stop = StopOrder {
+            id: 7,
+            user: user(1),
+            ask_or_bid: AskOrBid::Bid,
+            kind: StopKind::StopLoss,
+            trigger: dec!(11),
+            price: Some(dec!(12)),
+            amount: dec!(2),
+            oco: Some(6),
+            trail: None,
+        };
+        execute_oco(
+            &mut book,
+            user(1),
+            6,
+            dec!(8),
+            dec!(2),
+            AskOrBid::Bid,
+            stop,
+            SelfTradePrevention::CancelNewest,
+        );
+        let mr = execute_limit(
+            &mut book,
+            user(2),
+            8,
+            dec!(8),
+            dec!(0.5),
+            AskOrBid::Ask,
+            TimeInForce::GoodTillCancel,
+            SelfTradePrevention::CancelNewest,
+        );
+        let settled = settle_oco(&mut book, &mr);
+        assert_eq!(settled[0].taker.state, State::OcoReduced);
+        assert_eq!(settled[0].taker.canceled, dec!(2));
+        // canceling the stop leg cancels the group
+        let mr = cancel(&mut book, 7).unwrap();
+        assert_eq!(mr.taker.order_id, 6);
+        assert_eq!(mr.taker.state, State::Canceled);
+        let settled = settle_oco(&mut book, &mr);
+        assert_eq!(settled[0].taker.order_id, 7);
+        assert_eq!(settled[0].taker.state, State::OcoCanceled);
+        assert_eq!(settled[0].taker.canceled, dec!(6));
+        assert!(book.stops.is_empty());
+    }
 }
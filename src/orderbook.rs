@@ -12,14 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::core::{Amount, Fee, OrderId, Price, Symbol, UserId};
+use crate::core::{Amount, ClientOrderId, Fee, OrderId, Price, Symbol, Timestamp, UserId, Vol};
 use linked_hash_map::LinkedHashMap;
 use rust_decimal::{Decimal, prelude::Zero};
 use serde::{Deserialize, Serialize};
-use std::collections::{btree_map::OccupiedEntry, {BTreeMap, HashMap}};
+use std::collections::{btree_map::OccupiedEntry, {BTreeMap, BTreeSet, HashMap, HashSet, VecDeque}};
 
 const DEFAULT_PAGE_SIZE: usize = 256;
 
+/// the client order ids are reserved for this while the orders aren't resting
+pub const CLIENT_ORDER_WINDOW: Timestamp = 24 * 60 * 60;
+
 #[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
 pub enum AskOrBid {
     Ask,
@@ -40,13 +43,101 @@ impl std::convert::TryFrom<u32> for AskOrBid {
 
     fn try_from(x: u32) -> anyhow::Result<Self> {
         match x {
-            crate::sequence::ASK_LIMIT => Ok(AskOrBid::Ask),
-            crate::sequence::BID_LIMIT => Ok(AskOrBid::Bid),
+            crate::sequence::ASK_LIMIT
+            | crate::sequence::ASK_MARKET
+            | crate::sequence::ASK_STOP
+            | crate::sequence::ASK_OCO
+            | crate::sequence::ASK_PEG => Ok(AskOrBid::Ask),
+            crate::sequence::BID_LIMIT
+            | crate::sequence::BID_MARKET
+            | crate::sequence::BID_STOP
+            | crate::sequence::BID_OCO
+            | crate::sequence::BID_PEG => Ok(AskOrBid::Bid),
             _ => Err(anyhow::anyhow!("")),
         }
     }
 }
 
+/// what to do when the taker meets a resting order of the same user
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum SelfTradePrevention {
+    /// cancel the taker and stop matching
+    CancelNewest,
+    /// cancel the resting maker and keep matching
+    CancelOldest,
+    /// cancel both the maker and the taker
+    CancelBoth,
+    /// decrease both by the smaller unfilled, the smaller one is canceled
+    DecrementAndCancel,
+}
+
+impl Default for SelfTradePrevention {
+    fn default() -> Self {
+        Self::CancelNewest
+    }
+}
+
+impl Into<u32> for SelfTradePrevention {
+    fn into(self) -> u32 {
+        match self {
+            SelfTradePrevention::CancelNewest => 0,
+            SelfTradePrevention::CancelOldest => 1,
+            SelfTradePrevention::CancelBoth => 2,
+            SelfTradePrevention::DecrementAndCancel => 3,
+        }
+    }
+}
+
+impl std::convert::TryFrom<u32> for SelfTradePrevention {
+    type Error = anyhow::Error;
+
+    fn try_from(x: u32) -> anyhow::Result<Self> {
+        match x {
+            0 => Ok(SelfTradePrevention::CancelNewest),
+            1 => Ok(SelfTradePrevention::CancelOldest),
+            2 => Ok(SelfTradePrevention::CancelBoth),
+            3 => Ok(SelfTradePrevention::DecrementAndCancel),
+            _ => Err(anyhow::anyhow!("invalid self-trade prevention")),
+        }
+    }
+}
+
+/// how a taker is allocated among the orders of a price level
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum MatchingAlgorithm {
+    /// price-time priority
+    Fifo,
+    /// the oldest order is filled first, the rest in proportion to their unfilled
+    ProRata,
+}
+
+impl Default for MatchingAlgorithm {
+    fn default() -> Self {
+        Self::Fifo
+    }
+}
+
+impl Into<u32> for MatchingAlgorithm {
+    fn into(self) -> u32 {
+        match self {
+            MatchingAlgorithm::Fifo => 0,
+            MatchingAlgorithm::ProRata => 1,
+        }
+    }
+}
+
+impl std::convert::TryFrom<u32> for MatchingAlgorithm {
+    type Error = anyhow::Error;
+
+    fn try_from(x: u32) -> anyhow::Result<Self> {
+        match x {
+            0 => Ok(MatchingAlgorithm::Fifo),
+            1 => Ok(MatchingAlgorithm::ProRata),
+            _ => Err(anyhow::anyhow!("invalid matching algorithm")),
+        }
+    }
+}
+
 impl Into<u8> for AskOrBid {
     fn into(self) -> u8 {
         match self {
@@ -72,7 +163,12 @@ pub struct Order {
     pub id: OrderId,
     pub user: UserId,
     pub price: Price,
+    /// the visible part of an iceberg order
     pub unfilled: Amount,
+    /// the hidden reserve of an iceberg order
+    pub hidden: Amount,
+    /// the refill size of an iceberg order
+    pub display: Amount,
 }
 
 impl Order {
@@ -82,6 +178,8 @@ impl Order {
             user,
             price,
             unfilled,
+            hidden: Amount::ZERO,
+            display: Amount::ZERO,
         }
     }
 
@@ -92,6 +190,27 @@ impl Order {
     pub fn is_filled(&self) -> bool {
         self.unfilled == Amount::ZERO
     }
+
+    pub fn total_unfilled(&self) -> Amount {
+        self.unfilled + self.hidden
+    }
+
+    /// move the next slice from the hidden reserve to the visible
+    pub fn refill(&mut self) -> Amount {
+        let visible = self.display.min(self.hidden);
+        self.hidden -= visible;
+        self.unfilled += visible;
+        visible
+    }
+
+    /// decrease from the hidden reserve first, returns the decreased visible part
+    pub fn reduce(&mut self, delta: Amount) -> Amount {
+        let from_hidden = delta.min(self.hidden);
+        self.hidden -= from_hidden;
+        let from_visible = delta - from_hidden;
+        self.unfilled -= from_visible;
+        from_visible
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
@@ -146,22 +265,415 @@ impl OrderPage {
 
 pub type Tape = BTreeMap<Price, OrderPage>;
 
-pub type Index = HashMap<OrderId, Price>;
+/// the page of a resting order, i.e. the side and the price level
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Slot {
+    pub ask_or_bid: AskOrBid,
+    pub price: Price,
+}
+
+pub type Index = HashMap<OrderId, Slot>;
+
+/// stop orders to their trigger prices
+pub type TriggerIndex = HashMap<OrderId, Price>;
+
+pub type UserIndex = HashMap<UserId, BTreeSet<OrderId>>;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum StopKind {
+    StopLoss,
+    TakeProfit,
+}
+
+impl Into<u32> for StopKind {
+    fn into(self) -> u32 {
+        match self {
+            StopKind::StopLoss => 0,
+            StopKind::TakeProfit => 1,
+        }
+    }
+}
+
+impl std::convert::TryFrom<u32> for StopKind {
+    type Error = anyhow::Error;
+
+    fn try_from(x: u32) -> anyhow::Result<Self> {
+        match x {
+            0 => Ok(StopKind::StopLoss),
+            1 => Ok(StopKind::TakeProfit),
+            _ => Err(anyhow::anyhow!("invalid stop kind")),
+        }
+    }
+}
+
+/// how far the trigger of a trailing stop keeps behind the best price since placement
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum TrailOffset {
+    Amount(Price),
+    /// a ratio of the best price
+    Ratio(Decimal),
+}
+
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Trail {
+    pub offset: TrailOffset,
+    /// the highest last price of an ask or the lowest of a bid since placement
+    pub extreme: Price,
+}
+
+impl Trail {
+    /// trailing stops are stop-loss, i.e. an ask triggers below and a bid above the extreme
+    pub fn trigger(&self, ask_or_bid: AskOrBid) -> Price {
+        let distance = match self.offset {
+            TrailOffset::Amount(amount) => amount,
+            TrailOffset::Ratio(ratio) => self.extreme * ratio,
+        };
+        match ask_or_bid {
+            AskOrBid::Ask => self.extreme - distance,
+            AskOrBid::Bid => self.extreme + distance,
+        }
+    }
+
+    /// move the extreme with the last price, return true if it moved
+    pub fn follow(&mut self, last_price: Price, ask_or_bid: AskOrBid) -> bool {
+        let extreme = match ask_or_bid {
+            AskOrBid::Ask => self.extreme.max(last_price),
+            AskOrBid::Bid => self.extreme.min(last_price),
+        };
+        let moved = extreme != self.extreme;
+        self.extreme = extreme;
+        moved
+    }
+}
+
+/// the price a pegged order follows, the pegged orders themselves are excluded
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum PegReference {
+    /// the best price of the same side
+    Primary,
+    /// the best price of the opposite side
+    Market,
+    /// the middle of the best bid and the best ask
+    Mid,
+}
+
+impl Default for PegReference {
+    fn default() -> Self {
+        Self::Primary
+    }
+}
+
+impl Into<u32> for PegReference {
+    fn into(self) -> u32 {
+        match self {
+            PegReference::Primary => 0,
+            PegReference::Market => 1,
+            PegReference::Mid => 2,
+        }
+    }
+}
+
+impl std::convert::TryFrom<u32> for PegReference {
+    type Error = anyhow::Error;
+
+    fn try_from(x: u32) -> anyhow::Result<Self> {
+        match x {
+            0 => Ok(PegReference::Primary),
+            1 => Ok(PegReference::Market),
+            2 => Ok(PegReference::Mid),
+            _ => Err(anyhow::anyhow!("invalid peg reference")),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Peg {
+    pub reference: PegReference,
+    /// added to the reference price
+    pub offset: Price,
+    /// the highest price of a bid or the lowest price of an ask
+    pub cap: Option<Price>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone)]
+pub struct StopOrder {
+    pub id: OrderId,
+    pub user: UserId,
+    pub ask_or_bid: AskOrBid,
+    pub kind: StopKind,
+    pub trigger: Price,
+    /// the limit price once triggered, `None` means a market order
+    pub price: Option<Price>,
+    /// base amount, or quote volume of a stop-market bid
+    pub amount: Amount,
+    /// the limit leg of the same OCO group
+    pub oco: Option<OrderId>,
+    /// the trigger follows the last price if present
+    pub trail: Option<Trail>,
+}
+
+impl StopOrder {
+    /// stop-loss asks and take-profit bids fire when the price falls to the trigger,
+    /// the others fire when the price rises to the trigger
+    pub fn on_falling(&self) -> bool {
+        matches!(
+            (self.ask_or_bid, self.kind),
+            (AskOrBid::Ask, StopKind::StopLoss) | (AskOrBid::Bid, StopKind::TakeProfit)
+        )
+    }
+}
+
+pub type StopPage = LinkedHashMap<OrderId, StopOrder>;
+
+/// pending stop orders keyed by trigger price
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+pub struct StopBook {
+    pub rising: BTreeMap<Price, StopPage>,
+    pub falling: BTreeMap<Price, StopPage>,
+    pub indices: TriggerIndex,
+    pub trailing: BTreeSet<OrderId>,
+}
+
+impl StopBook {
+    pub fn insert(&mut self, stop: StopOrder) {
+        let tape = match stop.on_falling() {
+            true => &mut self.falling,
+            false => &mut self.rising,
+        };
+        self.indices.insert(stop.id, stop.trigger);
+        if stop.trail.is_some() {
+            self.trailing.insert(stop.id);
+        }
+        tape.entry(stop.trigger)
+            .or_insert_with(StopPage::new)
+            .insert(stop.id, stop);
+    }
+
+    pub fn get(&self, order_id: OrderId) -> Option<&StopOrder> {
+        let trigger = self.indices.get(&order_id)?;
+        self.rising
+            .get(trigger)
+            .and_then(|page| page.get(&order_id))
+            .or_else(|| self.falling.get(trigger).and_then(|page| page.get(&order_id)))
+    }
+
+    pub fn get_mut(&mut self, order_id: OrderId) -> Option<&mut StopOrder> {
+        let trigger = self.indices.get(&order_id)?;
+        match self.rising.get_mut(trigger).and_then(|page| page.get_mut(&order_id)) {
+            Some(stop) => Some(stop),
+            None => self.falling.get_mut(trigger).and_then(|page| page.get_mut(&order_id)),
+        }
+    }
+
+    pub fn remove(&mut self, order_id: OrderId) -> Option<StopOrder> {
+        let trigger = self.indices.remove(&order_id)?;
+        self.trailing.remove(&order_id);
+        Self::remove_from(&mut self.rising, order_id, &trigger)
+            .or_else(|| Self::remove_from(&mut self.falling, order_id, &trigger))
+    }
+
+    fn remove_from(
+        tape: &mut BTreeMap<Price, StopPage>,
+        order_id: OrderId,
+        trigger: &Price,
+    ) -> Option<StopOrder> {
+        let page = tape.get_mut(trigger)?;
+        let removed = page.remove(&order_id);
+        if page.is_empty() {
+            tape.remove(trigger);
+        }
+        removed
+    }
+
+    /// the falling side is checked first, the highest trigger fires first when falling and
+    /// the lowest fires first when rising, orders on the same trigger fire in FIFO
+    pub fn pop_triggered(&mut self, last_price: Price) -> Option<StopOrder> {
+        let id = self
+            .falling
+            .range(last_price..)
+            .next_back()
+            .or_else(|| self.rising.range(..=last_price).next())
+            .and_then(|(_, page)| page.keys().next().copied())?;
+        self.remove(id)
+    }
+
+    /// move the triggers of the trailing stops with the last price, a moved stop is queued
+    /// behind the others on its new trigger
+    pub fn trail(&mut self, last_price: Price) {
+        let trailing = self.trailing.iter().copied().collect::<Vec<_>>();
+        for id in trailing {
+            let moved = match self.get_mut(id) {
+                Some(StopOrder { ask_or_bid, trail: Some(trail), .. }) => {
+                    trail.follow(last_price, *ask_or_bid)
+                }
+                _ => false,
+            };
+            if moved {
+                let mut stop = self.remove(id).unwrap();
+                stop.trigger = stop.trail.unwrap().trigger(stop.ask_or_bid);
+                self.insert(stop);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+}
+
+/// halts the orderbook once the trades move the price past `band` within `window` seconds
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct CircuitBreaker {
+    pub band: Decimal,
+    pub window: Timestamp,
+    recent: VecDeque<(Timestamp, Price)>,
+}
+
+impl CircuitBreaker {
+    pub fn new(band: Decimal, window: Timestamp) -> Self {
+        Self {
+            band,
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// return true if the price breaks the band
+    pub fn observe(&mut self, price: Price, time: Timestamp) -> bool {
+        while let Some(&(t, _)) = self.recent.front() {
+            if t + self.window >= time {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        let broken = self
+            .recent
+            .iter()
+            .any(|&(_, p)| (price - p).abs() > p * self.band);
+        if broken {
+            self.recent.clear();
+        } else if self.recent.back().map(|&(_, p)| p) != Some(price) {
+            self.recent.push_back((time, price));
+        }
+        broken
+    }
+}
+
+/// the parts of an orderbook to restore, i.e. the pages crossed or canceled from and the orders
+/// placed in between, the stop orders are saved only if touched
+#[derive(Debug, Clone)]
+pub struct Savepoint {
+    pages: Vec<(AskOrBid, Price, Option<OrderPage>)>,
+    placing: HashSet<OrderId>,
+    stops: Option<(StopBook, HashMap<OrderId, (OrderId, Price)>)>,
+    max_id: OrderId,
+    last_price: Option<Price>,
+}
+
+impl Savepoint {
+    /// the users of the orders saved
+    pub fn users(&self) -> impl Iterator<Item = UserId> + '_ {
+        self.pages
+            .iter()
+            .filter_map(|(_, _, page)| page.as_ref())
+            .flat_map(|page| page.orders.values().map(|o| o.user))
+    }
+}
+
+/// the order placed with a client order id, a retry is answered with it
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ClientOrder {
+    pub symbol: Symbol,
+    pub order_id: OrderId,
+    pub ask_or_bid: AskOrBid,
+    pub price: Price,
+    pub amount: Amount,
+    pub placed_at: Timestamp,
+}
+
+/// the client order ids reserved by each user across the symbols
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+pub struct ClientOrders {
+    pub orders: HashMap<(UserId, ClientOrderId), ClientOrder>,
+    /// the client order ids in the order of reserving
+    queue: VecDeque<(Timestamp, UserId, ClientOrderId)>,
+}
+
+impl ClientOrders {
+    pub fn get(&self, user: &UserId, client_order_id: &str) -> Option<&ClientOrder> {
+        self.orders.get(&(*user, client_order_id.to_string()))
+    }
+
+    pub fn insert(&mut self, user: UserId, client_order_id: ClientOrderId, order: ClientOrder) {
+        self.queue.push_back((order.placed_at, user, client_order_id.clone()));
+        self.orders.insert((user, client_order_id), order);
+    }
+
+    pub fn remove(&mut self, user: &UserId, client_order_id: &str) {
+        if self.orders.remove(&(*user, client_order_id.to_string())).is_some() {
+            self.queue.retain(|(_, u, id)| !(u == user && id == client_order_id));
+        }
+    }
+
+    /// release the client order ids reserved before the window, except the resting ones
+    pub fn prune(&mut self, now: Timestamp, resting: impl Fn(&ClientOrder) -> bool) {
+        while let Some((t, user, client_order_id)) = self.queue.pop_front() {
+            if t + CLIENT_ORDER_WINDOW >= now {
+                self.queue.push_front((t, user, client_order_id));
+                break;
+            }
+            let key = (user, client_order_id);
+            match self.orders.get(&key) {
+                // reserved again after the window, it's queued again as well
+                Some(c) if c.placed_at > t => {}
+                Some(c) if resting(c) => self.queue.push_back((now, key.0, key.1)),
+                _ => {
+                    self.orders.remove(&key);
+                }
+            }
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
 pub struct OrderBook {
     pub asks: Tape,
     pub bids: Tape,
     pub indices: Index,
+    /// the resting orders of each user, rebuilt from the tapes on loading
+    #[serde(skip)]
+    pub user_indices: UserIndex,
     pub base_scale: u32,
     pub quote_scale: u32,
     pub taker_fee: Fee,
     pub maker_fee: Fee,
     pub min_amount: Amount,
     pub min_vol: Amount,
+    pub tick_size: Option<Price>,
+    pub lot_size: Option<Amount>,
+    pub max_amount: Option<Amount>,
+    pub max_vol: Option<Vol>,
+    /// limit orders priced away from the last price by more than this ratio are rejected
+    pub price_band: Option<Decimal>,
+    pub circuit_breaker: Option<CircuitBreaker>,
     pub enable_market_order: bool,
     pub open: bool,
-    max_id: OrderId,
+    /// orders are collected without matching until the book is uncrossed
+    pub auction: bool,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub matching_algorithm: MatchingAlgorithm,
+    pub stops: StopBook,
+    /// the pegged orders, the filled or canceled ones are dropped lazily
+    pub pegs: BTreeMap<OrderId, Peg>,
+    /// the ladders placed by mass quotes, the filled or canceled orders are dropped lazily
+    pub quotes: UserIndex,
+    /// the limit legs of the OCO groups to their stop legs and limit prices
+    pub oco_groups: HashMap<OrderId, (OrderId, Price)>,
+    /// price of the latest trade
+    pub last_price: Option<Price>,
+    /// good-till-time orders, the filled or canceled ones are dropped lazily
+    pub expiries: BTreeSet<(Timestamp, OrderId)>,
+    pub(crate) max_id: OrderId,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -187,14 +699,30 @@ impl OrderBook {
             asks: Tape::new(),
             bids: Tape::new(),
             indices: Index::with_capacity(DEFAULT_PAGE_SIZE),
+            user_indices: UserIndex::new(),
             base_scale,
             quote_scale,
             taker_fee,
             maker_fee,
             min_amount,
             min_vol,
+            tick_size: None,
+            lot_size: None,
+            max_amount: None,
+            max_vol: None,
+            price_band: None,
+            circuit_breaker: None,
             enable_market_order,
             open: open,
+            auction: false,
+            self_trade_prevention: SelfTradePrevention::default(),
+            matching_algorithm: MatchingAlgorithm::default(),
+            stops: StopBook::default(),
+            pegs: BTreeMap::new(),
+            quotes: HashMap::new(),
+            oco_groups: HashMap::new(),
+            last_price: None,
+            expiries: BTreeSet::new(),
             max_id: 0,
         }
     }
@@ -230,16 +758,16 @@ impl OrderBook {
     }
 
     pub fn insert(&mut self, order: Order, ask_or_bid: AskOrBid) {
-        self.max_id = order.id;
-        match ask_or_bid {
-            AskOrBid::Ask => Self::insert_into(&mut self.asks, &mut self.indices, order),
-            AskOrBid::Bid => Self::insert_into(&mut self.bids, &mut self.indices, order),
-        }
-    }
-
-    fn insert_into(tape: &mut Tape, index: &mut Index, order: Order) {
-        index.insert(order.id, order.price);
-        tape.entry(order.price)
+        // a replaced order is inserted again with its original id
+        self.max_id = self.max_id.max(order.id);
+        self.user_indices.entry(order.user).or_default().insert(order.id);
+        let slot = Slot {
+            ask_or_bid,
+            price: order.price,
+        };
+        self.indices.insert(order.id, slot);
+        self.tape_mut(ask_or_bid)
+            .entry(order.price)
             .and_modify(|page| {
                 page.amount += order.unfilled;
                 page.orders.insert(order.id, order.clone());
@@ -256,22 +784,39 @@ impl OrderBook {
     // }
 
     pub fn remove(&mut self, order_id: OrderId) -> Option<(Order, AskOrBid)> {
-        let price = self.indices.remove(&order_id)?;
-        match (self.get_best_ask(), self.get_best_bid()) {
-            (Some(best_ask), Some(_)) => {
-                if price >= best_ask {
-                    Self::remove_from(&mut self.asks, order_id, &price).map(|o| (o, AskOrBid::Ask))
-                } else {
-                    Self::remove_from(&mut self.bids, order_id, &price).map(|o| (o, AskOrBid::Bid))
-                }
-            }
-            (None, Some(_)) => {
-                Self::remove_from(&mut self.bids, order_id, &price).map(|o| (o, AskOrBid::Bid))
-            }
-            (Some(_), None) => {
-                Self::remove_from(&mut self.asks, order_id, &price).map(|o| (o, AskOrBid::Ask))
+        let slot = self.indices.remove(&order_id)?;
+        let removed = Self::remove_from(self.tape_mut(slot.ask_or_bid), order_id, &slot.price)?;
+        self.unindex_user(&removed.user, order_id);
+        Some((removed, slot.ask_or_bid))
+    }
+
+    fn tape_mut(&mut self, ask_or_bid: AskOrBid) -> &mut Tape {
+        match ask_or_bid {
+            AskOrBid::Ask => &mut self.asks,
+            AskOrBid::Bid => &mut self.bids,
+        }
+    }
+
+    /// drop the indices of an order which has been taken out of its page
+    pub fn forget(&mut self, order_id: OrderId, user: &UserId) {
+        self.indices.remove(&order_id);
+        self.unindex_user(user, order_id);
+    }
+
+    pub fn rebuild_user_indices(&mut self) {
+        self.user_indices.clear();
+        let pages = self.asks.values().chain(self.bids.values());
+        for order in pages.flat_map(|page| page.orders.values()) {
+            self.user_indices.entry(order.user).or_default().insert(order.id);
+        }
+    }
+
+    fn unindex_user(&mut self, user: &UserId, order_id: OrderId) {
+        if let Some(ids) = self.user_indices.get_mut(user) {
+            ids.remove(&order_id);
+            if ids.is_empty() {
+                self.user_indices.remove(user);
             }
-            _ => None,
         }
     }
 
@@ -295,6 +840,67 @@ impl OrderBook {
         }
     }
 
+    pub fn get_best(&mut self, ask_or_bid: AskOrBid) -> Option<OccupiedEntry<Price, OrderPage>> {
+        match ask_or_bid {
+            AskOrBid::Bid => self.asks.first_entry(),
+            AskOrBid::Ask => self.bids.last_entry(),
+        }
+    }
+
+    /// move the price of a post-only order one tick behind the opposite best if it would cross
+    pub fn post_only_price(&self, ask_or_bid: AskOrBid, price: Price) -> Price {
+        let tick = self.tick_size.unwrap_or_else(|| Price::new(1, self.quote_scale));
+        let repriced = match ask_or_bid {
+            AskOrBid::Bid => self
+                .get_best_ask()
+                .filter(|best| price >= *best)
+                .map(|best| best - tick),
+            AskOrBid::Ask => self
+                .get_best_bid()
+                .filter(|best| price <= *best)
+                .map(|best| best + tick),
+        };
+        repriced.filter(|p| p.is_sign_positive() && !p.is_zero()).unwrap_or(price)
+    }
+
+    /// the price of a pegged order rounded to the tick away from the opposite side, `None` if
+    /// there is no reference price
+    pub fn peg_price(&self, peg: &Peg, ask_or_bid: AskOrBid) -> Option<Price> {
+        let reference = match peg.reference {
+            PegReference::Primary => self.best_unpegged(ask_or_bid)?,
+            PegReference::Market => self.best_unpegged(!ask_or_bid)?,
+            PegReference::Mid => {
+                let (ask, bid) = (
+                    self.best_unpegged(AskOrBid::Ask)?,
+                    self.best_unpegged(AskOrBid::Bid)?,
+                );
+                (ask + bid) / Decimal::TWO
+            }
+        };
+        let tick = self.tick_size.unwrap_or_else(|| Price::new(1, self.quote_scale));
+        let price = reference + peg.offset;
+        let price = match (ask_or_bid, peg.cap) {
+            (AskOrBid::Bid, cap) => {
+                let price = (price / tick).floor() * tick;
+                cap.map_or(price, |cap| price.min(cap))
+            }
+            (AskOrBid::Ask, cap) => {
+                let price = (price / tick).ceil() * tick;
+                cap.map_or(price, |cap| price.max(cap))
+            }
+        };
+        Some(price).filter(|p| p.is_sign_positive() && !p.is_zero())
+    }
+
+    fn best_unpegged(&self, ask_or_bid: AskOrBid) -> Option<Price> {
+        let unpegged = |page: &OrderPage| page.orders.keys().any(|id| !self.pegs.contains_key(id));
+        match ask_or_bid {
+            AskOrBid::Ask => self.asks.values().find(|page| unpegged(page)),
+            AskOrBid::Bid => self.bids.values().rev().find(|page| unpegged(page)),
+        }
+        .map(|page| page.price)
+    }
+
     pub fn get_best_ask(&self) -> Option<Price> {
         self.asks.first_key_value().map(|(price, _)| *price)
     }
@@ -303,25 +909,367 @@ impl OrderBook {
         self.bids.last_key_value().map(|(price, _)| *price)
     }
 
+    /// ids of the resting and the stop orders of the user in ascending order
+    pub fn user_orders(&self, user: &UserId) -> Vec<OrderId> {
+        let mut ids = self
+            .user_indices
+            .get(user)
+            .into_iter()
+            .flatten()
+            .chain(
+                self.stops
+                    .indices
+                    .keys()
+                    .filter(|id| self.stops.get(**id).filter(|s| s.user == *user).is_some()),
+            )
+            .copied()
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// the resting orders of the user in ascending order of ids
+    pub fn open_orders(&self, user: &UserId) -> Vec<(&Order, AskOrBid)> {
+        self.user_indices
+            .get(user)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.find_order_with_side(*id))
+            .collect()
+    }
+
+    /// the resting orders of the last mass quote of the user in ascending order of ids
+    pub fn ladder(&self, user: &UserId) -> Vec<(&Order, AskOrBid)> {
+        self.quotes
+            .get(user)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.find_order_with_side(*id))
+            .filter(|(order, _)| order.user == *user)
+            .collect()
+    }
+
     pub fn find_order(&self, order_id: OrderId) -> Option<&Order> {
-        let price = self.indices.get(&order_id)?;
-        match (self.get_best_ask(), self.get_best_bid()) {
-            (Some(best_ask), Some(_)) => {
-                if *price >= best_ask {
-                    self.asks.get(price).and_then(|page| page.get(order_id))
-                } else {
-                    self.bids.get(price).and_then(|page| page.get(order_id))
+        self.find_order_with_side(order_id).map(|(order, _)| order)
+    }
+
+    pub fn find_order_with_side(&self, order_id: OrderId) -> Option<(&Order, AskOrBid)> {
+        let slot = self.indices.get(&order_id)?;
+        let tape = match slot.ask_or_bid {
+            AskOrBid::Ask => &self.asks,
+            AskOrBid::Bid => &self.bids,
+        };
+        tape.get(&slot.price)
+            .and_then(|page| page.get(order_id))
+            .map(|order| (order, slot.ask_or_bid))
+    }
+
+    /// decrease the total unfilled of a resting order without losing its place in the page
+    pub fn reduce_order(&mut self, order_id: OrderId, unfilled: Amount) -> Option<Order> {
+        let page = self.get_page_mut(order_id)?;
+        let order = page.orders.get_mut(&order_id).filter(|o| o.total_unfilled() >= unfilled)?;
+        let delta = order.total_unfilled() - unfilled;
+        let visible = order.reduce(delta);
+        let reduced = order.clone();
+        page.decr_size(&visible);
+        Some(reduced)
+    }
+
+    /// turn a resting order into an iceberg order which only shows `display`
+    pub fn hide(&mut self, order_id: OrderId, display: Amount) {
+        if let Some(page) = self.get_page_mut(order_id) {
+            if let Some(order) = page.orders.get_mut(&order_id) {
+                order.display = display;
+                if order.unfilled > display {
+                    let hidden = order.unfilled - display;
+                    order.hidden += hidden;
+                    order.unfilled = display;
+                    page.decr_size(&hidden);
                 }
             }
-            (None, Some(_)) => self.bids.get(price).and_then(|page| page.get(order_id)),
-            (Some(_), None) => self.asks.get(price).and_then(|page| page.get(order_id)),
-            _ => None,
         }
     }
 
-    pub fn should_accept(&self, price: Price, amount: Amount, id: OrderId) -> bool {
-        self.open && id > self.max_id && amount >= self.min_amount
-            && price.scale() <= self.quote_scale && amount.scale() <= self.base_scale
+    fn get_page_mut(&mut self, order_id: OrderId) -> Option<&mut OrderPage> {
+        let slot = *self.indices.get(&order_id)?;
+        self.tape_mut(slot.ask_or_bid).get_mut(&slot.price)
+    }
+
+    /// feed the last price to the circuit breaker, the orderbook is closed if it's broken
+    pub fn watch_price(&mut self, time: Timestamp) {
+        if let (Some(price), Some(breaker)) = (self.last_price, self.circuit_breaker.as_mut()) {
+            if breaker.observe(price, time) {
+                log::warn!("circuit breaker triggered at price {}", price);
+                self.open = false;
+            }
+        }
+    }
+
+    /// only the resting orders could expire
+    pub fn set_expiry(&mut self, order_id: OrderId, expire_at: Timestamp) {
+        if self.indices.contains_key(&order_id) {
+            self.expiries.insert((expire_at, order_id));
+        }
+    }
+
+    pub fn pop_expired(&mut self, now: Timestamp) -> Option<OrderId> {
+        let first = *self.expiries.iter().next().filter(|(t, _)| *t <= now)?;
+        self.expiries.remove(&first);
+        Some(first.1)
+    }
+
+    /// save the pages crossed by the limit orders `placing` and those of the orders `canceling`
+    pub fn savepoint(
+        &self,
+        placing: &[(OrderId, AskOrBid, Price)],
+        canceling: &[OrderId],
+    ) -> Savepoint {
+        let (mut asks, mut bids) = (BTreeSet::<Price>::new(), BTreeSet::<Price>::new());
+        for (_, ask_or_bid, price) in placing {
+            match ask_or_bid {
+                AskOrBid::Ask => bids.extend(self.bids.range(price..).map(|(p, _)| *p)),
+                AskOrBid::Bid => asks.extend(self.asks.range(..=price).map(|(p, _)| *p)),
+            }
+        }
+        let mut with_stops = false;
+        for id in canceling {
+            let stop = self.stops.get(*id);
+            with_stops |= stop.is_some();
+            // canceling a stop leg cancels the limit leg
+            let id = stop.and_then(|s| s.oco).unwrap_or(*id);
+            if let Some(slot) = self.indices.get(&id) {
+                match slot.ask_or_bid {
+                    AskOrBid::Ask => asks.insert(slot.price),
+                    AskOrBid::Bid => bids.insert(slot.price),
+                };
+            }
+        }
+        let pages = asks
+            .into_iter()
+            .map(|p| (AskOrBid::Ask, p, self.asks.get(&p).cloned()))
+            .chain(bids.into_iter().map(|p| (AskOrBid::Bid, p, self.bids.get(&p).cloned())))
+            .collect::<Vec<_>>();
+        // the limit legs of the OCO groups touch the stop legs
+        with_stops |= pages
+            .iter()
+            .filter_map(|(_, _, page)| page.as_ref())
+            .flat_map(|page| page.orders.keys())
+            .any(|id| self.oco_groups.contains_key(id));
+        Savepoint {
+            pages,
+            placing: placing.iter().map(|(id, _, _)| *id).collect(),
+            stops: match with_stops {
+                true => Some((self.stops.clone(), self.oco_groups.clone())),
+                false => None,
+            },
+            max_id: self.max_id,
+            last_price: self.last_price,
+        }
+    }
+
+    pub fn restore(&mut self, savepoint: Savepoint) {
+        for (ask_or_bid, price, page) in savepoint.pages {
+            if let Some(current) = self.tape_mut(ask_or_bid).remove(&price) {
+                for order in current.orders.values() {
+                    self.indices.remove(&order.id);
+                    self.unindex_user(&order.user, order.id);
+                }
+            }
+            if let Some(page) = page {
+                for order in page.orders.values() {
+                    self.indices.insert(order.id, Slot { ask_or_bid, price });
+                    self.user_indices.entry(order.user).or_default().insert(order.id);
+                }
+                self.tape_mut(ask_or_bid).insert(price, page);
+            }
+        }
+        let placing = savepoint.placing;
+        for id in placing.iter() {
+            self.remove(*id);
+        }
+        self.expiries.retain(|(_, id)| !placing.contains(id));
+        if let Some((stops, oco_groups)) = savepoint.stops {
+            self.stops = stops;
+            self.oco_groups = oco_groups;
+        }
+        self.max_id = savepoint.max_id;
+        self.last_price = savepoint.last_price;
+    }
+
+    pub fn insert_stop(&mut self, stop: StopOrder) {
+        self.max_id = self.max_id.max(stop.id);
+        self.stops.insert(stop);
+    }
+
+    pub fn validate_limit(&self, price: Price, amount: Amount, id: OrderId) -> anyhow::Result<()> {
+        self.validate_id(id)?;
+        self.validate_order(price, amount, None)
+    }
+
+    /// `amount` is the quote volume of stop-market bids, the base amount of the others
+    pub fn validate_stop(
+        &self,
+        trigger: Price,
+        price: Option<Price>,
+        amount: Amount,
+        ask_or_bid: AskOrBid,
+        id: OrderId,
+    ) -> anyhow::Result<()> {
+        self.validate_price(trigger)?;
+        match price {
+            Some(price) => self.validate_limit(price, amount, id),
+            None => self.validate_market(amount, ask_or_bid, id),
+        }
+    }
+
+    pub fn validate_iceberg(
+        &self,
+        price: Price,
+        amount: Amount,
+        display: Amount,
+        id: OrderId,
+    ) -> anyhow::Result<()> {
+        self.validate_id(id)?;
+        self.validate_order(price, amount, Some(display))
+    }
+
+    /// validate a limit order without the event id, i.e. the orders placed by one event
+    pub fn validate_order(
+        &self,
+        price: Price,
+        amount: Amount,
+        display: Option<Amount>,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(self.open, "orderbook closed");
+        self.validate_price(price)?;
+        self.validate_amount(amount)?;
+        self.validate_vol(price * amount)?;
+        if let Some(display) = display {
+            anyhow::ensure!(
+                display <= amount,
+                "display {} greater than amount {}",
+                display,
+                amount
+            );
+            anyhow::ensure!(
+                display >= self.min_amount,
+                "display {} less than min amount {}",
+                display,
+                self.min_amount
+            );
+            anyhow::ensure!(
+                display.scale() <= self.base_scale,
+                "display {} exceeds base scale {}",
+                display,
+                self.base_scale
+            );
+            self.validate_lot(display)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate_band(&self, price: Price) -> anyhow::Result<()> {
+        if let (Some(band), Some(last)) = (self.price_band, self.last_price) {
+            anyhow::ensure!(
+                (price - last).abs() <= last * band,
+                "price {} out of band {} around last price {}",
+                price,
+                band,
+                last
+            );
+        }
+        Ok(())
+    }
+
+    pub fn validate_replace(&self, price: Price, amount: Amount) -> anyhow::Result<()> {
+        self.validate_order(price, amount, None)
+    }
+
+    /// `amount` is the base amount of an ask or the quote volume of a bid
+    pub fn validate_market(
+        &self,
+        amount: Amount,
+        ask_or_bid: AskOrBid,
+        id: OrderId,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(self.enable_market_order, "market order not enabled");
+        anyhow::ensure!(!self.auction, "market order not accepted in auction");
+        self.validate_id(id)?;
+        match ask_or_bid {
+            AskOrBid::Ask => self.validate_amount(amount),
+            AskOrBid::Bid => {
+                anyhow::ensure!(
+                    amount.scale() <= self.quote_scale,
+                    "vol {} exceeds quote scale {}",
+                    amount,
+                    self.quote_scale
+                );
+                self.validate_vol(amount)
+            }
+        }
+    }
+
+    pub fn validate_id(&self, id: OrderId) -> anyhow::Result<()> {
+        anyhow::ensure!(self.open, "orderbook closed");
+        anyhow::ensure!(id > self.max_id, "event id {} not greater than {}", id, self.max_id);
+        Ok(())
+    }
+
+    fn validate_price(&self, price: Price) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            price.scale() <= self.quote_scale,
+            "price {} exceeds quote scale {}",
+            price,
+            self.quote_scale
+        );
+        if let Some(tick) = self.tick_size {
+            anyhow::ensure!(
+                (price % tick).is_zero(),
+                "price {} not a multiple of tick size {}",
+                price,
+                tick
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_amount(&self, amount: Amount) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            amount.scale() <= self.base_scale,
+            "amount {} exceeds base scale {}",
+            amount,
+            self.base_scale
+        );
+        anyhow::ensure!(
+            amount >= self.min_amount,
+            "amount {} less than min amount {}",
+            amount,
+            self.min_amount
+        );
+        if let Some(max) = self.max_amount {
+            anyhow::ensure!(amount <= max, "amount {} greater than max amount {}", amount, max);
+        }
+        self.validate_lot(amount)
+    }
+
+    fn validate_lot(&self, amount: Amount) -> anyhow::Result<()> {
+        if let Some(lot) = self.lot_size {
+            anyhow::ensure!(
+                (amount % lot).is_zero(),
+                "amount {} not a multiple of lot size {}",
+                amount,
+                lot
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_vol(&self, vol: Vol) -> anyhow::Result<()> {
+        anyhow::ensure!(vol >= self.min_vol, "vol {} less than min vol {}", vol, self.min_vol);
+        if let Some(max) = self.max_vol {
+            anyhow::ensure!(vol <= max, "vol {} greater than max vol {}", vol, max);
+        }
+        Ok(())
     }
 }
 
@@ -337,6 +1285,16 @@ pub fn test_scale() {
     assert_eq!("0.00", amount.to_string());
 }
 
+#[test]
+pub fn test_market_bid_scale() {
+    use rust_decimal_macros::dec;
+
+    let orderbook = OrderBook::new(4, 2, dec!(0.001), dec!(0.001), dec!(0.1), dec!(1), true, true);
+    assert!(orderbook.validate_market(dec!(10.01), AskOrBid::Bid, 1).is_ok());
+    // the quote budget of a market bid is limited by the quote scale
+    assert!(orderbook.validate_market(dec!(10.001), AskOrBid::Bid, 1).is_err());
+}
+
 #[test]
 pub fn test_orderbook() {
     use rust_decimal_macros::dec;
@@ -377,3 +1335,156 @@ pub fn test_orderbook() {
     assert!(!book.asks.is_empty());
     assert_eq!(book.get_best_ask().unwrap(), dec!(105));
 }
+
+#[test]
+pub fn test_validate() {
+    use rust_decimal_macros::dec;
+    let mut book = OrderBook::new(4, 2, dec!(0.001), dec!(0.001), dec!(1), dec!(10), true, true);
+    book.tick_size = Some(dec!(0.05));
+    book.lot_size = Some(dec!(0.5));
+    book.max_amount = Some(dec!(100));
+    book.max_vol = Some(dec!(1000));
+    assert!(book.validate_limit(dec!(10.05), dec!(1.5), 1).is_ok());
+    assert!(book.validate_limit(dec!(10.03), dec!(1.5), 1).is_err());
+    assert!(book.validate_limit(dec!(10.001), dec!(1.5), 1).is_err());
+    assert!(book.validate_limit(dec!(10), dec!(1.2), 1).is_err());
+    assert!(book.validate_limit(dec!(10), dec!(0.5), 1).is_err());
+    assert!(book.validate_limit(dec!(1), dec!(5), 1).is_err());
+    assert!(book.validate_limit(dec!(1), dec!(100.5), 1).is_err());
+    assert!(book.validate_limit(dec!(20), dec!(60), 1).is_err());
+    assert!(book.validate_iceberg(dec!(10), dec!(10), dec!(1.5), 1).is_ok());
+    assert!(book.validate_iceberg(dec!(10), dec!(10), dec!(1.2), 1).is_err());
+    book.lot_size = None;
+    assert!(book.validate_iceberg(dec!(10), dec!(10), dec!(1.00001), 1).is_err());
+    book.lot_size = Some(dec!(0.5));
+    assert!(book.validate_market(dec!(1.5), AskOrBid::Ask, 1).is_ok());
+    assert!(book.validate_market(dec!(1.2), AskOrBid::Ask, 1).is_err());
+    assert!(book.validate_market(dec!(1001), AskOrBid::Bid, 1).is_err());
+    assert!(book.validate_stop(dec!(10.01), Some(dec!(10)), dec!(1.5), AskOrBid::Bid, 1).is_err());
+    assert!(book.validate_replace(dec!(10.5), dec!(2)).is_ok());
+    book.insert(Order::new(1, UserId::zero(), dec!(10), dec!(2)), AskOrBid::Bid);
+    let err = book.validate_limit(dec!(10.05), dec!(1.5), 1).unwrap_err();
+    assert_eq!(err.to_string(), "event id 1 not greater than 1");
+    assert_eq!(book.post_only_price(AskOrBid::Ask, dec!(9)), dec!(10.05));
+}
+
+#[test]
+pub fn test_price_band_and_circuit_breaker() {
+    use rust_decimal_macros::dec;
+    let mut book = OrderBook::new(4, 2, dec!(0.001), dec!(0.001), dec!(1), dec!(1), true, true);
+    book.price_band = Some(dec!(0.1));
+    assert!(book.validate_band(dec!(1000)).is_ok());
+    book.last_price = Some(dec!(100));
+    assert!(book.validate_band(dec!(110)).is_ok());
+    assert!(book.validate_band(dec!(90)).is_ok());
+    assert!(book.validate_band(dec!(110.01)).is_err());
+    assert!(book.validate_band(dec!(89.99)).is_err());
+
+    book.circuit_breaker = Some(CircuitBreaker::new(dec!(0.2), 60));
+    book.watch_price(1000);
+    book.last_price = Some(dec!(115));
+    book.watch_price(1030);
+    assert!(book.open);
+    // out of the window
+    book.last_price = Some(dec!(125));
+    book.watch_price(1061);
+    assert!(book.open);
+    book.last_price = Some(dec!(140));
+    book.watch_price(1080);
+    assert!(!book.open);
+}
+
+#[test]
+pub fn test_user_indices() {
+    use crate::matcher;
+    use rust_decimal_macros::dec;
+    let mut book = OrderBook::new(4, 2, dec!(0.001), dec!(0.001), dec!(1), dec!(10), true, true);
+    let (alice, bob) = (UserId::from_low_u64_be(1), UserId::from_low_u64_be(2));
+    book.insert(Order::new(1, alice, dec!(10), dec!(2)), AskOrBid::Bid);
+    book.insert(Order::new(2, alice, dec!(11), dec!(2)), AskOrBid::Ask);
+    book.insert(Order::new(3, bob, dec!(9), dec!(2)), AskOrBid::Bid);
+    assert_eq!(book.user_orders(&alice), vec![1, 2]);
+    assert_eq!(
+        book.open_orders(&bob).iter().map(|(o, side)| (o.id, *side)).collect::<Vec<_>>(),
+        vec![(3, AskOrBid::Bid)]
+    );
+    assert!(book.remove(1).is_some());
+    assert_eq!(book.user_orders(&alice), vec![2]);
+    // the filled makers are dropped from the index
+    matcher::execute_limit(
+        &mut book,
+        bob,
+        4,
+        dec!(11),
+        dec!(2),
+        AskOrBid::Bid,
+        matcher::TimeInForce::GoodTillCancel,
+        SelfTradePrevention::default(),
+    );
+    assert!(book.user_orders(&alice).is_empty());
+    assert!(!book.user_indices.contains_key(&alice));
+    assert_eq!(book.user_orders(&bob), vec![3]);
+}
+
+#[test]
+pub fn test_index_on_crossed_book() {
+    use rust_decimal_macros::dec;
+    let mut book = OrderBook::new(4, 2, dec!(0.001), dec!(0.001), dec!(1), dec!(10), true, true);
+    assert!(book.find_order(1).is_none());
+    assert!(book.remove(1).is_none());
+    // both sides at one price and a bid over the best ask, as in the auction
+    book.insert(Order::new(1, UserId::zero(), dec!(11), dec!(2)), AskOrBid::Ask);
+    book.insert(Order::new(2, UserId::zero(), dec!(11), dec!(3)), AskOrBid::Bid);
+    book.insert(Order::new(3, UserId::zero(), dec!(12), dec!(4)), AskOrBid::Bid);
+    assert_eq!(book.get_best_ask(), Some(dec!(11)));
+    assert_eq!(book.get_best_bid(), Some(dec!(12)));
+    assert_eq!(book.find_order_with_side(1).map(|(o, s)| (o.id, s)), Some((1, AskOrBid::Ask)));
+    assert_eq!(book.find_order_with_side(2).map(|(o, s)| (o.id, s)), Some((2, AskOrBid::Bid)));
+    assert_eq!(book.size(), (dec!(2), dec!(7)));
+    let (order, side) = book.remove(2).unwrap();
+    assert_eq!((order.id, side), (2, AskOrBid::Bid));
+    assert!(book.remove(2).is_none());
+    assert!(book.asks.contains_key(&dec!(11)));
+    assert!(!book.bids.contains_key(&dec!(11)));
+    assert_eq!(book.reduce_order(3, dec!(1)).unwrap().unfilled, dec!(1));
+    assert_eq!(book.size(), (dec!(2), dec!(1)));
+    book.hide(1, dec!(1));
+    assert_eq!(book.size(), (dec!(1), dec!(1)));
+    let (order, side) = book.remove(1).unwrap();
+    assert_eq!((order.total_unfilled(), side), (dec!(2), AskOrBid::Ask));
+    assert!(book.asks.is_empty());
+    assert_eq!(book.find_order_with_side(3).map(|(_, s)| s), Some(AskOrBid::Bid));
+}
+
+#[test]
+pub fn test_trailing_stops() {
+    use rust_decimal_macros::dec;
+    let mut stops = StopBook::default();
+    let trail = Trail {
+        offset: TrailOffset::Ratio(dec!(0.1)),
+        extreme: dec!(100),
+    };
+    stops.insert(StopOrder {
+        id: 1,
+        user: UserId::zero(),
+        ask_or_bid: AskOrBid::Bid,
+        kind: StopKind::StopLoss,
+        trigger: trail.trigger(AskOrBid::Bid),
+        price: None,
+        amount: dec!(100),
+        oco: None,
+        trail: Some(trail),
+    });
+    assert_eq!(stops.get(1).unwrap().trigger, dec!(110));
+    // a bid follows the lowest price
+    stops.trail(dec!(105));
+    assert_eq!(stops.get(1).unwrap().trigger, dec!(110));
+    stops.trail(dec!(90));
+    assert_eq!(stops.get(1).unwrap().trigger, dec!(99));
+    assert!(stops.pop_triggered(dec!(98)).is_none());
+    stops.trail(dec!(99));
+    let stop = stops.pop_triggered(dec!(99)).unwrap();
+    assert_eq!(stop.trail.unwrap().extreme, dec!(90));
+    assert!(stops.is_empty());
+    assert!(stops.trailing.is_empty());
+}
@@ -47,9 +47,33 @@ pub struct Output {
     pub base_delta: Amount,
     pub base_available: Amount,
     pub base_frozen: Amount,
+    /// the fee rate charging the deltas
+    pub fee: Fee,
     pub timestamp: u64,
 }
 
+/// the clearing results of a symbol waiting to be written
+struct Pending {
+    /// the results of the events before it are dropped, they're written already
+    event_id: u64,
+    /// the last event received and the sub-sequence of its next result
+    next: (u64, u32),
+    /// the results with their sub-sequences in the event
+    results: Vec<(u32, Output)>,
+}
+
+impl Pending {
+    fn append(&mut self, cr: Vec<Output>) {
+        for output in cr {
+            if self.next.0 != output.event_id {
+                self.next = (output.event_id, 0);
+            }
+            self.results.push((self.next.1, output));
+            self.next.1 += 1;
+        }
+    }
+}
+
 pub fn write_depth(depth: Vec<Depth>) {
     let redis = REDIS.get_connection();
     match redis {
@@ -71,7 +95,7 @@ pub fn write_depth(depth: Vec<Depth>) {
 }
 
 pub fn init(sender: Sender<Vec<Output>>, recv: Receiver<Vec<Output>>) {
-    let mut buf = HashMap::<Symbol, (u64, Vec<Output>)>::new();
+    let mut buf = HashMap::<Symbol, Pending>::new();
     thread::spawn(move || loop {
         let cr = recv.recv().unwrap();
         if cr.is_empty() {
@@ -102,12 +126,12 @@ fn get_max_record(symbol: Symbol) -> u64 {
     id.or(Some(0)).unwrap()
 }
 
-fn flush(symbol: Symbol, pending: &mut Vec<Output>) {
+fn flush(symbol: Symbol, pending: &mut Vec<(u32, Output)>) {
     let sql = format!(
         r#"INSERT IGNORE INTO t_clearing_result_{}_{}
-(f_event_id,f_order_id,f_user_id,f_status,f_role,f_ask_or_bid,f_price,f_quote_delta,f_base_delta,f_quote_charge,f_base_charge,f_quote_available,f_base_available,f_quote_frozen,f_base_frozen,f_timestamp)
+(f_event_id,f_seq,f_order_id,f_user_id,f_status,f_role,f_ask_or_bid,f_price,f_quote_delta,f_base_delta,f_quote_charge,f_base_charge,f_quote_available,f_base_available,f_quote_frozen,f_base_frozen,f_fee,f_timestamp)
 VALUES
-(:event_id,:order_id,:user_id,:state,:role,:ask_or_bid,:price,:quote_delta,:base_delta,:quote_charge,:base_charge,:quote_available,:base_available,:quote_frozen,:base_frozen,FROM_UNIXTIME(:timestamp))"#,
+(:event_id,:seq,:order_id,:user_id,:state,:role,:ask_or_bid,:price,:quote_delta,:base_delta,:quote_charge,:base_charge,:quote_available,:base_available,:quote_frozen,:base_frozen,:fee,FROM_UNIXTIME(:timestamp))"#,
         symbol.0, symbol.1
     );
     let conn = DB.get_conn();
@@ -118,9 +142,10 @@ VALUES
     let mut conn = conn.unwrap();
     let r = conn.exec_batch(
         sql,
-        pending.iter().map(|p| {
+        pending.iter().map(|(seq, p)| {
             params! {
                 "event_id" => p.event_id,
+                "seq" => seq,
                 "order_id" => p.order_id,
                 "user_id" => format!("{:?}", p.user_id),
                 "state" => p.state.into(): u32,
@@ -135,6 +160,7 @@ VALUES
                 "base_available" => p.base_available,
                 "quote_frozen" => p.quote_frozen,
                 "base_frozen" => p.base_frozen,
+                "fee" => p.fee,
                 "timestamp" => p.timestamp,
             }
         }),
@@ -147,27 +173,81 @@ VALUES
     }
 }
 
-fn flush_all(buf: &mut HashMap<Symbol, (u64, Vec<Output>)>) {
+fn flush_all(buf: &mut HashMap<Symbol, Pending>) {
     for (symbol, pending) in buf.iter_mut() {
-        flush(*symbol, &mut pending.1);
+        flush(*symbol, &mut pending.results);
     }
 }
 
-fn write(mut cr: Vec<Output>, buf: &mut HashMap<Symbol, (u64, Vec<Output>)>) {
+fn write(cr: Vec<Output>, buf: &mut HashMap<Symbol, Pending>) {
     let symbol = cr.first().unwrap().symbol;
     let pending = buf.get_mut(&symbol);
     if pending.is_none() {
-        buf.insert(symbol, (get_max_record(symbol), cr));
+        let mut pending = Pending {
+            event_id: get_max_record(symbol),
+            next: (0, 0),
+            results: vec![],
+        };
+        pending.append(cr);
+        buf.insert(symbol, pending);
         return;
     }
     let pending = pending.unwrap();
     let prepare_write_event_id = cr.last().unwrap().event_id;
-    if prepare_write_event_id < pending.0 {
+    if prepare_write_event_id < pending.event_id {
         return;
     }
-    pending.0 = prepare_write_event_id;
-    pending.1.append(&mut cr);
-    if pending.1.len() >= 100 {
-        flush(symbol, &mut pending.1);
+    pending.event_id = prepare_write_event_id;
+    pending.append(cr);
+    if pending.results.len() >= 100 {
+        flush(symbol, &mut pending.results);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn output(event_id: u64, order_id: u64) -> Output {
+        Output {
+            event_id,
+            order_id,
+            user_id: UserId::default(),
+            symbol: (101, 100),
+            state: State::Filled,
+            role: Role::Maker,
+            ask_or_bid: AskOrBid::Ask,
+            price: Price::ZERO,
+            quote_charge: Amount::ZERO,
+            quote_delta: Amount::ZERO,
+            quote_available: Amount::ZERO,
+            quote_frozen: Amount::ZERO,
+            base_charge: Amount::ZERO,
+            base_delta: Amount::ZERO,
+            base_available: Amount::ZERO,
+            base_frozen: Amount::ZERO,
+            fee: Fee::ZERO,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    pub fn test_sub_sequence() {
+        let mut pending = Pending {
+            event_id: 0,
+            next: (0, 0),
+            results: vec![],
+        };
+        // an order could be output twice in an event, even across the channel messages
+        pending.append(vec![output(1, 2), output(1, 1)]);
+        pending.append(vec![output(1, 3), output(1, 1)]);
+        pending.results.clear();
+        pending.append(vec![output(1, 4), output(2, 1)]);
+        let keys = pending
+            .results
+            .iter()
+            .map(|(seq, o)| (o.event_id, *seq, o.order_id))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(1, 4, 4), (2, 0, 1)], keys);
     }
 }
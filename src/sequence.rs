@@ -13,7 +13,9 @@
 // limitations under the License.
 
 use anyhow::{anyhow, ensure};
-use crate::{config::C, core::*, db::DB, event::*, orderbook::AskOrBid};
+use crate::{config::C, core::*, currency::*, db::DB, event::*, fees::*, matcher::TimeInForce,
+            orderbook::{AskOrBid, MatchingAlgorithm, Peg, PegReference, SelfTradePrevention,
+                        StopKind, TrailOffset}};
 use mysql::{*, prelude::*};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
@@ -22,10 +24,16 @@ use std::{convert::{TryFrom, TryInto}, str::FromStr,
 
 pub const ASK_LIMIT: u32 = 0;
 pub const BID_LIMIT: u32 = 1;
+pub const ASK_MARKET: u32 = 2;
+pub const BID_MARKET: u32 = 3;
 pub const CANCEL: u32 = 4;
 pub const CANCEL_ALL: u32 = 5;
+pub const REPLACE: u32 = 6;
+pub const ASK_STOP: u32 = 7;
+pub const BID_STOP: u32 = 8;
 pub const TRANSFER_OUT: u32 = 10;
 pub const TRANSFER_IN: u32 = 11;
+pub const UNCROSS: u32 = 12;
 pub const UPDATE_SYMBOL: u32 = 13;
 
 pub const QUERY_ORDER: u32 = 14;
@@ -34,6 +42,19 @@ pub const QUERY_ACCOUNTS: u32 = 16;
 pub const DUMP: u32 = 17;
 pub const UPDATE_DEPTH: u32 = 18;
 pub const CONFIRM_ALL: u32 = 19;
+pub const ASK_OCO: u32 = 20;
+pub const BID_OCO: u32 = 21;
+pub const BATCH: u32 = 22;
+pub const CANCEL_USER_ORDERS: u32 = 23;
+pub const QUERY_OPEN_ORDERS: u32 = 24;
+pub const ASK_PEG: u32 = 25;
+pub const BID_PEG: u32 = 26;
+pub const QUOTE: u32 = 27;
+pub const UPDATE_FEE_TIERS: u32 = 28;
+pub const UPDATE_USER_FEE: u32 = 29;
+pub const QUERY_SYMBOL: u32 = 30;
+pub const DELIST: u32 = 31;
+pub const UPDATE_CURRENCY: u32 = 32;
 
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct Sequence {
@@ -61,13 +82,45 @@ impl TryInto<Event> for Sequence {
                 );
                 let vol = amount.checked_mul(price).ok_or(anyhow!(""))?;
                 ensure!(vol < max_number(), "");
+                #[cfg(feature = "fusotao")]
+                ensure!(self.cmd.display.is_none(), "iceberg orders not supported");
+                #[cfg(feature = "fusotao")]
+                ensure!(self.cmd.expire_at.is_none(), "good-till-time orders not supported");
+                #[cfg(feature = "fusotao")]
+                ensure!(self.cmd.client_order_id.is_none(), "client order ids not supported");
+                if let Some(ref client_order_id) = self.cmd.client_order_id {
+                    ensure!(
+                        !client_order_id.is_empty() && client_order_id.len() <= 64,
+                        "invalid client order id"
+                    );
+                }
+                if let Some(display) = self.cmd.display {
+                    ensure!(
+                        display.is_sign_positive() && display <= amount && display.scale() <= 10,
+                        "invalid display numeric"
+                    );
+                }
                 let cmd = LimitCmd {
                     symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
                     user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
-                    order_id: self.cmd.order_id.ok_or(anyhow!(""))?,
+                    // the event id is taken if the order is identified by the client order id
+                    order_id: self.cmd
+                        .order_id
+                        .or_else(|| self.cmd.client_order_id.as_ref().map(|_| self.id))
+                        .ok_or(anyhow!(""))?,
                     price: price,
                     amount: amount,
                     ask_or_bid: AskOrBid::try_from(self.cmd.cmd)?,
+                    time_in_force: self.cmd
+                        .time_in_force
+                        .map_or(Ok(TimeInForce::default()), TimeInForce::try_from)?,
+                    self_trade_prevention: self.cmd
+                        .self_trade_prevention
+                        .map(SelfTradePrevention::try_from)
+                        .transpose()?,
+                    display: self.cmd.display,
+                    expire_at: self.cmd.expire_at,
+                    client_order_id: self.cmd.client_order_id,
                     #[cfg(feature = "fusotao")]
                     nonce: self.cmd.nonce.ok_or(anyhow!(""))?,
                     #[cfg(feature = "fusotao")]
@@ -75,12 +128,42 @@ impl TryInto<Event> for Sequence {
                 };
                 Ok(Event::Limit(self.id, cmd, self.timestamp))
             }
+            #[cfg(not(feature = "fusotao"))]
+            ASK_MARKET | BID_MARKET => {
+                let ask_or_bid = AskOrBid::try_from(self.cmd.cmd)?;
+                // asks are measured in base, bids are measured in quote
+                let amount = match ask_or_bid {
+                    AskOrBid::Ask => self.cmd.amount,
+                    AskOrBid::Bid => self.cmd.vol,
+                }
+                .ok_or(anyhow!(""))?;
+                ensure!(
+                    amount.is_sign_positive() && amount < max_number() && amount.scale() <= 10,
+                    "invalid amount numeric"
+                );
+                let cmd = MarketCmd {
+                    symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
+                    user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    order_id: self.cmd.order_id.ok_or(anyhow!(""))?,
+                    amount,
+                    ask_or_bid,
+                    self_trade_prevention: self.cmd
+                        .self_trade_prevention
+                        .map(SelfTradePrevention::try_from)
+                        .transpose()?,
+                };
+                Ok(Event::Market(self.id, cmd, self.timestamp))
+            }
             CANCEL => Ok(Event::Cancel(
                 self.id,
                 CancelCmd {
                     symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
                     user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
-                    order_id: self.cmd.order_id.ok_or(anyhow!(""))?,
+                    order_id: self.cmd
+                        .order_id
+                        .or_else(|| self.cmd.client_order_id.as_ref().map(|_| 0))
+                        .ok_or(anyhow!(""))?,
+                    client_order_id: self.cmd.client_order_id,
                     #[cfg(feature = "fusotao")]
                     nonce: self.cmd.nonce.ok_or(anyhow!(""))?,
                     #[cfg(feature = "fusotao")]
@@ -88,7 +171,266 @@ impl TryInto<Event> for Sequence {
                 },
                 self.timestamp,
             )),
-            // TODO scale and max
+            #[cfg(not(feature = "fusotao"))]
+            REPLACE => {
+                ensure!(
+                    self.cmd.price.is_some() || self.cmd.amount.is_some(),
+                    "nothing to replace"
+                );
+                if let Some(price) = self.cmd.price {
+                    ensure!(
+                        price.is_sign_positive() && price < max_number() && price.scale() <= 12,
+                        "invalid price numeric"
+                    );
+                }
+                if let Some(amount) = self.cmd.amount {
+                    ensure!(
+                        amount.is_sign_positive() && amount < max_number() && amount.scale() <= 10,
+                        "invalid amount numeric"
+                    );
+                }
+                let cmd = ReplaceCmd {
+                    symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
+                    user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    order_id: self.cmd.order_id.ok_or(anyhow!(""))?,
+                    price: self.cmd.price,
+                    amount: self.cmd.amount,
+                };
+                Ok(Event::Replace(self.id, cmd, self.timestamp))
+            }
+            #[cfg(not(feature = "fusotao"))]
+            ASK_STOP | BID_STOP => {
+                let ask_or_bid = AskOrBid::try_from(self.cmd.cmd)?;
+                let trail = match (self.cmd.trail_amount, self.cmd.trail_ratio) {
+                    (None, None) => None,
+                    (Some(amount), None) => {
+                        ensure!(
+                            amount > Price::ZERO && amount < max_number() && amount.scale() <= 12,
+                            "invalid trail amount numeric"
+                        );
+                        Some(TrailOffset::Amount(amount))
+                    }
+                    (None, Some(ratio)) => {
+                        ensure!(
+                            ratio > Decimal::ZERO && ratio < Decimal::ONE && ratio.scale() <= 8,
+                            "invalid trail ratio numeric"
+                        );
+                        Some(TrailOffset::Ratio(ratio))
+                    }
+                    _ => return Err(anyhow!("both trail amount and ratio present")),
+                };
+                // the trigger of a trailing stop is derived from the last price
+                let trigger = match trail {
+                    Some(_) => Price::ZERO,
+                    None => self.cmd.trigger_price.ok_or(anyhow!(""))?,
+                };
+                ensure!(
+                    trigger.is_sign_positive() && trigger < max_number() && trigger.scale() <= 12,
+                    "invalid trigger price numeric"
+                );
+                if let Some(price) = self.cmd.price {
+                    ensure!(
+                        price.is_sign_positive() && price < max_number() && price.scale() <= 12,
+                        "invalid price numeric"
+                    );
+                }
+                // stop-market bids are measured in quote like market bids
+                let amount = match (self.cmd.price, ask_or_bid) {
+                    (None, AskOrBid::Bid) => self.cmd.vol,
+                    _ => self.cmd.amount,
+                }
+                .ok_or(anyhow!(""))?;
+                ensure!(
+                    amount.is_sign_positive() && amount < max_number() && amount.scale() <= 10,
+                    "invalid amount numeric"
+                );
+                let cmd = StopCmd {
+                    symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
+                    user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    order_id: self.cmd.order_id.ok_or(anyhow!(""))?,
+                    kind: StopKind::try_from(self.cmd.stop_kind.ok_or(anyhow!(""))?)?,
+                    trigger,
+                    price: self.cmd.price,
+                    amount,
+                    ask_or_bid,
+                    trail,
+                };
+                Ok(Event::Stop(self.id, cmd, self.timestamp))
+            }
+            #[cfg(not(feature = "fusotao"))]
+            ASK_OCO | BID_OCO => {
+                let ask_or_bid = AskOrBid::try_from(self.cmd.cmd)?;
+                let amount = self.cmd.amount.ok_or(anyhow!(""))?;
+                let price = self.cmd.price.ok_or(anyhow!(""))?;
+                let trigger = self.cmd.trigger_price.ok_or(anyhow!(""))?;
+                for p in [Some(price), Some(trigger), self.cmd.stop_price].iter().flatten() {
+                    ensure!(
+                        p.is_sign_positive() && *p < max_number() && p.scale() <= 12,
+                        "invalid price numeric"
+                    );
+                }
+                ensure!(
+                    amount.is_sign_positive() && amount < max_number() && amount.scale() <= 10,
+                    "invalid amount numeric"
+                );
+                let cmd = OcoCmd {
+                    symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
+                    user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    order_id: self.cmd.order_id.ok_or(anyhow!(""))?,
+                    price,
+                    amount,
+                    ask_or_bid,
+                    stop_order_id: self.cmd.stop_order_id.ok_or(anyhow!(""))?,
+                    kind: self.cmd.stop_kind.map_or(Ok(StopKind::StopLoss), StopKind::try_from)?,
+                    trigger,
+                    stop_price: self.cmd.stop_price,
+                };
+                Ok(Event::Oco(self.id, cmd, self.timestamp))
+            }
+            #[cfg(not(feature = "fusotao"))]
+            BATCH => {
+                let items = self.cmd.batch.as_ref().filter(|b| !b.is_empty()).ok_or(anyhow!(""))?;
+                // the items share the event id, so the order ids can't be taken from it
+                ensure!(
+                    items.iter().all(|item| item.cmd == CANCEL || item.order_id.is_some()),
+                    "order ids of limit orders required in batch"
+                );
+                let mut ids = std::collections::HashSet::new();
+                ensure!(
+                    items
+                        .iter()
+                        .filter(|item| item.cmd != CANCEL)
+                        .all(|item| ids.insert(item.order_id)),
+                    "duplicated order id in batch"
+                );
+                // the items inherit the symbol and the user of the batch
+                let orders = items
+                    .iter()
+                    .map(|item| {
+                        let seq = Sequence {
+                            id: self.id,
+                            cmd: Command {
+                                base: self.cmd.base,
+                                quote: self.cmd.quote,
+                                user_id: self.cmd.user_id.clone(),
+                                ..item.clone()
+                            },
+                            status: self.status,
+                            timestamp: self.timestamp,
+                        };
+                        match seq.try_into()? {
+                            Event::Limit(_, cmd, _) => Ok(BatchItem::Limit(cmd)),
+                            Event::Cancel(_, cmd, _) => Ok(BatchItem::Cancel(cmd)),
+                            _ => Err(anyhow!("only limit orders and cancels allowed in batch")),
+                        }
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let cmd = BatchCmd {
+                    symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
+                    user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    all_or_nothing: self.cmd.all_or_nothing.unwrap_or(false),
+                    orders,
+                };
+                Ok(Event::Batch(self.id, cmd, self.timestamp))
+            }
+            #[cfg(not(feature = "fusotao"))]
+            UPDATE_FEE_TIERS => {
+                let tiers = self.cmd.fee_tiers.clone().unwrap_or_default();
+                let min_taker_fee = tiers.iter().map(|t| t.taker_fee).min().unwrap_or_default();
+                for tier in tiers.iter() {
+                    ensure!(
+                        tier.volume.is_sign_positive() && tier.volume < max_number(),
+                        "invalid tier volume numeric"
+                    );
+                    ensure!(valid_fees(tier.taker_fee, tier.maker_fee), "invalid fee");
+                    ensure!(
+                        tier.maker_fee >= -min_taker_fee,
+                        "maker rebate larger than taker fee"
+                    );
+                }
+                let cmd = FeeTiersCmd {
+                    quote: self.cmd.quote.ok_or(anyhow!(""))?,
+                    tiers,
+                };
+                Ok(Event::UpdateFeeTiers(self.id, cmd, self.timestamp))
+            }
+            #[cfg(not(feature = "fusotao"))]
+            UPDATE_USER_FEE => {
+                let rates = match (self.cmd.taker_fee, self.cmd.maker_fee) {
+                    (Some(taker_fee), Some(maker_fee)) => {
+                        ensure!(valid_fees(taker_fee, maker_fee), "invalid fee");
+                        Some(FeeRates {
+                            taker_fee,
+                            maker_fee,
+                        })
+                    }
+                    (None, None) => None,
+                    _ => return Err(anyhow!("taker_fee and maker_fee must be set together")),
+                };
+                let cmd = UserFeeCmd {
+                    user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    rates,
+                };
+                Ok(Event::UpdateUserFee(self.id, cmd, self.timestamp))
+            }
+            #[cfg(not(feature = "fusotao"))]
+            QUOTE => {
+                let bids = self.cmd.bids.clone().unwrap_or_default();
+                let asks = self.cmd.asks.clone().unwrap_or_default();
+                ensure!(
+                    bids.iter().chain(asks.iter()).all(|level| {
+                        level.price.is_sign_positive()
+                            && level.price < max_number()
+                            && level.price.scale() <= 12
+                            && level.amount.is_sign_positive()
+                            && level.amount < max_number()
+                            && level.amount.scale() <= 10
+                    }),
+                    "invalid quote numeric"
+                );
+                let cmd = QuoteCmd {
+                    symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
+                    user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    bids,
+                    asks,
+                };
+                Ok(Event::Quote(self.id, cmd, self.timestamp))
+            }
+            #[cfg(not(feature = "fusotao"))]
+            ASK_PEG | BID_PEG => {
+                let amount = self.cmd.amount.ok_or(anyhow!(""))?;
+                ensure!(
+                    amount.is_sign_positive() && amount < max_number() && amount.scale() <= 10,
+                    "invalid amount numeric"
+                );
+                let offset = self.cmd.peg_offset.unwrap_or_default();
+                ensure!(
+                    offset.abs() < max_number() && offset.scale() <= 12,
+                    "invalid offset numeric"
+                );
+                if let Some(cap) = self.cmd.peg_cap {
+                    ensure!(
+                        cap.is_sign_positive() && cap < max_number() && cap.scale() <= 12,
+                        "invalid price numeric"
+                    );
+                }
+                let cmd = PegCmd {
+                    symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
+                    user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    order_id: self.cmd.order_id.ok_or(anyhow!(""))?,
+                    amount,
+                    ask_or_bid: AskOrBid::try_from(self.cmd.cmd)?,
+                    peg: Peg {
+                        reference: self.cmd
+                            .peg_reference
+                            .map_or(Ok(PegReference::default()), PegReference::try_from)?,
+                        offset,
+                        cap: self.cmd.peg_cap,
+                    },
+                };
+                Ok(Event::Peg(self.id, cmd, self.timestamp))
+            }
+            // the scale and the range are checked by the currency registry
             TRANSFER_OUT => Ok(Event::TransferOut(
                 self.id,
                 AssetsCmd {
@@ -123,35 +465,121 @@ impl TryInto<Event> for Sequence {
                 },
                 self.timestamp,
             )),
-            UPDATE_SYMBOL => Ok(Event::UpdateSymbol(
+            UPDATE_CURRENCY => {
+                let min_transfer = self.cmd.min_transfer.unwrap_or_default();
+                ensure!(min_transfer.is_sign_positive(), "invalid min transfer numeric");
+                if let Some(max_transfer) = self.cmd.max_transfer {
+                    ensure!(max_transfer >= min_transfer, "max transfer less than min transfer");
+                }
+                let cmd = CurrencyCmd {
+                    currency: self.cmd.currency.ok_or(anyhow!(""))?,
+                    config: CurrencyConfig {
+                        decimals: self.cmd.decimals.filter(|d| *d < 18).ok_or(anyhow!(""))?,
+                        min_transfer,
+                        max_transfer: self.cmd.max_transfer,
+                        enabled: self.cmd.enabled.unwrap_or(true),
+                    },
+                };
+                Ok(Event::UpdateCurrency(self.id, cmd, self.timestamp))
+            }
+            UPDATE_SYMBOL => {
+                for limit in [
+                    self.cmd.tick_size,
+                    self.cmd.lot_size,
+                    self.cmd.max_amount,
+                    self.cmd.max_vol,
+                    self.cmd.price_band,
+                    self.cmd.halt_band,
+                ]
+                .iter()
+                .flatten()
+                {
+                    ensure!(limit.is_sign_positive(), "invalid symbol limit numeric");
+                }
+                ensure!(
+                    self.cmd.halt_band.is_some() == self.cmd.halt_window.is_some(),
+                    "halt_band and halt_window must be set together"
+                );
+                #[cfg(feature = "fusotao")]
+                ensure!(self.cmd.auction != Some(true), "auction not supported");
+                // an update replaces the fields of the legacy config unless `partial`, as the
+                // sequences before the partial updates were executed, the limits, the self-trade
+                // prevention and the matching algorithm absent are kept in both cases
+                if !self.cmd.partial.unwrap_or(false) {
+                    ensure!(
+                        self.cmd.open.is_some()
+                            && self.cmd.base_scale.is_some()
+                            && self.cmd.quote_scale.is_some()
+                            && self.cmd.taker_fee.is_some()
+                            && self.cmd.maker_fee.is_some()
+                            && self.cmd.min_amount.is_some()
+                            && self.cmd.min_vol.is_some()
+                            && self.cmd.enable_market_order.is_some(),
+                        "all the fields of the symbol are required unless partial"
+                    );
+                }
+                // the fields are validated with the current config of the symbol on execution
+                Ok(Event::UpdateSymbol(
+                    self.id,
+                    SymbolCmd {
+                        symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
+                        open: self.cmd.open,
+                        base_scale: self.cmd.base_scale,
+                        quote_scale: self.cmd.quote_scale,
+                        taker_fee: self.cmd.taker_fee,
+                        maker_fee: self.cmd.maker_fee,
+                        min_amount: self.cmd.min_amount,
+                        min_vol: self.cmd.min_vol,
+                        enable_market_order: self.cmd.enable_market_order,
+                        self_trade_prevention: self.cmd
+                            .self_trade_prevention
+                            .map(SelfTradePrevention::try_from)
+                            .transpose()?,
+                        matching_algorithm: self.cmd
+                            .matching_algorithm
+                            .map(MatchingAlgorithm::try_from)
+                            .transpose()?,
+                        tick_size: self.cmd.tick_size,
+                        lot_size: self.cmd.lot_size,
+                        max_amount: self.cmd.max_amount,
+                        max_vol: self.cmd.max_vol,
+                        price_band: self.cmd.price_band,
+                        halt_band: self.cmd.halt_band,
+                        halt_window: self.cmd.halt_window,
+                        auction: self.cmd.auction,
+                    },
+                    self.timestamp,
+                ))
+            }
+            CANCEL_USER_ORDERS => {
+                ensure!(
+                    self.cmd.base.is_some() == self.cmd.quote.is_some(),
+                    "both base and quote are required for a symbol"
+                );
+                let cmd = CancelUserCmd {
+                    user_id: UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    symbol: self.cmd.symbol(),
+                    #[cfg(feature = "fusotao")]
+                    nonce: self.cmd.nonce.ok_or(anyhow!(""))?,
+                    #[cfg(feature = "fusotao")]
+                    signature: hex::decode(self.cmd.signature.ok_or(anyhow!(""))?)?,
+                };
+                Ok(Event::CancelUser(self.id, cmd, self.timestamp))
+            }
+            #[cfg(not(feature = "fusotao"))]
+            CANCEL_ALL => Ok(Event::CancelAll(
+                self.id,
+                self.cmd.symbol().ok_or(anyhow!(""))?,
+                self.timestamp,
+            )),
+            #[cfg(not(feature = "fusotao"))]
+            DELIST => Ok(Event::Delist(
                 self.id,
-                SymbolCmd {
-                    symbol: self.cmd.symbol().ok_or(anyhow!(""))?,
-                    open: self.cmd.open.ok_or(anyhow!(""))?,
-                    base_scale: self.cmd.base_scale.filter(|b| *b < 18).ok_or(anyhow!(""))?,
-                    quote_scale: self.cmd.quote_scale.filter(|q| *q < 18).ok_or(anyhow!(""))?,
-                    taker_fee: self.cmd
-                        .maker_fee
-                        .filter(|f| f.is_sign_positive())
-                        .ok_or(anyhow!(""))?,
-                    maker_fee: self.cmd
-                        .maker_fee
-                        .filter(|f| f.is_sign_positive())
-                        .ok_or(anyhow!(""))?,
-                    min_amount: self.cmd
-                        .min_amount
-                        .filter(|f| f.is_sign_positive())
-                        .ok_or(anyhow!(""))?,
-                    min_vol: self.cmd
-                        .min_vol
-                        .filter(|f| f.is_sign_positive())
-                        .ok_or(anyhow!(""))?,
-                    enable_market_order: self.cmd.enable_market_order.ok_or(anyhow!(""))?,
-                },
+                self.cmd.symbol().ok_or(anyhow!(""))?,
                 self.timestamp,
             )),
             #[cfg(not(feature = "fusotao"))]
-            CANCEL_ALL => Ok(Event::CancelAll(
+            UNCROSS => Ok(Event::Uncross(
                 self.id,
                 self.cmd.symbol().ok_or(anyhow!(""))?,
                 self.timestamp,
@@ -192,12 +620,24 @@ impl TryInto<Inspection> for Watch {
 
     fn try_into(self) -> anyhow::Result<Inspection> {
         match self.cmd.cmd {
+            QUERY_ORDER if self.cmd.client_order_id.is_some() => Ok(Inspection::QueryClientOrder(
+                self.cmd.symbol().ok_or(anyhow!(""))?,
+                UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                self.cmd.client_order_id.unwrap_or_default(),
+                self.session,
+                self.req_id,
+            )),
             QUERY_ORDER => Ok(Inspection::QueryOrder(
                 self.cmd.symbol().ok_or(anyhow!(""))?,
                 self.cmd.order_id.ok_or(anyhow!(""))?,
                 self.session,
                 self.req_id,
             )),
+            QUERY_SYMBOL => Ok(Inspection::QuerySymbol(
+                self.cmd.symbol().ok_or(anyhow!(""))?,
+                self.session,
+                self.req_id,
+            )),
             QUERY_BALANCE => Ok(Inspection::QueryBalance(
                 UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
                 self.cmd.currency.ok_or(anyhow!(""))?,
@@ -209,6 +649,18 @@ impl TryInto<Inspection> for Watch {
                 self.session,
                 self.req_id,
             )),
+            QUERY_OPEN_ORDERS => {
+                ensure!(
+                    self.cmd.base.is_some() == self.cmd.quote.is_some(),
+                    "both base and quote are required for a symbol"
+                );
+                Ok(Inspection::QueryOpenOrders(
+                    UserId::from_str(self.cmd.user_id.as_ref().ok_or(anyhow!(""))?)?,
+                    self.cmd.symbol(),
+                    self.session,
+                    self.req_id,
+                ))
+            }
             UPDATE_DEPTH => Ok(Inspection::UpdateDepth),
             CONFIRM_ALL => Ok(Inspection::ConfirmAll(
                 self.cmd.from.ok_or(anyhow!(""))?,
@@ -271,6 +723,14 @@ pub struct Command {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub block_number: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub decimals: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_transfer: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_transfer: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub base_scale: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub quote_scale: Option<u32>,
@@ -283,10 +743,68 @@ pub struct Command {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub min_vol: Option<Amount>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub tick_size: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lot_size: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_amount: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_vol: Option<Vol>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price_band: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub halt_band: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub halt_window: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub open: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub enable_market_order: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub auction: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_in_force: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub self_trade_prevention: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matching_algorithm: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger_price: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_kind: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_order_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_price: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trail_amount: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trail_ratio: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_order_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peg_reference: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peg_offset: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peg_cap: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch: Option<Vec<Command>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub all_or_nothing: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bids: Option<Vec<QuoteLevel>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asks: Option<Vec<QuoteLevel>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_tiers: Option<Vec<FeeTier>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub from: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub exclude: Option<u64>,
@@ -311,7 +829,10 @@ impl Command {
 
     #[must_use]
     pub const fn is_read(&self) -> bool {
-        matches!(self.cmd, QUERY_ACCOUNTS | QUERY_BALANCE | QUERY_ORDER)
+        matches!(
+            self.cmd,
+            QUERY_ACCOUNTS | QUERY_BALANCE | QUERY_ORDER | QUERY_OPEN_ORDERS | QUERY_SYMBOL
+        )
     }
 }
 
@@ -474,3 +995,117 @@ pub fn test_deserialize_cmd() {
     }.try_into();
     assert!(s.is_ok());
 }
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_deserialize_market_cmd() {
+    let bid_market = r#"{"quote":100, "base":101, "cmd":3, "vol":"100.0", "order_id":1, "user_id":"0x0000000000000000000000000000000000000000000000000000000000000001"}"#;
+    let e = serde_json::from_str::<Command>(bid_market).unwrap();
+    let s: anyhow::Result<Event> = Sequence {
+        id: 1,
+        cmd: e,
+        status: 0,
+        timestamp: 0,
+    }.try_into();
+    assert!(matches!(s, Ok(Event::Market(_, MarketCmd { ask_or_bid: AskOrBid::Bid, .. }, _))));
+    // asks must be measured in base
+    let ask_market = r#"{"quote":100, "base":101, "cmd":2, "vol":"100.0", "order_id":2, "user_id":"0x0000000000000000000000000000000000000000000000000000000000000001"}"#;
+    let e = serde_json::from_str::<Command>(ask_market).unwrap();
+    let s: anyhow::Result<Event> = Sequence {
+        id: 2,
+        cmd: e,
+        status: 0,
+        timestamp: 0,
+    }.try_into();
+    assert!(s.is_err());
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_deserialize_batch_cmd() {
+    let batch = r#"{"quote":100, "base":101, "cmd":22, "all_or_nothing":true, "user_id":"0x0000000000000000000000000000000000000000000000000000000000000001", "batch":[{"cmd":4, "order_id":1}, {"cmd":1, "price":"10.0", "amount":"0.5", "order_id":2}]}"#;
+    let e = serde_json::from_str::<Command>(batch).unwrap();
+    let s: anyhow::Result<Event> = Sequence {
+        id: 3,
+        cmd: e,
+        status: 0,
+        timestamp: 0,
+    }.try_into();
+    match s {
+        Ok(Event::Batch(3, cmd, _)) => {
+            assert!(cmd.all_or_nothing);
+            assert!(matches!(cmd.orders[0], BatchItem::Cancel(CancelCmd { order_id: 1, .. })));
+            assert!(matches!(cmd.orders[1], BatchItem::Limit(LimitCmd { order_id: 2, .. })));
+        }
+        _ => panic!("batch not parsed"),
+    }
+    // only limit orders and cancels are allowed
+    let batch = r#"{"quote":100, "base":101, "cmd":22, "user_id":"0x0000000000000000000000000000000000000000000000000000000000000001", "batch":[{"cmd":3, "vol":"10.0", "order_id":1}]}"#;
+    let e = serde_json::from_str::<Command>(batch).unwrap();
+    let s: anyhow::Result<Event> = Sequence {
+        id: 4,
+        cmd: e,
+        status: 0,
+        timestamp: 0,
+    }.try_into();
+    assert!(s.is_err());
+    // the order ids of the limit orders are distinct
+    let batch = r#"{"quote":100, "base":101, "cmd":22, "user_id":"0x0000000000000000000000000000000000000000000000000000000000000001", "batch":[{"cmd":1, "price":"10.0", "amount":"0.5", "order_id":2}, {"cmd":0, "price":"10.0", "amount":"0.5", "order_id":2}]}"#;
+    let e = serde_json::from_str::<Command>(batch).unwrap();
+    let s: anyhow::Result<Event> = Sequence {
+        id: 5,
+        cmd: e,
+        status: 0,
+        timestamp: 0,
+    }.try_into();
+    assert!(s.is_err());
+}
+
+#[test]
+#[cfg(not(feature = "fusotao"))]
+pub fn test_deserialize_rebate_cmd() {
+    let user_fee = |taker_fee: &str, maker_fee: &str| {
+        let cmd = format!(
+            r#"{{"cmd":29, "taker_fee":"{}", "maker_fee":"{}", "user_id":"0x0000000000000000000000000000000000000000000000000000000000000001"}}"#,
+            taker_fee, maker_fee
+        );
+        let e = serde_json::from_str::<Command>(&cmd).unwrap();
+        let s: anyhow::Result<Event> = Sequence {
+            id: 1,
+            cmd: e,
+            status: 0,
+            timestamp: 0,
+        }.try_into();
+        s
+    };
+    assert!(user_fee("0.002", "-0.001").is_ok());
+    assert!(user_fee("0.002", "-0.002").is_ok());
+    // the rebate can't be larger than the taker fee
+    assert!(user_fee("0.002", "-0.003").is_err());
+    assert!(user_fee("-0.001", "0.001").is_err());
+}
+
+#[test]
+pub fn test_deserialize_symbol_cmd() {
+    use rust_decimal_macros::dec;
+    let update = |json| -> anyhow::Result<Event> {
+        let e = serde_json::from_str::<Command>(json).unwrap();
+        Sequence {
+            id: 1,
+            cmd: e,
+            status: 0,
+            timestamp: 0,
+        }.try_into()
+    };
+    // an update without `partial` requires all the fields of the legacy config
+    assert!(update(r#"{"quote":100, "base":101, "cmd":13, "taker_fee":"0.003"}"#).is_err());
+    // a partial update carries the fields present only
+    match update(r#"{"quote":100, "base":101, "cmd":13, "taker_fee":"0.003", "partial":true}"#) {
+        Ok(Event::UpdateSymbol(1, cmd, _)) => {
+            assert_eq!(Some(dec!(0.003)), cmd.taker_fee);
+            assert_eq!(None, cmd.maker_fee);
+            assert_eq!(None, cmd.open);
+        }
+        _ => panic!("symbol cmd not parsed"),
+    }
+}
@@ -12,15 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::{config, core};
+#[cfg(feature = "fusotao")]
+use crate::fusotao::GlobalStates;
+use crate::{config, core::*, orderbook::*};
 use chrono::prelude::DateTime;
 use chrono::Utc;
-
+use linked_hash_map::LinkedHashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::{TryFrom, TryInto};
 use std::time::{Duration, UNIX_EPOCH};
 use std::{fs, path, thread};
 
 /// dump snapshot at id(executed)
-pub fn dump(id: u64, time: u64, data: &core::Data) {
+pub fn dump(id: u64, time: u64, data: &Data) {
     let data = data.clone();
     let timestamp = UNIX_EPOCH + Duration::from_secs(time);
     let datetime = DateTime::<Utc>::from(timestamp);
@@ -49,7 +54,7 @@ fn get_id(path: &path::Path) -> u64 {
 }
 
 /// return the id(not executed yet), and the snapshot
-pub fn load() -> anyhow::Result<(u64, core::Data)> {
+pub fn load() -> anyhow::Result<(u64, Data)> {
     let dir = fs::read_dir(&config::C.sequence.coredump_dir)?;
     let file_path = dir
         .map(|e| e.unwrap())
@@ -65,10 +70,10 @@ pub fn load() -> anyhow::Result<(u64, core::Data)> {
                 event_id,
                 event_id + 1
             );
-            Ok((event_id + 1, core::Data::from_raw(fs::File::open(f)?)?))
+            Ok((event_id + 1, Data::from_raw(fs::File::open(f)?)?))
         }
         None => match config::C.sequence.enable_from_genesis {
-            true => Ok((1, core::Data::new())),
+            true => Ok((1, Data::new())),
             false => Err(anyhow::anyhow!(
                 "missing snapshot, add `enable_from_genesis` to force to start"
             )),
@@ -76,6 +81,107 @@ pub fn load() -> anyhow::Result<(u64, core::Data)> {
     }
 }
 
+/// the layout of the snapshots before versioning
+#[derive(Serialize, Deserialize)]
+pub struct DataV1 {
+    pub orderbooks: HashMap<Symbol, OrderBookV1>,
+    pub accounts: Accounts,
+    #[cfg(feature = "fusotao")]
+    pub merkle_tree: GlobalStates,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OrderBookV1 {
+    pub asks: BTreeMap<Price, OrderPageV1>,
+    pub bids: BTreeMap<Price, OrderPageV1>,
+    /// the sides are not indexed, they are derived from the tapes on migrating
+    pub indices: HashMap<OrderId, Price>,
+    pub base_scale: u32,
+    pub quote_scale: u32,
+    pub taker_fee: Fee,
+    pub maker_fee: Fee,
+    pub min_amount: Amount,
+    pub min_vol: Amount,
+    pub enable_market_order: bool,
+    pub open: bool,
+    pub max_id: OrderId,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OrderPageV1 {
+    pub orders: LinkedHashMap<OrderId, OrderV1>,
+    pub amount: Amount,
+    pub price: Price,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OrderV1 {
+    pub id: OrderId,
+    pub user: UserId,
+    pub price: Price,
+    pub unfilled: Amount,
+}
+
+impl TryFrom<DataV1> for Data {
+    type Error = anyhow::Error;
+
+    fn try_from(v1: DataV1) -> anyhow::Result<Self> {
+        let mut orderbooks = HashMap::new();
+        for (symbol, orderbook) in v1.orderbooks {
+            orderbooks.insert(symbol, orderbook.try_into()?);
+        }
+        Ok(Self {
+            orderbooks,
+            accounts: v1.accounts,
+            #[cfg(feature = "fusotao")]
+            merkle_tree: v1.merkle_tree,
+            ..Data::new()
+        })
+    }
+}
+
+impl TryFrom<OrderBookV1> for OrderBook {
+    type Error = anyhow::Error;
+
+    fn try_from(v1: OrderBookV1) -> anyhow::Result<Self> {
+        let mut orderbook = OrderBook::new(
+            v1.base_scale,
+            v1.quote_scale,
+            v1.taker_fee,
+            v1.maker_fee,
+            v1.min_amount,
+            v1.min_vol,
+            v1.enable_market_order,
+            v1.open,
+        );
+        let tapes = vec![(AskOrBid::Ask, v1.asks), (AskOrBid::Bid, v1.bids)];
+        for (ask_or_bid, tape) in tapes {
+            // inserting by the order of the pages keeps the time priority
+            for (_, page) in tape {
+                for (_, o) in page.orders {
+                    let order = Order::new(o.id, o.user, o.price, o.unfilled);
+                    orderbook.insert(order, ask_or_bid);
+                }
+            }
+        }
+        for (id, price) in v1.indices.iter() {
+            let slot = orderbook.indices.get(id);
+            anyhow::ensure!(
+                slot.map(|s| s.price) == Some(*price),
+                "order {} not found at {} in the tapes",
+                id,
+                price
+            );
+        }
+        anyhow::ensure!(
+            v1.indices.len() == orderbook.indices.len(),
+            "orders not indexed in the tapes"
+        );
+        orderbook.max_id = v1.max_id;
+        Ok(orderbook)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use chrono::prelude::DateTime;
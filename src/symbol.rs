@@ -0,0 +1,301 @@
+// Copyright 2021 UINB Technologies Pte. Ltd.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{core::*, event::SymbolCmd, fees, orderbook::*};
+use anyhow::{anyhow, ensure};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// the trading rules of a symbol
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SymbolConfig {
+    pub open: bool,
+    pub base_scale: Scale,
+    pub quote_scale: Scale,
+    pub taker_fee: Fee,
+    pub maker_fee: Fee,
+    pub min_amount: Amount,
+    pub min_vol: Vol,
+    pub enable_market_order: bool,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub matching_algorithm: MatchingAlgorithm,
+    pub tick_size: Option<Price>,
+    pub lot_size: Option<Amount>,
+    pub max_amount: Option<Amount>,
+    pub max_vol: Option<Vol>,
+    pub price_band: Option<Decimal>,
+    pub halt_band: Option<Decimal>,
+    pub halt_window: Option<Timestamp>,
+    pub auction: bool,
+}
+
+impl SymbolConfig {
+    pub fn of(orderbook: &OrderBook) -> Self {
+        Self {
+            open: orderbook.open,
+            base_scale: orderbook.base_scale,
+            quote_scale: orderbook.quote_scale,
+            taker_fee: orderbook.taker_fee,
+            maker_fee: orderbook.maker_fee,
+            min_amount: orderbook.min_amount,
+            min_vol: orderbook.min_vol,
+            enable_market_order: orderbook.enable_market_order,
+            self_trade_prevention: orderbook.self_trade_prevention,
+            matching_algorithm: orderbook.matching_algorithm,
+            tick_size: orderbook.tick_size,
+            lot_size: orderbook.lot_size,
+            max_amount: orderbook.max_amount,
+            max_vol: orderbook.max_vol,
+            price_band: orderbook.price_band,
+            halt_band: orderbook.circuit_breaker.as_ref().map(|c| c.band),
+            halt_window: orderbook.circuit_breaker.as_ref().map(|c| c.window),
+            auction: orderbook.auction,
+        }
+    }
+
+    /// apply the fields present in `cmd` over the config, a new symbol requires all the fields
+    /// except the limits, the limits are removed by zero
+    pub fn merge(current: Option<Self>, cmd: &SymbolCmd) -> anyhow::Result<Self> {
+        let missing = |field| anyhow!("{} is required for a new symbol", field);
+        let config = match current {
+            Some(config) => config,
+            None => Self {
+                open: cmd.open.ok_or_else(|| missing("open"))?,
+                base_scale: cmd.base_scale.ok_or_else(|| missing("base_scale"))?,
+                quote_scale: cmd.quote_scale.ok_or_else(|| missing("quote_scale"))?,
+                taker_fee: cmd.taker_fee.ok_or_else(|| missing("taker_fee"))?,
+                maker_fee: cmd.maker_fee.ok_or_else(|| missing("maker_fee"))?,
+                min_amount: cmd.min_amount.ok_or_else(|| missing("min_amount"))?,
+                min_vol: cmd.min_vol.ok_or_else(|| missing("min_vol"))?,
+                enable_market_order: cmd
+                    .enable_market_order
+                    .ok_or_else(|| missing("enable_market_order"))?,
+                self_trade_prevention: SelfTradePrevention::default(),
+                matching_algorithm: MatchingAlgorithm::default(),
+                tick_size: None,
+                lot_size: None,
+                max_amount: None,
+                max_vol: None,
+                price_band: None,
+                halt_band: None,
+                halt_window: None,
+                auction: false,
+            },
+        };
+        let limit = |update: Option<Decimal>, current| match update {
+            Some(v) if v.is_zero() => None,
+            Some(v) => Some(v),
+            None => current,
+        };
+        let (halt_band, halt_window) = match cmd.halt_band.zip(cmd.halt_window) {
+            Some((band, _)) if band.is_zero() => (None, None),
+            Some((band, window)) => (Some(band), Some(window)),
+            None => (config.halt_band, config.halt_window),
+        };
+        let config = Self {
+            open: cmd.open.unwrap_or(config.open),
+            base_scale: cmd.base_scale.unwrap_or(config.base_scale),
+            quote_scale: cmd.quote_scale.unwrap_or(config.quote_scale),
+            taker_fee: cmd.taker_fee.unwrap_or(config.taker_fee),
+            maker_fee: cmd.maker_fee.unwrap_or(config.maker_fee),
+            min_amount: cmd.min_amount.unwrap_or(config.min_amount),
+            min_vol: cmd.min_vol.unwrap_or(config.min_vol),
+            enable_market_order: cmd.enable_market_order.unwrap_or(config.enable_market_order),
+            self_trade_prevention: cmd
+                .self_trade_prevention
+                .unwrap_or(config.self_trade_prevention),
+            matching_algorithm: cmd.matching_algorithm.unwrap_or(config.matching_algorithm),
+            tick_size: limit(cmd.tick_size, config.tick_size),
+            lot_size: limit(cmd.lot_size, config.lot_size),
+            max_amount: limit(cmd.max_amount, config.max_amount),
+            max_vol: limit(cmd.max_vol, config.max_vol),
+            price_band: limit(cmd.price_band, config.price_band),
+            halt_band,
+            halt_window,
+            // the auction is left by uncrossing only
+            auction: config.auction || cmd.auction.unwrap_or_default(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(self.base_scale < 18 && self.quote_scale < 18, "scale out of range");
+        ensure!(fees::valid_fees(self.taker_fee, self.maker_fee), "invalid fee");
+        #[cfg(feature = "fusotao")]
+        ensure!(self.maker_fee.is_sign_positive(), "maker rebate not supported");
+        ensure!(
+            self.min_amount.is_sign_positive() && self.min_vol.is_sign_positive(),
+            "invalid min amount or min vol"
+        );
+        // the smallest vol is the smallest price times the smallest amount
+        ensure!(
+            within_scale(self.min_amount, self.base_scale)
+                && within_scale(self.min_vol, self.base_scale + self.quote_scale),
+            "min amount or min vol exceeds the scales"
+        );
+        if let Some(tick) = self.tick_size {
+            ensure!(within_scale(tick, self.quote_scale), "tick size exceeds quote scale");
+        }
+        if let Some(lot) = self.lot_size {
+            ensure!(within_scale(lot, self.base_scale), "lot size exceeds base scale");
+        }
+        if let Some(max) = self.max_amount {
+            ensure!(max >= self.min_amount, "max amount less than min amount");
+        }
+        if let Some(max) = self.max_vol {
+            ensure!(max >= self.min_vol, "max vol less than min vol");
+        }
+        ensure!(self.halt_window != Some(0), "halt window must be positive");
+        Ok(())
+    }
+
+    pub fn apply(&self, orderbook: &mut OrderBook) {
+        orderbook.open = self.open;
+        orderbook.base_scale = self.base_scale;
+        orderbook.quote_scale = self.quote_scale;
+        orderbook.taker_fee = self.taker_fee;
+        orderbook.maker_fee = self.maker_fee;
+        orderbook.min_amount = self.min_amount;
+        orderbook.min_vol = self.min_vol;
+        orderbook.enable_market_order = self.enable_market_order;
+        orderbook.self_trade_prevention = self.self_trade_prevention;
+        orderbook.matching_algorithm = self.matching_algorithm;
+        orderbook.tick_size = self.tick_size;
+        orderbook.lot_size = self.lot_size;
+        orderbook.max_amount = self.max_amount;
+        orderbook.max_vol = self.max_vol;
+        orderbook.price_band = self.price_band;
+        // keep the recent prices observed unless the breaker changes
+        let unchanged = matches!(
+            &orderbook.circuit_breaker,
+            Some(c) if Some(c.band) == self.halt_band && Some(c.window) == self.halt_window
+        );
+        if !unchanged {
+            orderbook.circuit_breaker = self
+                .halt_band
+                .zip(self.halt_window)
+                .map(|(band, window)| CircuitBreaker::new(band, window));
+        }
+        orderbook.auction = self.auction;
+    }
+}
+
+/// what remained of a symbol when it was delisted
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct DelistReport {
+    pub event_id: EventId,
+    pub timestamp: Timestamp,
+    pub config: SymbolConfig,
+    pub last_price: Option<Price>,
+    /// the number of orders canceled, including the stop orders
+    pub canceled: usize,
+    /// the frozen funds returned to each user
+    pub released: Vec<Release>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct Release {
+    pub user_id: UserId,
+    pub base: Amount,
+    pub quote: Amount,
+}
+
+fn within_scale(v: Decimal, scale: Scale) -> bool {
+    v.normalize().scale() <= scale
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn cmd() -> SymbolCmd {
+        SymbolCmd {
+            symbol: (101, 100),
+            open: None,
+            base_scale: None,
+            quote_scale: None,
+            taker_fee: None,
+            maker_fee: None,
+            min_amount: None,
+            min_vol: None,
+            enable_market_order: None,
+            self_trade_prevention: None,
+            matching_algorithm: None,
+            tick_size: None,
+            lot_size: None,
+            max_amount: None,
+            max_vol: None,
+            price_band: None,
+            halt_band: None,
+            halt_window: None,
+            auction: None,
+        }
+    }
+
+    #[test]
+    pub fn test_merge_symbol_config() {
+        // a new symbol requires the fields without defaults
+        assert!(SymbolConfig::merge(None, &cmd()).is_err());
+        let new = SymbolCmd {
+            open: Some(true),
+            base_scale: Some(4),
+            quote_scale: Some(2),
+            taker_fee: Some(dec!(0.002)),
+            maker_fee: Some(dec!(0.001)),
+            min_amount: Some(dec!(0.1)),
+            min_vol: Some(dec!(10)),
+            enable_market_order: Some(false),
+            tick_size: Some(dec!(0.05)),
+            ..cmd()
+        };
+        let config = SymbolConfig::merge(None, &new).unwrap();
+        assert_eq!(Some(dec!(0.05)), config.tick_size);
+
+        // only the fields present are updated, the limits are removed by zero
+        let update = SymbolCmd {
+            taker_fee: Some(dec!(0.003)),
+            tick_size: Some(dec!(0)),
+            ..cmd()
+        };
+        let updated = SymbolConfig::merge(Some(config.clone()), &update).unwrap();
+        assert_eq!(dec!(0.003), updated.taker_fee);
+        assert_eq!(dec!(0.001), updated.maker_fee);
+        assert_eq!(config.min_vol, updated.min_vol);
+        assert_eq!(None, updated.tick_size);
+
+        // the merged config is validated as a whole
+        let rebate = SymbolCmd {
+            maker_fee: Some(dec!(-0.003)),
+            ..cmd()
+        };
+        assert!(SymbolConfig::merge(Some(config.clone()), &rebate).is_err());
+        let scale = SymbolCmd {
+            base_scale: Some(0),
+            ..cmd()
+        };
+        assert!(SymbolConfig::merge(Some(config.clone()), &scale).is_err());
+        let tick = SymbolCmd {
+            tick_size: Some(dec!(0.001)),
+            ..cmd()
+        };
+        assert!(SymbolConfig::merge(Some(config.clone()), &tick).is_err());
+        let max = SymbolCmd {
+            max_amount: Some(dec!(0.01)),
+            ..cmd()
+        };
+        assert!(SymbolConfig::merge(Some(config), &max).is_err());
+    }
+}